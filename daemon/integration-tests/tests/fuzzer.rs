@@ -61,7 +61,7 @@ async fn main() -> Result<()> {
     // Set up the actors.
     let mut app_config = AppConfig::default();
     app_config.base_dir = dir.path().to_path_buf();
-    let daemon = Daemon::new(app_config, &socket_path, true, false).await?;
+    let daemon = Daemon::new(app_config, &socket_path, true, None).await?;
 
     // Wait until iroh's DNS discovery (hopefully) works.
     sleep(Duration::from_millis(1000)).await;
@@ -70,8 +70,8 @@ async fn main() -> Result<()> {
 
     let mut app_config2 = AppConfig::default();
     app_config2.base_dir = dir2.path().to_path_buf();
-    app_config2.peer = Some(config::Peer::SecretAddress(daemon.address.clone()));
-    let peer = Daemon::new(app_config2, &socket_path2, false, false).await?;
+    app_config2.peers = vec![config::Peer::SecretAddress(daemon.address.clone())];
+    let peer = Daemon::new(app_config2, &socket_path2, false, None).await?;
 
     // Wait until file2 appears.
     while !file2.exists() {
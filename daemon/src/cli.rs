@@ -17,6 +17,12 @@ pub struct Cli {
     /// The shared directory. Defaults to current directory.
     #[arg(long, global = true)]
     pub directory: Option<PathBuf>,
+    /// Answer "yes" to all questions, instead of asking. You can also set `TEAMTYPE_ASSUME=yes`.
+    #[arg(long, global = true, conflicts_with = "no")]
+    pub yes: bool,
+    /// Answer "no" to all questions, instead of asking. You can also set `TEAMTYPE_ASSUME=no`.
+    #[arg(long, global = true)]
+    pub no: bool,
 }
 
 #[derive(Args)]
@@ -28,6 +34,14 @@ pub struct SyncVcsFlag {
     pub sync_vcs: bool,
 }
 
+#[derive(Args)]
+pub struct DetachFlag {
+    /// Run the daemon in the background. Its output goes to .teamtype/log, and you can stop it
+    /// with `teamtype stop`.
+    #[arg(long)]
+    pub detach: bool,
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     /// Share a directory with a new peer.
@@ -42,18 +56,165 @@ pub enum Commands {
         /// Print the secret address. Useful for sharing with multiple people.
         #[arg(long)]
         show_secret_address: bool,
+        /// Only let the peers who join with the join codes (or the printed secret address) watch,
+        /// without being able to edit.
+        #[arg(long)]
+        read_only: bool,
+        /// Ask before letting peers connect which aren't trusted yet, in the terminal or in an
+        /// editor. Their connection waits until you decide.
+        #[arg(long)]
+        approve_peers: bool,
+        /// Also accept peers which connect via plain TCP on this address, like `0.0.0.0:4000`.
+        /// Useful in networks where the relays and the discovery service aren't reachable.
+        #[arg(long, value_name = "ADDRESS")]
+        listen_tcp: Option<String>,
         #[command(flatten)]
         sync_vcs: SyncVcsFlag,
+        #[command(flatten)]
+        detach: DetachFlag,
     },
     /// Join a shared directory via a join code, or connect to the most recent one.
     Join {
         /// Specify to connect to a new peer. Otherwise, try to connect to the most recent peer.
         join_code: Option<String>,
+        /// Start with an empty history instead of the one in .teamtype/doc, to join a peer whose
+        /// history is unrelated. Files which differ are overwritten with the peer's versions.
+        #[arg(long)]
+        discard_history: bool,
         #[command(flatten)]
         sync_vcs: SyncVcsFlag,
+        #[command(flatten)]
+        detach: DetachFlag,
     },
+    /// Stop the daemon running in this directory. It persists the history and disconnects from
+    /// all peers before it exits.
+    Stop,
+    /// Compact the persisted history in .teamtype/doc, so that it takes less space and loads
+    /// faster. The daemon also does this automatically from time to time.
+    Gc,
+    /// Check the shared directory for setup problems, and offer to fix them.
+    Doctor,
+    /// Manage the peers which can connect without knowing the passphrase. Peers become trusted when
+    /// they first connect with the passphrase (or when we first connect to them).
+    Trust {
+        #[command(subcommand)]
+        command: TrustCommand,
+    },
+    /// Invite peers without revealing the secret address. Invites can expire, and be limited to a
+    /// number of peers. Peers which joined with an invite are trusted from then on.
+    Invite {
+        #[command(subcommand)]
+        command: InviteCommand,
+    },
+    /// Generate a new passphrase for the secret address, so that a leaked secret address stops
+    /// working. The node ID stays the same. Peers which connected with the old passphrase are
+    /// disconnected, but trusted peers can still connect.
+    RotateSecret,
+    /// Disconnect a peer from the running daemon. Unless you also revoke its trust, it can
+    /// connect again.
+    Disconnect {
+        /// The node ID of the peer, or a unique prefix of it.
+        node_id: String,
+    },
+    /// Let a peer connect which waits for approval (see `teamtype share --approve-peers`).
+    Approve {
+        /// The node ID of the peer, or a unique prefix of it.
+        node_id: String,
+        /// Also trust the peer from now on, so that it won't need approval again.
+        #[arg(long)]
+        trust: bool,
+    },
+    /// Close the connection of a peer which waits for approval. It can ask again.
+    Reject {
+        /// The node ID of the peer, or a unique prefix of it.
+        node_id: String,
+    },
+    /// Encrypt the history and the key file in .teamtype/ with a passphrase. Teamtype will ask for
+    /// the passphrase when it starts, unless it's in the `TEAMTYPE_PASSPHRASE` environment variable.
+    Encrypt,
     /// Open a JSON-RPC connection to the Teamtype daemon on stdin/stdout. Used by text editor plugins.
     Client,
+    /// Show what the daemon running in this directory is doing: connected peers, open files, etc.
+    Status {
+        /// Print the status as JSON, for use in scripts.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show the history of the shared directory, newest changes first.
+    Log {
+        /// Only show changes to this file.
+        path: Option<PathBuf>,
+        /// Only show changes made since this time. Either a duration like "10m", "2h" or "3d", or
+        /// a time in UTC like "2025-10-29" or "2025-10-29 13:37".
+        #[arg(long)]
+        since: Option<String>,
+        /// Only show changes made until this time. Same format as --since.
+        #[arg(long)]
+        until: Option<String>,
+    },
+    /// Print the content of a file at an earlier point in history.
+    Show {
+        path: PathBuf,
+        /// A change hash (at least 7 characters), or a time like "10m", "2h" or "2025-10-29 13:37".
+        #[arg(long)]
+        at: String,
+    },
+    /// Show who last changed each line of a file, and when.
+    Blame { path: PathBuf },
+    /// Bring a file back to its content at an earlier point in history. This also works for
+    /// deleted files. The restore is a regular change, which is synchronized to all peers.
+    Restore {
+        path: PathBuf,
+        /// A change hash (at least 7 characters), or a time like "10m", "2h" or "2025-10-29 13:37".
+        #[arg(long)]
+        at: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TrustCommand {
+    /// List the trusted (with full or read-only access) and revoked peers.
+    List,
+    /// Trust a peer, even if its trust was revoked before.
+    Add {
+        node_id: String,
+        /// Only let the peer watch, without being able to edit.
+        #[arg(long)]
+        read_only: bool,
+    },
+    /// Stop trusting a peer. It can't connect anymore, even with the passphrase, until you trust it
+    /// again with `teamtype trust add`.
+    Revoke {
+        /// The node ID of the peer, or a unique prefix of it.
+        node_id: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum InviteCommand {
+    /// Create an invite, and print the secret address which peers can join with.
+    Create {
+        /// When the invite expires. Either a duration like "1h" or "7d", or a time in UTC like
+        /// "2025-10-29 13:37".
+        #[arg(long)]
+        expires: Option<String>,
+        /// How many peers can join with the invite.
+        #[arg(long)]
+        uses: Option<u32>,
+        /// Only let the invited peers watch, without being able to edit.
+        #[arg(long)]
+        read_only: bool,
+        /// Also hand out the invite via a join code, and wait until someone used it.
+        #[arg(long)]
+        join_code: bool,
+    },
+    /// List the invites, and how often they can still be used.
+    List,
+    /// Delete an invite, so that no one can join with it anymore.
+    Revoke {
+        /// The ID of the invite, or a unique prefix of it.
+        id: String,
+    },
 }
 
 #[test]
@@ -4,18 +4,90 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use anyhow::{bail, Result};
-use std::io::Write;
+use std::io::{IsTerminal as _, Write};
+use std::sync::OnceLock;
+
+/// Set this environment variable to "yes" or "no" to answer all questions without asking.
+pub const ASSUME_ENV_VAR: &str = "TEAMTYPE_ASSUME";
+
+/// The answer given with `--yes` or `--no`. Takes precedence over [`ASSUME_ENV_VAR`].
+static ASSUMED_ANSWER: OnceLock<bool> = OnceLock::new();
+
+/// Makes [`ask`] answer all questions with `answer`, instead of asking.
+pub fn assume_answer(answer: bool) {
+    let _ = ASSUMED_ANSWER.set(answer);
+}
+
+/// Returns the answer which [`ask`] will give without asking, if any.
+pub fn assumed_answer() -> Result<Option<bool>> {
+    if let Some(answer) = ASSUMED_ANSWER.get() {
+        return Ok(Some(*answer));
+    }
+    match std::env::var(ASSUME_ENV_VAR) {
+        Ok(value) => match parse_answer(&value) {
+            Some(answer) => Ok(Some(answer)),
+            None => bail!("{ASSUME_ENV_VAR} must be 'yes' or 'no', but is '{value}'"),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Whether [`ask`] can get an answer, either from the user or because it was given up front.
+#[must_use]
+pub fn can_ask() -> bool {
+    !matches!(assumed_answer(), Ok(None)) || std::io::stdin().is_terminal()
+}
+
+fn parse_answer(answer: &str) -> Option<bool> {
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Some(true),
+        "n" | "no" => Some(false),
+        _ => None,
+    }
+}
 
 pub fn ask(question: &str) -> Result<bool> {
+    if let Some(answer) = assumed_answer()? {
+        println!("{question} (y/N): {}", if answer { "yes" } else { "no" });
+        return Ok(answer);
+    }
+    if !std::io::stdin().is_terminal() {
+        bail!("Can't ask \"{question}\", because the input is not a terminal. Answer it with --yes or --no, or by setting {ASSUME_ENV_VAR}=yes or {ASSUME_ENV_VAR}=no.");
+    }
+    ask_in_terminal(question)
+}
+
+/// Like [`ask`], but ignores the answers given up front. Use this for things which can't be
+/// undone, so that the user confirms them each time.
+pub fn ask_interactively(question: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        bail!("Can't ask \"{question}\", because the input is not a terminal. This question can't be answered up front.");
+    }
+    ask_in_terminal(question)
+}
+
+fn ask_in_terminal(question: &str) -> Result<bool> {
     print!("{question} (y/N): ");
     std::io::stdout().flush()?;
     let mut lines = std::io::stdin().lines();
     if let Some(Ok(line)) = lines.next() {
-        match line.to_lowercase().as_str() {
-            "y" | "yes" => Ok(true),
-            _ => Ok(false),
-        }
+        Ok(parse_answer(&line).unwrap_or(false))
     } else {
         bail!("Failed to read answer");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_answers() {
+        assert_eq!(parse_answer("y"), Some(true));
+        assert_eq!(parse_answer("YES"), Some(true));
+        assert_eq!(parse_answer(" no\n"), Some(false));
+        assert_eq!(parse_answer("n"), Some(false));
+        assert_eq!(parse_answer("maybe"), None);
+        assert_eq!(parse_answer(""), None);
+    }
+}
@@ -5,11 +5,13 @@
 
 //! Data structures and helper methods around influencing the configuration of the application.
 use crate::sandbox;
+use crate::types::Access;
 use crate::wormhole::get_secret_address_from_wormhole;
 use anyhow::{bail, Context, Result};
 use git2::ConfigLevel;
 use ini::Ini;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tracing::info;
 
 pub const DOC_FILE: &str = "doc";
@@ -17,10 +19,20 @@ pub const DEFAULT_SOCKET_NAME: &str = "socket";
 pub const CONFIG_DIR: &str = ".teamtype";
 pub const CONFIG_FILE: &str = "config";
 pub const BOOKMARK_FILE: &str = "bookmark";
+pub const PID_FILE: &str = "pid";
+pub const LOG_FILE: &str = "log";
+pub const KEY_FILE: &str = "key";
+pub const ENCRYPTION_FILE: &str = "encryption";
+pub const TRUST_FILE: &str = "trust";
+pub const INVITES_FILE: &str = "invites";
+/// The addresses under which the daemon could be reached when it last started.
+pub const ADDRESS_FILE: &str = "address";
 // TODO: Remove this after a while.
 pub const LEGACY_CONFIG_DIR: &str = ".ethersync";
 
 const EMIT_JOIN_CODE_DEFAULT: bool = true;
+/// Named peers are configured in sections like `[peer.alice]`.
+const PEER_SECTION_PREFIX: &str = "peer.";
 const EMIT_SECRET_ADDRESS_DEFAULT: bool = false;
 
 #[derive(Clone)]
@@ -29,13 +41,119 @@ pub enum Peer {
     JoinCode(String),
 }
 
+/// What happens when a peer we don't trust yet connects with a valid passphrase.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PeerApproval {
+    /// It can connect right away.
+    #[default]
+    Automatic,
+    /// Its connection waits until someone approves it, in the terminal or in an editor.
+    Required,
+}
+
+/// Which relays the iroh endpoint uses, to reach peers which can't be reached directly.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Relays {
+    /// The public relays run by n0.
+    #[default]
+    Default,
+    /// None. Peers have to be reachable directly.
+    Disabled,
+    /// Self-hosted relays, by their URLs.
+    Custom(Vec<String>),
+}
+
+impl Relays {
+    /// Parses `default`, `none`, or a comma-separated list of relay URLs.
+    fn parse(value: &str) -> Self {
+        match value.trim() {
+            "default" => Self::Default,
+            "none" => Self::Disabled,
+            urls => Self::Custom(
+                urls.split(',')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// How the iroh endpoint finds other daemons, and lets them find us.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Discovery {
+    /// Via n0's public discovery service.
+    #[default]
+    N0,
+    /// Not at all. Then, our secret address contains the addresses under which we can be
+    /// reached.
+    Disabled,
+}
+
+/// How we try to reach peers again, after connecting failed, or the connection was lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reconnect {
+    /// How long to wait before the first retry. The delay doubles with every failed attempt.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// When to give up on a peer, counted from the first failed attempt. `None` to never give up.
+    pub give_up_after: Option<Duration>,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5 * 60),
+            give_up_after: Some(Duration::from_secs(24 * 60 * 60)),
+        }
+    }
+}
+
+impl Reconnect {
+    fn from_section(section: &ini::Properties) -> Self {
+        let seconds = |key: &str| {
+            section.get(key).map(|p| {
+                Duration::from_secs(p.parse().unwrap_or_else(|_| {
+                    panic!("Failed to parse config parameter `{key}` as number of seconds")
+                }))
+            })
+        };
+        let default = Self::default();
+        Self {
+            initial_delay: seconds("reconnect_delay").unwrap_or(default.initial_delay),
+            max_delay: seconds("reconnect_max_delay").unwrap_or(default.max_delay),
+            give_up_after: match section.get("reconnect_give_up_after") {
+                Some("never") => None,
+                Some(_) => seconds("reconnect_give_up_after"),
+                None => default.give_up_after,
+            },
+        }
+    }
+}
+
 #[derive(Clone, Default)]
 #[must_use]
 pub struct AppConfig {
     pub base_dir: PathBuf,
-    pub peer: Option<Peer>,
+    /// The peers to connect to. Empty if we're the host.
+    pub peers: Vec<Peer>,
     pub emit_join_code: bool,
     pub emit_secret_address: bool,
+    /// What the join codes and the printed secret address grant.
+    pub share_access: Access,
+    pub peer_approval: PeerApproval,
+    /// Where to listen for peers which connect via plain TCP, like `0.0.0.0:4000`.
+    pub listen_tcp: Option<String>,
+    pub relays: Relays,
+    pub discovery: Discovery,
+    /// The UDP port of the iroh endpoint. By default, a random one is used.
+    pub bind_port: Option<u16>,
+    pub reconnect: Reconnect,
+    /// The node IDs of the enabled peers which only gave us read-only access when we last
+    /// connected. See `remember_read_only_peer`.
+    pub read_only_peers: Vec<String>,
     // Whether to sync version control directories like .git, .jj, ...
     pub sync_vcs: bool,
 }
@@ -51,9 +169,7 @@ impl AppConfig {
                 // TODO: extract all the other fields to its own struct, s.t. we don't have to work
                 // around the fact that base_dir won't ever be in the config file.
                 base_dir: Path::new("/does-not-exist").to_path_buf(),
-                peer: general_section
-                    .get("peer")
-                    .map(|p| Peer::SecretAddress(p.to_string())),
+                peers: peers_from_config(&conf),
                 emit_join_code: general_section.get("emit_join_code").map_or(
                     EMIT_JOIN_CODE_DEFAULT,
                     |p| {
@@ -69,6 +185,32 @@ impl AppConfig {
                         )
                     },
                 ),
+                share_access: Access::Full,
+                peer_approval: if general_section.get("approve_peers").is_some_and(|p| {
+                    p.parse()
+                        .expect("Failed to parse config parameter `approve_peers` as bool")
+                }) {
+                    PeerApproval::Required
+                } else {
+                    PeerApproval::Automatic
+                },
+                listen_tcp: general_section.get("listen_tcp").map(str::to_string),
+                relays: general_section
+                    .get("relays")
+                    .map_or_else(Relays::default, Relays::parse),
+                discovery: match general_section.get("discovery") {
+                    None | Some("n0") => Discovery::N0,
+                    Some("none") => Discovery::Disabled,
+                    Some(other) => panic!(
+                        "Failed to parse config parameter `discovery`: expected 'n0' or 'none', got '{other}'"
+                    ),
+                },
+                bind_port: general_section.get("bind_port").map(|p| {
+                    p.parse()
+                        .expect("Failed to parse config parameter `bind_port` as port number")
+                }),
+                reconnect: Reconnect::from_section(general_section),
+                read_only_peers: read_only_peers_from_config(&conf),
                 sync_vcs: false,
             })
         } else {
@@ -80,41 +222,68 @@ impl AppConfig {
         self.base_dir.join(CONFIG_DIR).join(CONFIG_FILE)
     }
 
-    /// If we have a join code, try to use that and overwrite the config file.
-    /// If we don't have a join code, try to use the configured peer.
-    /// Otherwise, fail.
-    pub async fn resolve_peer(self) -> Result<Self> {
-        let peer = match self.peer {
-            Some(Peer::JoinCode(ref join_code)) => {
-                let secret_address = get_secret_address_from_wormhole(join_code).await.context(
-                    "Failed to retreive secret address, was this join code already used?",
-                )?;
-                info!(
-                    "Derived peer from join code. Storing in config (overwriting previous config)."
-                );
-                store_peer_in_config(&self.base_dir, &self.config_file(), &secret_address)?;
-                Peer::SecretAddress(secret_address)
-            }
-            Some(Peer::SecretAddress(secret_address)) => {
-                info!("Using peer from config file.");
-                Peer::SecretAddress(secret_address)
-            }
-            None => {
-                bail!("Missing join code, and no peer=<secret address> in .teamtype/config");
+    /// Turns join codes into secret addresses, and adds those to the config file. Fails if
+    /// there's no peer to connect to.
+    pub async fn resolve_peers(self) -> Result<Self> {
+        if self.peers.is_empty() {
+            bail!("Missing join code, and no enabled peers in .teamtype/config");
+        }
+        let config_file = self.config_file();
+        let mut secret_addresses = vec![];
+        for peer in self.peers {
+            let secret_address = match peer {
+                Peer::JoinCode(join_code) => {
+                    let secret_address =
+                        get_secret_address_from_wormhole(&join_code).await.context(
+                            "Failed to retreive secret address, was this join code already used?",
+                        )?;
+                    info!("Derived peer from join code.");
+                    add_peer_to_config(&self.base_dir, &config_file, &secret_address)?;
+                    secret_address
+                }
+                Peer::SecretAddress(secret_address) => secret_address,
+            };
+            if !secret_addresses.contains(&secret_address) {
+                secret_addresses.push(secret_address);
             }
-        };
+        }
         Ok(Self {
             base_dir: self.base_dir,
-            peer: Some(peer),
+            peers: secret_addresses
+                .into_iter()
+                .map(Peer::SecretAddress)
+                .collect(),
             emit_join_code: self.emit_join_code,
             emit_secret_address: self.emit_secret_address,
+            share_access: self.share_access,
+            peer_approval: self.peer_approval,
+            listen_tcp: self.listen_tcp,
+            relays: self.relays,
+            discovery: self.discovery,
+            bind_port: self.bind_port,
+            reconnect: self.reconnect,
+            read_only_peers: self.read_only_peers,
             sync_vcs: self.sync_vcs,
         })
     }
 
     #[must_use]
-    pub const fn is_host(&self) -> bool {
-        self.peer.is_none()
+    pub fn is_host(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Whether all peers we connect to only gave us read-only access when we last connected.
+    /// Peers we only know the join code of might let us edit.
+    #[must_use]
+    pub fn only_read_only_peers(&self) -> bool {
+        !self.peers.is_empty()
+            && self.peers.iter().all(|peer| match peer {
+                Peer::SecretAddress(address) => self
+                    .read_only_peers
+                    .iter()
+                    .any(|node_id| node_id == node_id_of(address)),
+                Peer::JoinCode(_) => false,
+            })
     }
 
     /// Merges two configurations by taking the "superset" of them.
@@ -128,19 +297,201 @@ impl AppConfig {
             None => self,
             Some(other) => Self {
                 base_dir: self.base_dir,
-                peer: self.peer.or(other.peer),
+                peers: self.peers.into_iter().chain(other.peers).collect(),
                 emit_join_code: self.emit_join_code && other.emit_join_code,
                 emit_secret_address: self.emit_secret_address || other.emit_secret_address,
+                share_access: if other.share_access == Access::ReadOnly {
+                    Access::ReadOnly
+                } else {
+                    self.share_access
+                },
+                peer_approval: if other.peer_approval == PeerApproval::Required {
+                    PeerApproval::Required
+                } else {
+                    self.peer_approval
+                },
+                listen_tcp: self.listen_tcp.or(other.listen_tcp),
+                relays: if self.relays == Relays::Default {
+                    other.relays
+                } else {
+                    self.relays
+                },
+                discovery: if other.discovery == Discovery::Disabled {
+                    Discovery::Disabled
+                } else {
+                    self.discovery
+                },
+                bind_port: self.bind_port.or(other.bind_port),
+                reconnect: if self.reconnect == Reconnect::default() {
+                    other.reconnect
+                } else {
+                    self.reconnect
+                },
+                read_only_peers: self
+                    .read_only_peers
+                    .into_iter()
+                    .chain(other.read_only_peers)
+                    .collect(),
                 sync_vcs: self.sync_vcs || other.sync_vcs,
             },
         }
     }
 }
 
-pub fn store_peer_in_config(directory: &Path, config_file: &Path, peer: &str) -> Result<()> {
-    info!("Storing peer's address in .teamtype/config.");
+/// Returns the enabled peers: the one in `peer = ...`, and those in `[peer.<name>]` sections.
+fn peers_from_config(conf: &Ini) -> Vec<Peer> {
+    let unnamed = conf.general_section().get("peer").map(str::to_string);
+    let named = conf.iter().filter_map(|(section, properties)| {
+        let name = section?.strip_prefix(PEER_SECTION_PREFIX)?;
+        let enabled = properties.get("enabled").map_or(true, |p| {
+            p.parse().unwrap_or_else(|_| {
+                panic!("Failed to parse config parameter `enabled` of peer '{name}' as bool")
+            })
+        });
+        let address = properties
+            .get("address")
+            .unwrap_or_else(|| panic!("Missing config parameter `address` of peer '{name}'"));
+        enabled.then(|| address.to_string())
+    });
+    unnamed
+        .into_iter()
+        .chain(named)
+        .map(Peer::SecretAddress)
+        .collect()
+}
+
+/// Returns the node IDs of the enabled peers which are marked with `read_only = true` (or
+/// `peer_read_only = true`, for the one in `peer = ...`).
+fn read_only_peers_from_config(conf: &Ini) -> Vec<String> {
+    let is_set = |properties: &ini::Properties, key: &str| {
+        properties.get(key).is_some_and(|p| {
+            p.parse()
+                .unwrap_or_else(|_| panic!("Failed to parse config parameter `{key}` as bool"))
+        })
+    };
+    let general = conf.general_section();
+    let unnamed = general
+        .get("peer")
+        .filter(|_| is_set(general, "peer_read_only"));
+    let named = conf.iter().filter_map(|(section, properties)| {
+        section?.strip_prefix(PEER_SECTION_PREFIX)?;
+        let enabled = properties.get("enabled") != Some("false");
+        (enabled && is_set(properties, "read_only"))
+            .then(|| properties.get("address"))
+            .flatten()
+    });
+    unnamed
+        .into_iter()
+        .chain(named)
+        .map(|address| node_id_of(address).to_string())
+        .collect()
+}
+
+/// Returns the node ID in a secret address. It comes first, or after the `#` in TCP addresses.
+fn node_id_of(secret_address: &str) -> &str {
+    let rest = secret_address
+        .strip_prefix("tcp://")
+        .and_then(|rest| rest.split_once('#'))
+        .map_or(secret_address, |(_, rest)| rest);
+    rest.split('#').next().unwrap_or(rest)
+}
+
+/// Marks the configured peer with `node_id` as only giving us read-only access, so that we don't
+/// make any changes after a restart, even before it tells us again. Only adds a line, so the rest
+/// of the file stays as it is. Peers which aren't in the config file aren't remembered.
+pub fn remember_read_only_peer(directory: &Path, config_file: &Path, node_id: &str) -> Result<()> {
+    if !sandbox::exists(directory, config_file)? {
+        return Ok(());
+    }
+    let content = String::from_utf8(sandbox::read_file(directory, config_file)?)?;
+    let conf = Ini::load_from_str(&content).context("Failed to parse config file")?;
+
+    let mut section = None;
+    let mut line_to_add = None;
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = Some(name.trim());
+            if let Some(properties) = conf.section(section) {
+                let matches = name.trim().starts_with(PEER_SECTION_PREFIX)
+                    && properties
+                        .get("address")
+                        .is_some_and(|address| node_id_of(address) == node_id);
+                if matches && properties.get("read_only") != Some("true") {
+                    line_to_add = Some((index, "read_only = true"));
+                    break;
+                }
+            }
+        } else if section.is_none() && line.split(['=', ':']).next().map(str::trim) == Some("peer")
+        {
+            let properties = conf.general_section();
+            let matches = properties
+                .get("peer")
+                .is_some_and(|address| node_id_of(address) == node_id);
+            if matches && properties.get("peer_read_only") != Some("true") {
+                line_to_add = Some((index, "peer_read_only = true"));
+                break;
+            }
+        }
+    }
+    let Some((index, line_to_add)) = line_to_add else {
+        return Ok(());
+    };
+
+    let mut lines: Vec<&str> = content.lines().collect();
+    lines.insert(index + 1, line_to_add);
+    let mut content = lines.join("\n");
+    content.push('\n');
+    sandbox::write_file(directory, config_file, content.as_bytes())
+        .context("Failed to write to config file")
+}
+
+/// Adds a `[peer.<name>]` section to the end of the config file, unless the peer is in there
+/// already. The name is derived from the peer's node ID. The rest of the file, including its
+/// comments, stays as it is.
+pub fn add_peer_to_config(
+    directory: &Path,
+    config_file: &Path,
+    secret_address: &str,
+) -> Result<()> {
+    let mut content = if sandbox::exists(directory, config_file)? {
+        String::from_utf8(sandbox::read_file(directory, config_file)?)?
+    } else {
+        String::new()
+    };
+    let conf = Ini::load_from_str(&content).context("Failed to parse config file")?;
+
+    let known = conf.iter().any(|(section, properties)| {
+        let address = match section {
+            None => properties.get("peer"),
+            Some(section) if section.starts_with(PEER_SECTION_PREFIX) => properties.get("address"),
+            Some(_) => None,
+        };
+        address == Some(secret_address)
+    });
+    if known {
+        return Ok(());
+    }
+
+    let short_id: String = node_id_of(secret_address).chars().take(10).collect();
+    let mut name = short_id.clone();
+    let mut n = 1;
+    while conf
+        .section(Some(format!("{PEER_SECTION_PREFIX}{name}")))
+        .is_some()
+    {
+        n += 1;
+        name = format!("{short_id}-{n}");
+    }
 
-    let content = format!("peer={peer}\n");
+    info!("Storing peer's address in .teamtype/config, as peer '{name}'.");
+    if !content.is_empty() {
+        if !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push('\n');
+    }
+    let content = format!("{content}[{PEER_SECTION_PREFIX}{name}]\naddress = {secret_address}\n");
     sandbox::write_file(directory, config_file, content.as_bytes())
         .context("Failed to write to config file")
 }
@@ -156,11 +507,13 @@ pub fn has_git_remote(path: &Path) -> bool {
 }
 
 #[must_use]
-fn teamtype_directory_should_be_ignored_but_isnt(path: &Path) -> bool {
+pub fn teamtype_directory_should_be_ignored_but_isnt(path: &Path) -> bool {
     if let Ok(repo) = find_git_repo(path) {
-        let teamtype_dir = path.join(CONFIG_DIR);
+        // Git doesn't consider the directory itself ignored when its own .gitignore ignores
+        // everything in it. So check a file inside instead.
+        let file_in_teamtype_dir = path.join(CONFIG_DIR).join(DOC_FILE);
         return !repo
-            .is_path_ignored(teamtype_dir)
+            .is_path_ignored(file_in_teamtype_dir)
             .expect("Should have been able to determine ignore state of path");
     }
     false
@@ -231,3 +584,89 @@ pub fn ensure_teamtype_is_ignored(directory: &Path) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    fn secret_addresses(peers: &[Peer]) -> Vec<&str> {
+        peers
+            .iter()
+            .map(|peer| match peer {
+                Peer::SecretAddress(secret_address) => secret_address.as_str(),
+                Peer::JoinCode(_) => panic!("Expected a secret address"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn adding_peers_keeps_other_settings() {
+        let dir = TempDir::new().unwrap();
+        sandbox::create_dir(dir.path(), &dir.child(CONFIG_DIR)).unwrap();
+        let config_file = dir.child(CONFIG_DIR).join(CONFIG_FILE);
+        let content = "# Our team.\nemit_join_code = false\npeer = aaaa#1\n\n[peer.bob]\naddress = bbbb#2\nenabled = false";
+        sandbox::write_file(dir.path(), &config_file, content.as_bytes()).unwrap();
+
+        add_peer_to_config(dir.path(), &config_file, "cccc#3").unwrap();
+        add_peer_to_config(dir.path(), &config_file, "tcp://127.0.0.1:4000#cccc#3").unwrap();
+        // Known peers aren't added again, even if they're disabled.
+        add_peer_to_config(dir.path(), &config_file, "cccc#3").unwrap();
+        add_peer_to_config(dir.path(), &config_file, "bbbb#2").unwrap();
+
+        let app_config = AppConfig::from_config_file(&config_file).unwrap();
+        assert!(!app_config.emit_join_code);
+        assert_eq!(
+            secret_addresses(&app_config.peers),
+            vec!["aaaa#1", "cccc#3", "tcp://127.0.0.1:4000#cccc#3"]
+        );
+        let conf = Ini::load_from_file(&config_file).unwrap();
+        assert_eq!(
+            conf.section(Some("peer.cccc-2")).unwrap().get("address"),
+            Some("tcp://127.0.0.1:4000#cccc#3")
+        );
+        // The peers are appended, and comments are kept.
+        let new_content =
+            String::from_utf8(sandbox::read_file(dir.path(), &config_file).unwrap()).unwrap();
+        assert_eq!(
+            new_content.strip_prefix(content),
+            Some("\n\n[peer.cccc]\naddress = cccc#3\n\n[peer.cccc-2]\naddress = tcp://127.0.0.1:4000#cccc#3\n")
+        );
+    }
+
+    #[test]
+    fn remembers_read_only_peers() {
+        let dir = TempDir::new().unwrap();
+        sandbox::create_dir(dir.path(), &dir.child(CONFIG_DIR)).unwrap();
+        let config_file = dir.child(CONFIG_DIR).join(CONFIG_FILE);
+        let content = "# The host.\npeer = aaaa#1\n\n[peer.bob]\n# Bob's laptop.\naddress = tcp://127.0.0.1:4000#bbbb#2\n";
+        sandbox::write_file(dir.path(), &config_file, content.as_bytes()).unwrap();
+        assert!(AppConfig::from_config_file(&config_file)
+            .unwrap()
+            .read_only_peers
+            .is_empty());
+
+        remember_read_only_peer(dir.path(), &config_file, "bbbb").unwrap();
+        remember_read_only_peer(dir.path(), &config_file, "bbbb").unwrap();
+        // The host still lets us edit.
+        assert!(!AppConfig::from_config_file(&config_file)
+            .unwrap()
+            .only_read_only_peers());
+        remember_read_only_peer(dir.path(), &config_file, "aaaa").unwrap();
+        remember_read_only_peer(dir.path(), &config_file, "unknown").unwrap();
+
+        assert_eq!(
+            String::from_utf8(sandbox::read_file(dir.path(), &config_file).unwrap()).unwrap(),
+            "# The host.\npeer = aaaa#1\npeer_read_only = true\n\n[peer.bob]\nread_only = true\n# Bob's laptop.\naddress = tcp://127.0.0.1:4000#bbbb#2\n"
+        );
+        assert_eq!(
+            AppConfig::from_config_file(&config_file)
+                .unwrap()
+                .read_only_peers,
+            vec!["aaaa", "bbbb"]
+        );
+        assert!(AppConfig::from_config_file(&config_file)
+            .unwrap()
+            .only_read_only_peers());
+    }
+}
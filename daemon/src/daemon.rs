@@ -4,27 +4,34 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use crate::config::{self, AppConfig};
-use crate::document::Document;
+use crate::document::{Document, FileContent};
 use crate::editor::{self, EditorId, EditorWriter};
 use crate::editor_connection::EditorConnection;
-use crate::path::{AbsolutePath, RelativePath};
+use crate::history;
+use crate::path::{AbsolutePath, FileUri, RelativePath};
 use crate::peer;
+use crate::persistence::Persistence;
+use crate::pidfile;
 use crate::sandbox;
+use crate::timestamp;
 use crate::types::{
-    ComponentMessage, CursorId, CursorState, EditorProtocolMessageError,
-    EditorProtocolMessageFromEditor, EditorProtocolMessageToEditor, EditorProtocolObject,
-    EphemeralMessage, FileTextDelta, JSONRPCFromEditor, JSONRPCResponse, PatchEffect, TextDelta,
+    Access, BlameLine, CompactionResult, ComponentMessage, ConnectionState, CursorId, CursorState,
+    DaemonStatus, EditorProtocolMessageError, EditorProtocolMessageFromEditor,
+    EditorProtocolMessageToEditor, EditorProtocolObject, EditorStatus, EphemeralMessage,
+    FileTextDelta, JSONRPCFromEditor, JSONRPCResponse, OutgoingPeerStatus, PatchEffect,
+    PeerDecision, PeerDirection, PeerStatus, PersistenceStatus, TextDelta,
 };
 use crate::watcher::WatcherEvent;
 use crate::watcher::{Watcher, WatcherEventType};
-use crate::wormhole::put_secret_address_into_wormhole;
-use anyhow::{Context, Result};
+use crate::wormhole::put_invites_into_wormhole;
+use anyhow::{bail, Context, Result};
 use automerge::ChangeHash;
 use automerge::{
     sync::{Message as AutomergeSyncMessage, State as SyncState},
     Patch,
 };
-use futures::SinkExt;
+use futures::stream::FuturesUnordered;
+use futures::{SinkExt, StreamExt};
 use rand::Rng;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
@@ -41,6 +48,13 @@ use tracing::{debug, error, info, warn};
 
 pub const TEST_FILE_PATH: &str = "text";
 
+/// Compact the persistence file after this many incremental saves, even if they're small. Loading
+/// many small fragments is slow.
+const MAX_INCREMENTAL_SAVES: usize = 1000;
+/// Also compact it when the incremental saves take up more space than a full save, and at least
+/// this many bytes.
+const MIN_INCREMENTAL_BYTES_FOR_COMPACTION: usize = 1024 * 1024;
+
 // These messages are sent to the task that owns the document.
 #[must_use]
 pub enum DocMessage {
@@ -64,6 +78,60 @@ pub enum DocMessage {
     NewEditorConnection(EditorId, EditorWriter),
     CloseEditorConnection(EditorId),
     ReceiveEphemeral(EphemeralMessage),
+    PeerConnected {
+        node_id: String,
+        direction: PeerDirection,
+        access: Access,
+    },
+    PeerDisconnected(String),
+    /// The peer with this node ID, which we connected to, only gives us read-only access.
+    ReadOnlyAccess(String),
+    /// Our own address, under which other session members can reach us. It contains no
+    /// passphrase.
+    OwnAddress(String),
+    /// The peer with `node_id` advertised the addresses of the session members it's connected to,
+    /// and its own.
+    PeerMembers {
+        node_id: String,
+        addresses: Vec<String>,
+    },
+    /// Responds with the addresses we advertise to peers with full access.
+    GetMembers {
+        response_tx: oneshot::Sender<Vec<String>>,
+    },
+    /// Responds with the identity of our history, or the one we pinned, if any.
+    GetIdentity {
+        response_tx: oneshot::Sender<Option<String>>,
+    },
+    /// Responds whether the history of a peer with this identity is related to ours. If our
+    /// history has no identity yet, the first one we accept is pinned, so that we don't sync with
+    /// peers of different shared directories at the same time.
+    CheckIdentity {
+        identity: Option<String>,
+        response_tx: oneshot::Sender<bool>,
+    },
+    /// The connection to the peer with `node_id`, which we connect to, got into `state`.
+    PeerConnectionState {
+        node_id: String,
+        state: ConnectionState,
+    },
+    /// A peer we don't know yet wants to connect with `access`. Someone has to decide about it.
+    WaitForApproval {
+        node_id: String,
+        access: Access,
+        response_tx: oneshot::Sender<PeerDecision>,
+    },
+    /// Decides about the waiting peer whose node ID starts with `node_id`. Responds with its full
+    /// node ID.
+    DecideOnPeer {
+        node_id: String,
+        decision: PeerDecision,
+        response_tx: oneshot::Sender<Result<String>>,
+    },
+    /// Persist the document one last time, because the daemon is about to shut down.
+    Shutdown {
+        response_tx: oneshot::Sender<()>,
+    },
 }
 
 impl fmt::Debug for DocMessage {
@@ -80,6 +148,22 @@ impl fmt::Debug for DocMessage {
             Self::NewEditorConnection(id, _) => format!("NewEditorConnection({id})"),
             Self::CloseEditorConnection(id) => format!("CloseEditorConnection({id})"),
             Self::ReceiveEphemeral(m) => format!("ReceiveEphemeral({m:?})"),
+            Self::PeerConnected { node_id, .. } => format!("PeerConnected({node_id})"),
+            Self::PeerDisconnected(node_id) => format!("PeerDisconnected({node_id})"),
+            Self::ReadOnlyAccess(node_id) => format!("ReadOnlyAccess({node_id})"),
+            Self::OwnAddress(_) => "OwnAddress".to_string(),
+            Self::PeerMembers { node_id, .. } => format!("PeerMembers({node_id})"),
+            Self::GetMembers { .. } => "GetMembers".to_string(),
+            Self::GetIdentity { .. } => "GetIdentity".to_string(),
+            Self::CheckIdentity { identity, .. } => format!("CheckIdentity({identity:?})"),
+            Self::PeerConnectionState { node_id, state } => {
+                format!("PeerConnectionState({node_id}, {state:?})")
+            }
+            Self::WaitForApproval { node_id, .. } => format!("WaitForApproval({node_id})"),
+            Self::DecideOnPeer {
+                node_id, decision, ..
+            } => format!("DecideOnPeer({node_id}, {decision:?})"),
+            Self::Shutdown { .. } => "Shutdown".to_string(),
         };
         write!(f, "{repr}")
     }
@@ -90,6 +174,36 @@ type DocChangedSender = broadcast::Sender<()>;
 type DocChangedReceiver = broadcast::Receiver<()>;
 type EphemeralMessageSender = broadcast::Sender<EphemeralMessage>;
 type EphemeralMessageReceiver = broadcast::Receiver<EphemeralMessage>;
+type ShutdownRequestSender = broadcast::Sender<()>;
+type ShutdownRequestReceiver = broadcast::Receiver<()>;
+type PeerCommandSender = broadcast::Sender<PeerCommand>;
+pub type PeerCommandReceiver = broadcast::Receiver<PeerCommand>;
+type MembersSender = broadcast::Sender<Vec<String>>;
+pub type MembersReceiver = broadcast::Receiver<Vec<String>>;
+type ApprovalRequestSender = broadcast::Sender<(String, Access)>;
+pub type ApprovalRequestReceiver = broadcast::Receiver<(String, Access)>;
+
+/// Instructions for the connection manager, which come from requests to the daemon.
+#[derive(Debug, Clone)]
+pub enum PeerCommand {
+    /// Close the connections of the peer with this node ID.
+    Disconnect(String),
+    /// Require this passphrase from now on, and close the connections of peers which
+    /// authenticated with the old one.
+    UsePassphrase(iroh::SecretKey),
+    /// Let the session member with this `address` in while the peer `voucher`, which has full
+    /// access, is connected and trusted, and connect to it, unless we're connected to it already.
+    Connect { voucher: String, address: String },
+    /// The peer with this node ID disconnected, so it doesn't vouch for any session members
+    /// anymore.
+    Left(String),
+}
+
+/// A peer whose connection waits until someone approves it.
+struct WaitingPeer {
+    access: Access,
+    response_tx: oneshot::Sender<PeerDecision>,
+}
 
 /// This Actor is responsible for applying changes to the document asynchronously.
 ///
@@ -99,12 +213,40 @@ pub struct DocumentActor {
     doc_message_rx: mpsc::Receiver<DocMessage>,
     doc_changed_ping_tx: DocChangedSender,
     ephemeral_message_tx: EphemeralMessageSender,
+    shutdown_request_tx: ShutdownRequestSender,
+    peer_command_tx: PeerCommandSender,
+    approval_request_tx: ApprovalRequestSender,
+    members_tx: MembersSender,
     editor_connections: HashMap<EditorId, (EditorConnection, EditorWriter)>,
     ephemeral_states: HashMap<CursorId, EphemeralMessage>,
+    /// Peers we're currently syncing with, by node ID.
+    peers: HashMap<String, PeerStatus>,
+    /// Peers we connect to, by node ID.
+    outgoing_peers: HashMap<String, OutgoingPeerStatus>,
+    /// Peers waiting for approval, by node ID.
+    waiting_peers: HashMap<String, WaitingPeer>,
+    /// Our own secret address, once the connection manager told us.
+    own_address: Option<String>,
+    /// The secret addresses which peers advertised as their own, by node ID.
+    member_addresses: HashMap<String, String>,
+    /// The identity of the first peer's history we accepted, while ours has none yet.
+    pinned_identity: Option<String>,
+    /// Whether the peers we're connected to only give us read-only access. Then we don't make
+    /// changes ourselves, because they would reject them. See `update_read_only`.
+    read_only: bool,
     /// The Document is the main I/O managed resource of this actor.
     crdt_doc: Document,
     app_config: AppConfig,
+    /// Where we keep the history between runs, if anywhere.
+    persistence: Option<Box<dyn Persistence>>,
     save_fully: bool,
+    /// How often, and how many bytes, we appended to the persisted history since the last full
+    /// save. See `needs_compaction`.
+    incremental_saves: usize,
+    incremental_bytes: usize,
+    full_save_bytes: usize,
+    last_persisted: Option<i64>,
+    persisted_heads: Vec<ChangeHash>,
 }
 
 impl DocumentActor {
@@ -115,43 +257,87 @@ impl DocumentActor {
         app_config: AppConfig,
         init: bool,
         is_host: bool,
-        persist: bool,
+        mut persistence: Option<Box<dyn Persistence>>,
     ) -> Self {
-        // If there is a persisted version in base_dir/.teamtype/doc, load it.
-        // TODO: Pull out ".teamtype" string into a constant.
-        let persistence_file = app_config.base_dir.join(".teamtype/doc");
-        let persistence_file_exists = sandbox::exists(&app_config.base_dir, &persistence_file)
-            .expect("Could not check for the existence of the persistence file");
-
-        let load_crdt_doc = persistence_file_exists && !init && persist;
-        let crdt_doc = if load_crdt_doc {
-            debug!(
-                "Loading persisted CRDT document from '{}'.",
-                persistence_file.display()
-            );
-            let bytes = sandbox::read_file(&app_config.base_dir, &persistence_file)
-                .unwrap_or_else(|_| panic!("Could not read file '{}'", persistence_file.display()));
-            Document::load(&bytes)
-        } else {
-            Document::default()
+        // If there is a persisted version (usually in base_dir/.teamtype/doc), load it.
+        let persisted_doc_exists = persistence.as_ref().is_some_and(|persistence| {
+            persistence
+                .exists()
+                .expect("Could not check for the existence of the persisted history")
+        });
+
+        let mut crdt_doc = match persistence.as_mut() {
+            Some(persistence) if persisted_doc_exists && !init => {
+                debug!("Loading persisted CRDT document from {persistence}.");
+                persistence
+                    .load()
+                    .unwrap_or_else(|err| panic!("Could not load {persistence}: {err:#}"))
+            }
+            _ => Document::default(),
         };
         debug!("Loading CRDT document completed.");
 
+        if let Some(username) = config::get_username(&app_config.base_dir) {
+            crdt_doc.set_author(&username);
+        }
+
+        // We will send a ping on this channel when someone asks the daemon to shut down.
+        let (shutdown_request_tx, _shutdown_request_rx) = broadcast::channel::<()>(1);
+        // The connection manager will receive instructions on this channel.
+        let (peer_command_tx, _peer_command_rx) = broadcast::channel::<PeerCommand>(16);
+        // The terminal prompt will receive the peers waiting for approval on this channel.
+        let (approval_request_tx, _approval_request_rx) =
+            broadcast::channel::<(String, Access)>(16);
+        // The sync tasks will receive the secret addresses to advertise to their peers on this
+        // channel, whenever they change.
+        let (members_tx, _members_rx) = broadcast::channel::<Vec<String>>(16);
+
+        // Before we make any change, find out whether the peers only let us watch.
+        let read_only = app_config.only_read_only_peers();
+        if read_only {
+            warn!("The peers only gave us read-only access last time. You can watch, but edits will be rejected.");
+        }
+
         let mut s = Self {
             doc_message_rx,
             doc_changed_ping_tx,
             ephemeral_message_tx,
+            shutdown_request_tx,
+            peer_command_tx,
+            approval_request_tx,
+            members_tx,
             editor_connections: HashMap::default(),
             ephemeral_states: HashMap::default(),
+            peers: HashMap::default(),
+            waiting_peers: HashMap::default(),
+            own_address: None,
+            member_addresses: HashMap::default(),
+            outgoing_peers: HashMap::default(),
+            pinned_identity: None,
+            read_only,
             app_config,
             crdt_doc,
+            persistence,
             save_fully: true,
+            incremental_saves: 0,
+            incremental_bytes: 0,
+            full_save_bytes: 0,
+            last_persisted: None,
+            persisted_heads: vec![],
         };
 
-        if persistence_file_exists && persist {
-            s.read_current_content_from_dir(init);
-        } else if is_host {
+        if is_host && (init || !persisted_doc_exists) {
             s.read_current_content_from_dir(true);
+        } else if read_only {
+            debug!("Not scanning files, because we only have read-only access.");
+        } else if persisted_doc_exists && !init {
+            s.read_current_content_from_dir(false);
+        }
+        // Otherwise, we're joining with an empty history, and get everything from the peer.
+
+        // Peers which join adopt the identity of our history.
+        if is_host {
+            s.crdt_doc.ensure_identity();
         }
 
         s
@@ -189,27 +375,15 @@ impl DocumentActor {
                 self.handle_watcher_event(&watcher_event);
             }
             DocMessage::RescanFiles => {
-                self.read_current_content_from_dir(false);
-            }
-            DocMessage::Persist => {
-                let persistence_file = self.app_config.base_dir.join(".teamtype/doc");
-                if self.save_fully {
-                    debug!("Persisting CRDT document fully.");
-                    let bytes = self.crdt_doc.save();
-                    sandbox::write_file(&self.app_config.base_dir, &persistence_file, &bytes)
-                        .unwrap_or_else(|_| {
-                            panic!("Failed to persist to '{}'", persistence_file.display())
-                        });
-                    self.save_fully = false;
+                if self.read_only {
+                    debug!("Not rescanning files, because we only have read-only access.");
                 } else {
-                    debug!("Persisting CRDT document incrementally.");
-                    let bytes = self.crdt_doc.save_incremental();
-                    sandbox::append_file(&self.app_config.base_dir, &persistence_file, &bytes)
-                        .unwrap_or_else(|_| {
-                            panic!("Failed to persist to '{}'", persistence_file.display())
-                        });
+                    self.read_current_content_from_dir(false);
                 }
             }
+            DocMessage::Persist => {
+                self.persist();
+            }
             DocMessage::ReceiveSyncMessage {
                 message,
                 state: mut peer_state,
@@ -275,6 +449,9 @@ impl DocumentActor {
                                 } else {
                                     info!("Peer deleted {file_path}, but you have it open in an editor. Bringing back an empty version.");
                                     self.crdt_doc.update_text("", &file_path);
+                                    self.crdt_doc.commit(&format!(
+                                        "Bring back {file_path}, which is open in an editor"
+                                    ));
                                 }
                             }
                         }
@@ -327,6 +504,49 @@ impl DocumentActor {
                     };
                     self.send_to_editor(id, &message).await;
                 }
+
+                if self.read_only {
+                    self.send_to_editor_client(
+                        &id,
+                        EditorProtocolObject::Request(EditorProtocolMessageToEditor::ReadOnly),
+                    )
+                    .await;
+                }
+
+                let outgoing_peers: Vec<(String, ConnectionState)> = self
+                    .outgoing_peers
+                    .values()
+                    .map(|peer| (peer.node_id.clone(), peer.state))
+                    .collect();
+                for (node_id, state) in outgoing_peers {
+                    self.send_to_editor_client(
+                        &id,
+                        EditorProtocolObject::Request(EditorProtocolMessageToEditor::PeerState {
+                            node_id,
+                            state,
+                        }),
+                    )
+                    .await;
+                }
+
+                self.forget_peers_which_stopped_waiting();
+                let waiting_peers: Vec<(String, Access)> = self
+                    .waiting_peers
+                    .iter()
+                    .map(|(node_id, peer)| (node_id.clone(), peer.access))
+                    .collect();
+                for (node_id, access) in waiting_peers {
+                    self.send_to_editor_client(
+                        &id,
+                        EditorProtocolObject::Request(
+                            EditorProtocolMessageToEditor::PeerWaitingForApproval {
+                                node_id,
+                                access,
+                            },
+                        ),
+                    )
+                    .await;
+                }
             }
             DocMessage::CloseEditorConnection(editor_id) => {
                 self.editor_connections.remove(&editor_id);
@@ -338,20 +558,237 @@ impl DocumentActor {
             DocMessage::ReceiveEphemeral(ephemeral_message) => {
                 self.react_to_ephemeral_message(ephemeral_message).await;
             }
+            DocMessage::PeerConnected {
+                node_id,
+                direction,
+                mut access,
+            } => {
+                // Peers tell us that we only have read-only access right after connecting. Until
+                // then, go by what they let us do last time.
+                if direction == PeerDirection::Outgoing
+                    && self.app_config.read_only_peers.contains(&node_id)
+                {
+                    access = Access::ReadOnly;
+                }
+                self.peers.insert(
+                    node_id.clone(),
+                    PeerStatus {
+                        node_id,
+                        direction,
+                        access,
+                        connected_since: timestamp::now(),
+                    },
+                );
+                self.update_read_only().await;
+            }
+            DocMessage::PeerDisconnected(node_id) => {
+                self.peers.remove(&node_id);
+                let _ = self.peer_command_tx.send(PeerCommand::Left(node_id));
+                self.update_read_only().await;
+            }
+            DocMessage::OwnAddress(address) => {
+                self.own_address = Some(address);
+                let _ = self.members_tx.send(self.members());
+            }
+            DocMessage::PeerMembers { node_id, addresses } => {
+                self.react_to_peer_members(&node_id, addresses);
+            }
+            DocMessage::GetMembers { response_tx } => {
+                let _ = response_tx.send(self.members());
+            }
+            DocMessage::GetIdentity { response_tx } => {
+                let _ = response_tx.send(self.identity());
+            }
+            DocMessage::CheckIdentity {
+                identity,
+                response_tx,
+            } => {
+                let _ = response_tx.send(self.check_identity(identity));
+            }
+            DocMessage::ReadOnlyAccess(node_id) => {
+                if let Some(peer) = self.peers.get_mut(&node_id) {
+                    peer.access = Access::ReadOnly;
+                }
+                if !self.app_config.read_only_peers.contains(&node_id) {
+                    let config_file = self
+                        .app_config
+                        .base_dir
+                        .join(config::CONFIG_DIR)
+                        .join(config::CONFIG_FILE);
+                    if let Err(err) = config::remember_read_only_peer(
+                        &self.app_config.base_dir,
+                        &config_file,
+                        &node_id,
+                    ) {
+                        warn!("Failed to remember that peer {node_id} gives us read-only access: {err:#}");
+                    }
+                    self.app_config.read_only_peers.push(node_id.clone());
+                }
+                info!("Peer {node_id} only gives us read-only access.");
+                self.update_read_only().await;
+            }
+            DocMessage::PeerConnectionState { node_id, state } => {
+                self.outgoing_peers.insert(
+                    node_id.clone(),
+                    OutgoingPeerStatus {
+                        node_id: node_id.clone(),
+                        state,
+                        since: timestamp::now(),
+                    },
+                );
+                let editor_ids: Vec<EditorId> = self.editor_connections.keys().copied().collect();
+                for editor_id in editor_ids {
+                    self.send_to_editor_client(
+                        &editor_id,
+                        EditorProtocolObject::Request(EditorProtocolMessageToEditor::PeerState {
+                            node_id: node_id.clone(),
+                            state,
+                        }),
+                    )
+                    .await;
+                }
+            }
+            DocMessage::WaitForApproval {
+                node_id,
+                access,
+                response_tx,
+            } => {
+                let access_description = match access {
+                    Access::Full => "",
+                    Access::ReadOnly => " with read-only access",
+                };
+                info!("Peer {node_id} wants to connect{access_description}. Approve it with `teamtype approve {node_id}` (add --trust to trust it from now on), or reject it with `teamtype reject {node_id}`.");
+                self.waiting_peers.insert(
+                    node_id.clone(),
+                    WaitingPeer {
+                        access,
+                        response_tx,
+                    },
+                );
+                let _ = self.approval_request_tx.send((node_id.clone(), access));
+                let editor_ids: Vec<EditorId> = self.editor_connections.keys().copied().collect();
+                for editor_id in editor_ids {
+                    self.send_to_editor_client(
+                        &editor_id,
+                        EditorProtocolObject::Request(
+                            EditorProtocolMessageToEditor::PeerWaitingForApproval {
+                                node_id: node_id.clone(),
+                                access,
+                            },
+                        ),
+                    )
+                    .await;
+                }
+            }
+            DocMessage::DecideOnPeer {
+                node_id,
+                decision,
+                response_tx,
+            } => {
+                let _ = response_tx.send(self.decide_on_peer(&node_id, decision));
+            }
+            DocMessage::Shutdown { response_tx } => {
+                if self.persistence.is_some() {
+                    self.persist();
+                }
+                if response_tx.send(()).is_err() {
+                    warn!("Failed to confirm that the document is ready for shutdown.");
+                }
+            }
         }
     }
 
+    fn persist(&mut self) {
+        if self.save_fully {
+            debug!("Persisting CRDT document fully.");
+            self.compact()
+                .unwrap_or_else(|err| panic!("Failed to persist the document: {err:#}"));
+        } else if self.needs_compaction() {
+            let compaction = self
+                .compact()
+                .unwrap_or_else(|err| panic!("Failed to compact the document: {err:#}"));
+            info!("Compacted the history {compaction}.");
+        } else {
+            debug!("Persisting CRDT document incrementally.");
+            let Some(persistence) = self.persistence.as_mut() else {
+                return;
+            };
+            let bytes = self.crdt_doc.save_incremental();
+            if !bytes.is_empty() {
+                persistence
+                    .append(&bytes)
+                    .unwrap_or_else(|err| panic!("Failed to persist the document: {err:#}"));
+                self.incremental_saves += 1;
+                self.incremental_bytes += bytes.len();
+            }
+            self.last_persisted = Some(timestamp::now());
+            self.persisted_heads = self.get_heads();
+        }
+    }
+
+    /// Whether the incremental saves appended to the persisted history have piled up enough to
+    /// replace them with a full save.
+    const fn needs_compaction(&self) -> bool {
+        self.incremental_saves >= MAX_INCREMENTAL_SAVES
+            || (self.incremental_bytes > self.full_save_bytes
+                && self.incremental_bytes >= MIN_INCREMENTAL_BYTES_FOR_COMPACTION)
+    }
+
+    /// Replaces the persisted history with a single full save.
+    fn compact(&mut self) -> Result<CompactionResult> {
+        let Some(persistence) = self.persistence.as_mut() else {
+            bail!("This daemon doesn't persist its history");
+        };
+        let size_before = persistence.size()?;
+        let bytes = self.crdt_doc.save();
+        persistence.save(&bytes)?;
+
+        self.save_fully = false;
+        self.incremental_saves = 0;
+        self.incremental_bytes = 0;
+        self.full_save_bytes = bytes.len();
+        self.last_persisted = Some(timestamp::now());
+        self.persisted_heads = self.get_heads();
+
+        Ok(CompactionResult {
+            size_before,
+            size_after: bytes.len() as u64,
+        })
+    }
+
+    fn file_path_for_uri(&self, uri: &str) -> Result<RelativePath> {
+        let absolute_path = FileUri::try_from(uri.to_string())?.to_absolute_path();
+        RelativePath::try_from_absolute(&self.app_config.base_dir, &absolute_path)
+    }
+
     fn absolute_path_for_file_path(&self, file_path: &RelativePath) -> AbsolutePath {
         AbsolutePath::from_parts(&self.app_config.base_dir, file_path)
             .expect("base_dir should be absolute")
     }
 
-    // Returns the messages to send back to the editor which made the request.
+    // Returns the result of the request, and the messages to send back to the editor which made
+    // the request.
     async fn react_to_message_from_editor(
         &mut self,
         editor_id: EditorId,
         message: &EditorProtocolMessageFromEditor,
-    ) -> Result<Vec<EditorProtocolMessageToEditor>, EditorProtocolMessageError> {
+    ) -> Result<(serde_json::Value, Vec<EditorProtocolMessageToEditor>), EditorProtocolMessageError>
+    {
+        // Some requests are about the daemon as a whole, and don't concern the editor connection.
+        if let Some(result) = self.react_to_daemon_request(editor_id, message).await {
+            return result.map(|result| (result, vec![]));
+        }
+
+        if self.read_only && self.would_change_document(message) {
+            return Err(EditorProtocolMessageError {
+                code: -1,
+                message: "This daemon only has read-only access".into(),
+                data: Some(
+                    "The peer you joined only lets you watch, so edits are not possible.".into(),
+                ),
+            });
+        }
+
         // First, convert the editor message into a component message (+ transformed edits from the
         // OT server).
         let (inside_message, mut messages_to_editor) = self
@@ -375,7 +812,350 @@ impl DocumentActor {
 
         messages_to_editor.append(&mut more_messages_to_editor);
 
-        Ok(messages_to_editor)
+        Ok(("success".into(), messages_to_editor))
+    }
+
+    /// Whether the message from an editor would make a change to the document. Opening a file
+    /// which isn't in the document yet creates it.
+    fn would_change_document(&self, message: &EditorProtocolMessageFromEditor) -> bool {
+        match message {
+            EditorProtocolMessageFromEditor::Edit { .. } => true,
+            EditorProtocolMessageFromEditor::Open { uri, .. } => self
+                .file_path_for_uri(uri)
+                .is_ok_and(|file_path| !self.crdt_doc.file_exists(&file_path)),
+            _ => false,
+        }
+    }
+
+    /// Returns `None` if the message is not a daemon request, but meant for the editor connection.
+    async fn react_to_daemon_request(
+        &mut self,
+        editor_id: EditorId,
+        message: &EditorProtocolMessageFromEditor,
+    ) -> Option<Result<serde_json::Value, EditorProtocolMessageError>> {
+        match message {
+            EditorProtocolMessageFromEditor::Status => {
+                let status = self.status(editor_id);
+                Some(Ok(
+                    serde_json::to_value(status).expect("Failed to convert daemon status to JSON")
+                ))
+            }
+            EditorProtocolMessageFromEditor::Blame { uri } => Some(
+                self.blame(uri)
+                    .map(|blame| {
+                        serde_json::to_value(blame).expect("Failed to convert blame to JSON")
+                    })
+                    .map_err(|error| EditorProtocolMessageError {
+                        code: -1,
+                        message: "Failed to blame file".into(),
+                        data: Some(format!("{error:#}")),
+                    }),
+            ),
+            EditorProtocolMessageFromEditor::Restore { uri, at } => Some(
+                self.restore(uri, at)
+                    .await
+                    .map(|()| "success".into())
+                    .map_err(|error| EditorProtocolMessageError {
+                        code: -1,
+                        message: "Failed to restore file".into(),
+                        data: Some(format!("{error:#}")),
+                    }),
+            ),
+            EditorProtocolMessageFromEditor::Ping => Some(Ok("pong".into())),
+            EditorProtocolMessageFromEditor::Compact => Some(
+                self.compact()
+                    .map(|compaction| {
+                        serde_json::to_value(compaction)
+                            .expect("Failed to convert compaction result to JSON")
+                    })
+                    .map_err(|error| EditorProtocolMessageError {
+                        code: -1,
+                        message: "Failed to compact the history".into(),
+                        data: Some(format!("{error:#}")),
+                    }),
+            ),
+            EditorProtocolMessageFromEditor::Shutdown => {
+                info!("Shutdown requested.");
+                // The main task does the actual shutdown, after we've responded.
+                let _ = self.shutdown_request_tx.send(());
+                Some(Ok("success".into()))
+            }
+            EditorProtocolMessageFromEditor::RotateSecret => Some(
+                self.rotate_secret()
+                    .map(serde_json::Value::from)
+                    .map_err(|error| EditorProtocolMessageError {
+                        code: -1,
+                        message: "Failed to rotate the secret".into(),
+                        data: Some(format!("{error:#}")),
+                    }),
+            ),
+            EditorProtocolMessageFromEditor::Disconnect { node_id } => Some(
+                self.disconnect_peer(node_id)
+                    .map(serde_json::Value::from)
+                    .map_err(|error| EditorProtocolMessageError {
+                        code: -1,
+                        message: "Failed to disconnect the peer".into(),
+                        data: Some(format!("{error:#}")),
+                    }),
+            ),
+            EditorProtocolMessageFromEditor::DecideOnPeer { node_id, decision } => Some(
+                self.decide_on_peer(node_id, *decision)
+                    .map(serde_json::Value::from)
+                    .map_err(|error| EditorProtocolMessageError {
+                        code: -1,
+                        message: "Failed to decide on the peer".into(),
+                        data: Some(format!("{error:#}")),
+                    }),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Gives the daemon a new passphrase, and returns the new secret address.
+    fn rotate_secret(&self) -> Result<String> {
+        let (secret_address, passphrase) = peer::rotate_passphrase(&self.app_config.base_dir)?;
+        let _ = self
+            .peer_command_tx
+            .send(PeerCommand::UsePassphrase(passphrase));
+        Ok(secret_address)
+    }
+
+    /// Closes the connections of the peer which connected to us, and whose node ID starts with
+    /// `prefix`. Returns its full node ID.
+    fn disconnect_peer(&self, prefix: &str) -> Result<String> {
+        let matching: Vec<&PeerStatus> = self
+            .peers
+            .values()
+            .filter(|peer| {
+                peer.direction == PeerDirection::Incoming && peer.node_id.starts_with(prefix)
+            })
+            .collect();
+        let node_id = match &matching[..] {
+            [peer] => peer.node_id.clone(),
+            [] => bail!("No peer with a node ID starting with '{prefix}' is connected to us"),
+            _ => bail!("Several connected peers have a node ID starting with '{prefix}'"),
+        };
+        let _ = self
+            .peer_command_tx
+            .send(PeerCommand::Disconnect(node_id.clone()));
+        Ok(node_id)
+    }
+
+    /// Decides whether we only have read-only access, from what each peer we connected to lets us
+    /// do: we can edit as long as one of them lets us. While we aren't connected to any, we go by
+    /// what the configured peers let us do last time. Tells the editors when we can't edit
+    /// anymore.
+    async fn update_read_only(&mut self) {
+        let granted: Vec<Access> = self
+            .peers
+            .values()
+            .filter(|peer| peer.direction == PeerDirection::Outgoing)
+            .map(|peer| peer.access)
+            .collect();
+        let read_only = if granted.is_empty() {
+            self.app_config.only_read_only_peers()
+        } else {
+            granted.iter().all(|access| *access == Access::ReadOnly)
+        };
+        if read_only == self.read_only {
+            return;
+        }
+        self.read_only = read_only;
+        if read_only {
+            warn!("The peers only give us read-only access. You can watch, but edits will be rejected.");
+            let editor_ids: Vec<EditorId> = self.editor_connections.keys().copied().collect();
+            for editor_id in editor_ids {
+                self.send_to_editor_client(
+                    &editor_id,
+                    EditorProtocolObject::Request(EditorProtocolMessageToEditor::ReadOnly),
+                )
+                .await;
+            }
+        } else {
+            info!("A peer lets us edit. Editors which were told that we only have read-only access have to reconnect.");
+        }
+    }
+
+    /// The addresses we advertise to peers with full access: our own, and those of the connected
+    /// peers with full access. They contain no passphrases. If we only have read-only access, we
+    /// don't take part.
+    fn members(&self) -> Vec<String> {
+        if self.read_only {
+            return vec![];
+        }
+        let mut members: Vec<String> = self
+            .member_addresses
+            .iter()
+            .filter(|(node_id, _)| {
+                self.peers
+                    .get(*node_id)
+                    .is_some_and(|peer| peer.access == Access::Full)
+            })
+            .map(|(_, address)| address.clone())
+            .collect();
+        members.sort();
+        members.extend(self.own_address.clone());
+        members
+    }
+
+    /// Remembers the address a peer advertised as its own, and lets in and connects to the other
+    /// session members it advertised. Only peers with full access can vouch for members.
+    fn react_to_peer_members(&mut self, node_id: &str, addresses: Vec<String>) {
+        let has_full_access = self
+            .peers
+            .get(node_id)
+            .is_some_and(|peer| peer.access == Access::Full);
+        if !has_full_access || self.read_only {
+            debug!("Ignoring the session members advertised by peer {node_id}.");
+            return;
+        }
+
+        for address in addresses {
+            if peer::member_node_id(&address) == node_id {
+                let previous = self
+                    .member_addresses
+                    .insert(node_id.to_string(), address.clone());
+                if previous.as_ref() != Some(&address) {
+                    let _ = self.members_tx.send(self.members());
+                }
+            } else {
+                let _ = self.peer_command_tx.send(PeerCommand::Connect {
+                    voucher: node_id.to_string(),
+                    address,
+                });
+            }
+        }
+    }
+
+    /// The identity of our history, or, if it has none yet, the one of the first peer we accepted.
+    fn identity(&self) -> Option<String> {
+        self.crdt_doc
+            .identity()
+            .or_else(|| self.pinned_identity.clone())
+    }
+
+    /// Whether we can sync with a peer whose history has the identity `theirs`. A history without
+    /// an identity, but with changes, was started on its own, so it's unrelated to any history
+    /// with an identity. A peer without an identity hasn't synced yet, so it's related to ours.
+    fn check_identity(&mut self, theirs: Option<String>) -> bool {
+        let Some(theirs) = theirs else {
+            return true;
+        };
+        match self.identity() {
+            Some(ours) => ours == theirs,
+            None if !self.crdt_doc.is_empty() => false,
+            None => {
+                debug!("Pinning the identity {theirs}, until our history adopts it.");
+                self.pinned_identity = Some(theirs);
+                true
+            }
+        }
+    }
+
+    /// Lets the peer waiting for approval, whose node ID starts with `prefix`, know what we decided.
+    /// Returns its full node ID.
+    fn decide_on_peer(&mut self, prefix: &str, decision: PeerDecision) -> Result<String> {
+        self.forget_peers_which_stopped_waiting();
+        let matching: Vec<&String> = self
+            .waiting_peers
+            .keys()
+            .filter(|node_id| node_id.starts_with(prefix))
+            .collect();
+        let node_id = match &matching[..] {
+            [node_id] => (*node_id).clone(),
+            [] => bail!("No peer with a node ID starting with '{prefix}' waits for approval"),
+            _ => bail!("Several waiting peers have a node ID starting with '{prefix}'"),
+        };
+        let peer = self
+            .waiting_peers
+            .remove(&node_id)
+            .expect("Waiting peer should exist");
+        if peer.response_tx.send(decision).is_err() {
+            bail!("Peer {node_id} stopped waiting");
+        }
+        Ok(node_id)
+    }
+
+    /// Peers can disconnect while they wait.
+    fn forget_peers_which_stopped_waiting(&mut self) {
+        self.waiting_peers
+            .retain(|_, peer| !peer.response_tx.is_closed());
+    }
+
+    fn blame(&mut self, uri: &str) -> Result<Vec<BlameLine>> {
+        let file_path = self.file_path_for_uri(uri)?;
+        self.crdt_doc.blame(&file_path)
+    }
+
+    /// Restores a file to its content at an earlier point in history. This is a regular change, so
+    /// it's synced to all peers and editors.
+    async fn restore(&mut self, uri: &str, at: &str) -> Result<()> {
+        if self.read_only {
+            bail!("This daemon only has read-only access");
+        }
+        let file_path = self.file_path_for_uri(uri)?;
+        let heads = history::resolve_at(&mut self.crdt_doc, at)?;
+
+        match self.crdt_doc.file_at(&file_path, &heads)? {
+            FileContent::Text(text) => {
+                info!("Restoring {file_path} to its content at '{at}'.");
+                let delta = self.crdt_doc.update_text(&text, &file_path);
+                self.crdt_doc
+                    .commit(&format!("Restore {file_path} to its content at '{at}'"));
+                let _ = self.doc_changed_ping_tx.send(());
+                self.write_file(&file_path);
+                if let Some(delta) = delta {
+                    let message = ComponentMessage::Edit { file_path, delta };
+                    self.broadcast_to_editors(None, &message).await;
+                }
+            }
+            FileContent::Binary(bytes) => {
+                info!("Restoring binary {file_path} to its content at '{at}'.");
+                self.crdt_doc.set_bytes(&bytes, &file_path);
+                self.crdt_doc
+                    .commit(&format!("Restore {file_path} to its content at '{at}'"));
+                let _ = self.doc_changed_ping_tx.send(());
+                self.ensure_file_has_bytes(&file_path, &bytes);
+            }
+        }
+        Ok(())
+    }
+
+    /// Describes the state of the daemon, as seen by editor `requested_by` (which is left out).
+    fn status(&mut self, requested_by: EditorId) -> DaemonStatus {
+        let mut peers: Vec<PeerStatus> = self.peers.values().cloned().collect();
+        peers.sort_by_key(|peer| peer.connected_since);
+        let mut outgoing_peers: Vec<OutgoingPeerStatus> =
+            self.outgoing_peers.values().cloned().collect();
+        outgoing_peers.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+
+        let mut editors: Vec<EditorStatus> = self
+            .editor_connections
+            .iter()
+            .filter(|(id, _)| **id != requested_by)
+            .map(|(id, (connection, _))| EditorStatus {
+                id: *id,
+                username: connection.username().map(str::to_string),
+                open_files: connection.owned_files(),
+            })
+            .collect();
+        editors.sort_by_key(|editor| editor.id);
+
+        let heads = self.get_heads();
+
+        DaemonStatus {
+            base_dir: self.app_config.base_dir.clone(),
+            peers,
+            outgoing_peers,
+            editors,
+            file_count: self.crdt_doc.files().len(),
+            heads: heads.iter().map(ToString::to_string).collect(),
+            persistence: PersistenceStatus {
+                enabled: self.persistence.is_some(),
+                last_persisted: self.last_persisted,
+                up_to_date: self.persistence.is_some() && heads == self.persisted_heads,
+            },
+        }
     }
 
     #[must_use]
@@ -400,12 +1180,12 @@ impl DocumentActor {
                             )
                             .await;
                         }
-                        Ok(messages) => {
+                        Ok((result, messages)) => {
                             self.send_to_editor_client(
                                 &editor_id,
                                 EditorProtocolObject::Response(JSONRPCResponse::RequestSuccess {
                                     id,
-                                    result: "success".into(),
+                                    result,
                                 }),
                             )
                             .await;
@@ -444,6 +1224,17 @@ impl DocumentActor {
             RelativePath::try_from_path(&self.app_config.base_dir, &watcher_event.file_path)
                 .expect("Watcher event should have a path within the base directory");
 
+        if self.read_only {
+            // Removals aren't checked against the ignore rules, so only mention those of files
+            // we know.
+            if watcher_event.event_type != WatcherEventType::Removed
+                || self.crdt_doc.file_exists(&relative_file_path)
+            {
+                warn!("Ignoring the change to {relative_file_path} on disk, because we only have read-only access.");
+            }
+            return;
+        }
+
         if self.owns(&relative_file_path) {
             match watcher_event.event_type {
                 WatcherEventType::Created | WatcherEventType::Changed => {
@@ -458,6 +1249,8 @@ impl DocumentActor {
 
     fn file_removed(&mut self, relative_file_path: &RelativePath) {
         self.remove_file(relative_file_path);
+        self.crdt_doc
+            .commit(&format!("Remove {relative_file_path} (deleted on disk)"));
         let _ = self.doc_changed_ping_tx.send(());
     }
 
@@ -483,6 +1276,8 @@ impl DocumentActor {
         } else {
             self.crdt_doc.set_bytes(&new_content, relative_file_path);
         }
+        self.crdt_doc
+            .commit(&format!("Update {relative_file_path} (changed on disk)"));
         let _ = self.doc_changed_ping_tx.send(());
     }
 
@@ -535,10 +1330,12 @@ impl DocumentActor {
     }
 
     async fn send_to_editor_client(&mut self, editor_id: &EditorId, message: EditorProtocolObject) {
-        let connection = self
-            .editor_connections
-            .get_mut(editor_id)
-            .expect("Could not get editor handle");
+        // An editor which just connected might have been removed already, because a message
+        // before this one failed.
+        let Some(connection) = self.editor_connections.get_mut(editor_id) else {
+            debug!("Not sending a message to editor #{editor_id}, which is gone.");
+            return;
+        };
 
         connection.1.send(message).await.unwrap_or_else(|err| {
             error!("Failed to send message to editor: {err} Removing editor.");
@@ -638,6 +1435,7 @@ impl DocumentActor {
                 self.remove_file(&relative_file_path);
             }
         }
+        self.crdt_doc.commit("Read files from disk");
         let _ = self.doc_changed_ping_tx.send(());
     }
 
@@ -690,6 +1488,8 @@ impl DocumentActor {
                 } else {
                     // The file doesn't exist yet - create it in the Automerge document.
                     self.crdt_doc.initialize_text(content, file_path);
+                    self.crdt_doc
+                        .commit(&format!("Create {file_path} (opened in an editor)"));
                     let _ = self.doc_changed_ping_tx.send(());
                     self.write_file(file_path);
                 }
@@ -699,6 +1499,7 @@ impl DocumentActor {
             }
             ComponentMessage::Edit { file_path, delta } => {
                 self.crdt_doc.apply_delta_to_doc(delta, file_path);
+                self.crdt_doc.commit(&format!("Edit {file_path}"));
                 let _ = self.doc_changed_ping_tx.send(());
                 self.write_file(file_path);
             }
@@ -822,9 +1623,9 @@ impl DocumentActor {
             },
         };
 
+        // This keeps the deleted cursor in `ephemeral_states`, so that the deletion isn't taken
+        // for a new cursor when another peer sends it back to us.
         self.process_component_message(None, &message).await;
-
-        self.ephemeral_states.remove(cursor_id);
     }
 
     async fn run(&mut self) {
@@ -847,11 +1648,20 @@ pub struct DocumentActorHandle {
     doc_message_tx: DocMessageSender,
     doc_changed_ping_tx: DocChangedSender,
     ephemeral_message_tx: EphemeralMessageSender,
+    shutdown_request_tx: ShutdownRequestSender,
+    peer_command_tx: PeerCommandSender,
+    approval_request_tx: ApprovalRequestSender,
+    members_tx: MembersSender,
     next_id: Arc<AtomicUsize>,
 }
 
 impl DocumentActorHandle {
-    pub fn new(app_config: &AppConfig, init: bool, is_host: bool, persist: bool) -> Self {
+    pub fn new(
+        app_config: &AppConfig,
+        init: bool,
+        is_host: bool,
+        persistence: Option<Box<dyn Persistence>>,
+    ) -> Self {
         // The document task will receive messages on this channel.
         let (doc_message_tx, doc_message_rx) = mpsc::channel(1);
 
@@ -870,15 +1680,24 @@ impl DocumentActorHandle {
             app_config.clone(),
             init,
             is_host,
-            persist,
+            persistence,
         );
 
+        let shutdown_request_tx = actor.shutdown_request_tx.clone();
+        let peer_command_tx = actor.peer_command_tx.clone();
+        let approval_request_tx = actor.approval_request_tx.clone();
+        let members_tx = actor.members_tx.clone();
+
         tokio::spawn(async move { actor.run().await });
 
         Self {
             doc_message_tx,
             doc_changed_ping_tx,
             ephemeral_message_tx,
+            shutdown_request_tx,
+            peer_command_tx,
+            approval_request_tx,
+            members_tx,
             next_id: Arc::default(),
         }
     }
@@ -900,6 +1719,55 @@ impl DocumentActorHandle {
         self.ephemeral_message_tx.subscribe()
     }
 
+    #[must_use]
+    pub fn subscribe_shutdown_requests(&self) -> ShutdownRequestReceiver {
+        self.shutdown_request_tx.subscribe()
+    }
+
+    #[must_use]
+    pub fn subscribe_peer_commands(&self) -> PeerCommandReceiver {
+        self.peer_command_tx.subscribe()
+    }
+
+    /// Receives the secret addresses to advertise to peers with full access, whenever they change.
+    #[must_use]
+    pub fn subscribe_members(&self) -> MembersReceiver {
+        self.members_tx.subscribe()
+    }
+
+    /// Receives the node ID and access of each peer which starts waiting for approval.
+    #[must_use]
+    pub fn subscribe_approval_requests(&self) -> ApprovalRequestReceiver {
+        self.approval_request_tx.subscribe()
+    }
+
+    /// Waits until someone decides about the peer with `node_id`.
+    pub async fn wait_for_approval(&self, node_id: String, access: Access) -> PeerDecision {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.send_message(DocMessage::WaitForApproval {
+            node_id,
+            access,
+            response_tx,
+        })
+        .await;
+        // If the document actor is gone, the daemon is shutting down anyway.
+        response_rx.await.unwrap_or(PeerDecision::Reject)
+    }
+
+    /// Decides about the waiting peer whose node ID starts with `node_id`. Returns its full node ID.
+    pub async fn decide_on_peer(&self, node_id: String, decision: PeerDecision) -> Result<String> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.send_message(DocMessage::DecideOnPeer {
+            node_id,
+            decision,
+            response_tx,
+        })
+        .await;
+        response_rx
+            .await
+            .context("DocumentActor task has been killed")?
+    }
+
     pub async fn content(&self) -> Result<String> {
         let (send, recv) = oneshot::channel();
         let message = DocMessage::GetContent { response_tx: send };
@@ -928,26 +1796,31 @@ pub struct Daemon {
     pub address: String,
     socket_path: PathBuf,
     app_config: AppConfig,
-    #[expect(dead_code)]
     // We need to store the connection manager in order to keep the connection alive.
     connection_manager: peer::ConnectionManager,
+    // Keeps other daemons out of the directory, as long as we run.
+    _pidfile: pidfile::Pidfile,
 }
 
 impl Daemon {
-    // Launch the daemon. Optionally, connect to given peer.
+    // Launch the daemon. Optionally, connect to given peer. Without a persistence backend, the
+    // history is lost when the daemon stops.
     pub async fn new(
         app_config: AppConfig,
         socket_path: &Path,
         init: bool,
-        persist: bool,
+        persistence: Option<Box<dyn Persistence>>,
     ) -> Result<Self> {
         let is_host = app_config.is_host();
 
-        let document_handle = DocumentActorHandle::new(&app_config, init, is_host, persist);
+        let mut pidfile = pidfile::acquire(&app_config.base_dir)?;
+
+        let persist = persistence.is_some();
+        let document_handle = DocumentActorHandle::new(&app_config, init, is_host, persistence);
 
         // Start socket listener.
         let socket_path = socket_path.to_path_buf();
-        editor::spawn_socket_listener(&socket_path, document_handle.clone())?;
+        editor::spawn_socket_listener(&socket_path, document_handle.clone()).await?;
 
         // Start file watcher.
         let base_dir = app_config.base_dir.clone();
@@ -959,35 +1832,86 @@ impl Daemon {
         }
 
         // Start connection manager.
-        let connection_manager = peer::ConnectionManager::new(document_handle.clone(), &base_dir)
+        let connection_manager = peer::ConnectionManager::new(document_handle.clone(), &app_config)
             .await
-            .expect("Failed to start connection manager");
-        let address = connection_manager.secret_address();
+            .context("Failed to start connection manager")?;
+        let access = app_config.share_access;
+        let address = connection_manager.secret_address(access);
 
         if app_config.emit_secret_address {
             info!(
             "\n\n\tOthers can connect by putting the following secret address in their .teamtype/config:\n\n\t{}\n",
             address
             );
+            if let Some(tcp_address) = connection_manager.tcp_secret_address(access) {
+                info!(
+                "\n\n\tPeers in your local network can also connect via TCP with this secret address:\n\n\t{}\n",
+                tcp_address
+                );
+            }
         }
         if app_config.emit_join_code {
-            put_secret_address_into_wormhole(address).await;
-        }
-        if let Some(config::Peer::SecretAddress(ref secret_address)) = app_config.peer {
-            connection_manager
-                .connect(secret_address.to_string())
-                .await
-                .context("Failed to connect to specified peer")?;
+            put_invites_into_wormhole(base_dir.clone(), access).await;
         }
+        connect_to_peers(&connection_manager, &app_config.peers).await?;
+
+        // Only now that we're up, tell others that we're running (`teamtype share --detach` waits
+        // for this).
+        pidfile.announce()?;
 
         Ok(Self {
             document_handle,
-            address: address.to_owned(),
+            address,
             socket_path,
             app_config,
             connection_manager,
+            _pidfile: pidfile,
         })
     }
+
+    /// Shuts the daemon down cleanly: Persists the document, closes the connections to all peers,
+    /// and removes the socket and the pidfile.
+    pub async fn shutdown(self) {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.document_handle
+            .send_message(DocMessage::Shutdown { response_tx })
+            .await;
+        if response_rx.await.is_err() {
+            warn!("Failed to persist the document before shutting down.");
+        }
+        self.connection_manager.close().await;
+    }
+}
+
+/// Connects to all peers at once. Returns as soon as one connection is made, or fails if none of
+/// them can be reached. Either way, the connection manager keeps trying to reach the others.
+async fn connect_to_peers(
+    connection_manager: &peer::ConnectionManager,
+    peers: &[config::Peer],
+) -> Result<()> {
+    let mut connections: FuturesUnordered<_> = peers
+        .iter()
+        .filter_map(|peer| match peer {
+            config::Peer::SecretAddress(secret_address) => {
+                Some(connection_manager.connect(secret_address.clone()))
+            }
+            config::Peer::JoinCode(_) => None,
+        })
+        .collect();
+    let mut first_error = None;
+    while let Some(result) = connections.next().await {
+        match result {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                warn!("Failed to connect to a peer, will keep trying: {err:#}");
+                first_error.get_or_insert(err);
+            }
+        }
+    }
+    if let Some(err) = first_error {
+        return Err(err.context("Failed to connect to specified peer"));
+    }
+    Ok(())
 }
 
 impl Drop for Daemon {
@@ -1078,12 +2002,21 @@ mod tests {
 
     mod document_actor {
         use super::*;
+        use crate::persistence::MemoryPersistence;
         use temp_dir::TempDir;
         //use tracing_test::traced_test;
 
         impl DocumentActor {
-            // TODO: Refactor, to reuse stuff from DocumentActorHandle constructor.
             fn setup_for_testing(directory: &TempDir) -> Self {
+                Self::setup_with_persistence(directory, true, None)
+            }
+
+            // TODO: Refactor, to reuse stuff from DocumentActorHandle constructor.
+            fn setup_with_persistence(
+                directory: &TempDir,
+                init: bool,
+                persistence: Option<Box<dyn Persistence>>,
+            ) -> Self {
                 // The document task will receive messages on this channel.
                 let (_doc_message_tx, doc_message_rx) = mpsc::channel(1);
 
@@ -1103,9 +2036,9 @@ mod tests {
                         base_dir: directory.path().to_path_buf(),
                         ..Default::default()
                     },
+                    init,
                     true,
-                    true,
-                    false,
+                    persistence,
                 )
             }
             fn assert_file_content(&self, file_path: &RelativePath, content: &str) {
@@ -1127,6 +2060,238 @@ mod tests {
             dir
         }
 
+        #[test]
+        fn compacts_after_many_incremental_saves() {
+            let dir = setup_filesystem_for_testing();
+            let persistence = MemoryPersistence::default();
+            let mut actor = DocumentActor::setup_with_persistence(
+                &dir,
+                true,
+                Some(Box::new(persistence.clone())),
+            );
+            let file_path = RelativePath::new("file1");
+
+            actor.persist();
+            assert_eq!(actor.incremental_saves, 0);
+
+            actor.crdt_doc.update_text("changed", &file_path);
+            actor.crdt_doc.commit("Change file1");
+            actor.persist();
+            assert_eq!(actor.incremental_saves, 1);
+            assert!(!actor.needs_compaction());
+
+            actor.incremental_saves = MAX_INCREMENTAL_SAVES;
+            assert!(actor.needs_compaction());
+            actor.persist();
+            assert_eq!(actor.incremental_saves, 0);
+            let persisted = persistence.bytes().unwrap();
+            assert_eq!(persisted.len(), actor.full_save_bytes);
+            assert_eq!(Document::load(&persisted).files(), actor.crdt_doc.files());
+        }
+
+        #[test]
+        fn loads_history_from_persistence_backend() {
+            let dir = setup_filesystem_for_testing();
+            let persistence = MemoryPersistence::default();
+            let mut actor = DocumentActor::setup_with_persistence(
+                &dir,
+                true,
+                Some(Box::new(persistence.clone())),
+            );
+            actor.persist();
+            let heads = actor.get_heads();
+
+            let mut actor =
+                DocumentActor::setup_with_persistence(&dir, false, Some(Box::new(persistence)));
+            assert_eq!(actor.get_heads(), heads);
+            actor.assert_file_content(&RelativePath::new("file1"), "content1");
+            assert!(!dir.child(config::CONFIG_DIR).exists());
+        }
+
+        #[test]
+        fn read_only_daemon_makes_no_changes() {
+            let dir = setup_filesystem_for_testing();
+            let mut actor = DocumentActor::setup_for_testing(&dir);
+            let open = |name: &str| EditorProtocolMessageFromEditor::Open {
+                uri: format!("file://{}", dir.child(name).display()),
+                content: String::new(),
+            };
+            assert!(!actor.would_change_document(&open("file1")));
+            assert!(actor.would_change_document(&open("new-file")));
+
+            actor.read_only = true;
+            let heads = actor.get_heads();
+            sandbox::write_file(dir.path(), &dir.child("file1"), b"changed").unwrap();
+            actor.handle_watcher_event(&WatcherEvent {
+                file_path: dir.child("file1"),
+                event_type: WatcherEventType::Changed,
+            });
+            assert_eq!(actor.get_heads(), heads);
+            actor.assert_file_content(&RelativePath::new("file1"), "content1");
+        }
+
+        #[tokio::test]
+        async fn decides_on_waiting_peers() {
+            let dir = setup_filesystem_for_testing();
+            let mut actor = DocumentActor::setup_for_testing(&dir);
+            let mut wait = |node_id: &str| {
+                let (response_tx, response_rx) = oneshot::channel();
+                actor.waiting_peers.insert(
+                    node_id.to_string(),
+                    WaitingPeer {
+                        access: Access::Full,
+                        response_tx,
+                    },
+                );
+                response_rx
+            };
+            let first = wait("aaaa1");
+            let second = wait("aaaa2");
+            drop(wait("bbbb"));
+
+            assert!(actor.decide_on_peer("aaaa", PeerDecision::Accept).is_err());
+            assert!(actor.decide_on_peer("bbbb", PeerDecision::Accept).is_err());
+            assert_eq!(
+                actor
+                    .decide_on_peer("aaaa2", PeerDecision::AlwaysTrust)
+                    .unwrap(),
+                "aaaa2"
+            );
+            assert_eq!(second.await.unwrap(), PeerDecision::AlwaysTrust);
+            assert_eq!(
+                actor.decide_on_peer("aaaa", PeerDecision::Reject).unwrap(),
+                "aaaa1"
+            );
+            assert_eq!(first.await.unwrap(), PeerDecision::Reject);
+            assert!(actor.waiting_peers.is_empty());
+        }
+
+        #[test]
+        fn only_syncs_with_related_histories() {
+            let dir = setup_filesystem_for_testing();
+            let mut actor = DocumentActor::setup_for_testing(&dir);
+            let identity = actor.crdt_doc.identity().unwrap();
+            assert!(actor.check_identity(Some(identity)));
+            assert!(actor.check_identity(None));
+            assert!(!actor.check_identity(Some("other".to_string())));
+
+            // A peer which joins, and connects to several peers at once.
+            actor.crdt_doc = Document::default();
+            assert!(actor.check_identity(Some("first".to_string())));
+            assert!(!actor.check_identity(Some("second".to_string())));
+            assert!(actor.check_identity(Some("first".to_string())));
+            assert_eq!(actor.identity(), Some("first".to_string()));
+
+            // A history which was started on its own.
+            actor.pinned_identity = None;
+            actor
+                .crdt_doc
+                .initialize_text("hello", &RelativePath::new("file1"));
+            assert!(!actor.check_identity(Some("first".to_string())));
+            assert!(actor.check_identity(None));
+        }
+
+        #[test]
+        fn advertises_members_with_full_access() {
+            let dir = setup_filesystem_for_testing();
+            let mut actor = DocumentActor::setup_for_testing(&dir);
+            let mut peer_command_rx = actor.peer_command_tx.subscribe();
+            for (node_id, access) in [("bbbb", Access::Full), ("rrrr", Access::ReadOnly)] {
+                actor.peers.insert(
+                    node_id.to_string(),
+                    PeerStatus {
+                        node_id: node_id.to_string(),
+                        direction: PeerDirection::Incoming,
+                        access,
+                        connected_since: 0,
+                    },
+                );
+            }
+            actor.own_address = Some("aaaa#192.0.2.1:1".to_string());
+
+            actor.react_to_peer_members("bbbb", vec!["bbbb#192.0.2.2:2".into(), "cccc".into()]);
+            // Read-only peers can't vouch for anyone.
+            actor.react_to_peer_members("rrrr", vec!["rrrr".into(), "dddd".into()]);
+
+            assert!(matches!(
+                peer_command_rx.try_recv(),
+                Ok(PeerCommand::Connect { voucher, address })
+                    if voucher == "bbbb" && address == "cccc"
+            ));
+            assert!(peer_command_rx.try_recv().is_err());
+            assert_eq!(
+                actor.members(),
+                vec!["bbbb#192.0.2.2:2", "aaaa#192.0.2.1:1"]
+            );
+
+            actor.peers.remove("bbbb");
+            assert_eq!(actor.members(), vec!["aaaa#192.0.2.1:1"]);
+            actor.read_only = true;
+            assert!(actor.members().is_empty());
+        }
+
+        #[tokio::test]
+        async fn read_only_while_all_peers_only_let_us_watch() {
+            let dir = setup_filesystem_for_testing();
+            let mut actor = DocumentActor::setup_for_testing(&dir);
+            let connected = |node_id: &str, direction| DocMessage::PeerConnected {
+                node_id: node_id.to_string(),
+                direction,
+                access: Access::Full,
+            };
+
+            actor
+                .handle_message(connected("rrrr", PeerDirection::Outgoing))
+                .await;
+            assert!(!actor.read_only);
+            actor
+                .handle_message(DocMessage::ReadOnlyAccess("rrrr".to_string()))
+                .await;
+            assert!(actor.read_only);
+
+            // What we let incoming peers do doesn't matter.
+            actor
+                .handle_message(connected("iiii", PeerDirection::Incoming))
+                .await;
+            assert!(actor.read_only);
+
+            // One peer which lets us edit is enough.
+            actor
+                .handle_message(connected("ffff", PeerDirection::Outgoing))
+                .await;
+            assert!(!actor.read_only);
+            actor
+                .handle_message(DocMessage::PeerDisconnected("ffff".to_string()))
+                .await;
+            assert!(actor.read_only);
+        }
+
+        #[tokio::test]
+        async fn ignores_ephemeral_messages_it_has_seen() {
+            let dir = setup_filesystem_for_testing();
+            let mut actor = DocumentActor::setup_for_testing(&dir);
+            let mut ephemeral_message_rx = actor.ephemeral_message_tx.subscribe();
+            let message = |sequence_number| EphemeralMessage {
+                cursor_id: "cursor".to_string(),
+                sequence_number,
+                cursor_state: CursorState {
+                    name: None,
+                    file_path: RelativePath::new("file1"),
+                    ranges: vec![],
+                },
+            };
+
+            actor.react_to_ephemeral_message(message(1)).await;
+            // In a mesh, the same message comes back via other peers.
+            actor.react_to_ephemeral_message(message(1)).await;
+            actor.react_to_ephemeral_message(message(0)).await;
+            actor.react_to_ephemeral_message(message(2)).await;
+
+            assert_eq!(ephemeral_message_rx.try_recv().unwrap(), message(1));
+            assert_eq!(ephemeral_message_rx.try_recv().unwrap(), message(2));
+            assert!(ephemeral_message_rx.try_recv().is_err());
+        }
+
         #[test]
         fn read_contents_from_dir() {
             let dir = setup_filesystem_for_testing();
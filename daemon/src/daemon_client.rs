@@ -0,0 +1,163 @@
+// SPDX-FileCopyrightText: 2025 blinry <mail@blinry.org>
+// SPDX-FileCopyrightText: 2025 zormit <nt4u@kpvn.de>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Talks to a running daemon over its socket, for commands like `teamtype status`.
+//!
+//! We speak the same newline-delimited JSON-RPC as the editors do, so the daemon treats us like a
+//! (short-lived) editor connection.
+use crate::types::{
+    CompactionResult, DaemonStatus, EditorProtocolMessageError, EditorProtocolMessageFromEditor,
+    PeerDecision,
+};
+use anyhow::{bail, Context, Result};
+use futures::{SinkExt, StreamExt};
+use std::path::Path;
+use std::time::Duration;
+use tokio::{net::UnixStream, time::timeout};
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+
+const REQUEST_ID: usize = 1;
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Sends a single request to the daemon, and returns the `result` of its response.
+pub async fn request(
+    socket_path: &Path,
+    message: &EditorProtocolMessageFromEditor,
+) -> Result<serde_json::Value> {
+    let stream = UnixStream::connect(socket_path).await.with_context(|| {
+        format!(
+            "Failed to connect to '{}'. Is the daemon running?",
+            socket_path.display()
+        )
+    })?;
+    let (socket_read, socket_write) = stream.into_split();
+    let mut reader = FramedRead::new(socket_read, LinesCodec::new());
+    let mut writer = FramedWrite::new(socket_write, LinesCodec::new());
+
+    let mut payload = serde_json::to_value(message)?;
+    let map = payload
+        .as_object_mut()
+        .context("EditorProtocolMessage was not serialized to a map")?;
+    map.insert("jsonrpc".to_string(), "2.0".into());
+    map.insert("id".to_string(), REQUEST_ID.into());
+    writer.send(payload.to_string()).await?;
+
+    // The daemon might send us notifications (like cursor positions) before the response arrives.
+    while let Some(line) = reader.next().await {
+        let mut response: serde_json::Value = serde_json::from_str(&line?)?;
+        if response.get("id") != Some(&REQUEST_ID.into()) {
+            continue;
+        }
+        if let Some(error) = response.get_mut("error") {
+            let error: EditorProtocolMessageError = serde_json::from_value(error.take())?;
+            match error.data {
+                Some(data) => bail!("{} ({data})", error.message),
+                None => bail!("{}", error.message),
+            }
+        }
+        return response
+            .get_mut("result")
+            .map(serde_json::Value::take)
+            .context("Response of the daemon has no result");
+    }
+    bail!("The daemon closed the connection without responding");
+}
+
+/// Checks whether a daemon is listening on the socket.
+pub async fn is_running(socket_path: &Path) -> bool {
+    UnixStream::connect(socket_path).await.is_ok()
+}
+
+/// What we found out about a socket by pinging it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PingResult {
+    /// Nobody listens on the socket (or it doesn't exist). It was probably left behind by a
+    /// daemon that crashed.
+    Dead,
+    /// A daemon answered.
+    Alive,
+    /// Someone accepted the connection, but didn't answer in time. The daemon might be busy.
+    NotResponding,
+}
+
+/// Pings the daemon listening on the socket, if there is one.
+pub async fn ping(socket_path: &Path) -> PingResult {
+    if !is_running(socket_path).await {
+        return PingResult::Dead;
+    }
+    match timeout(
+        PING_TIMEOUT,
+        request(socket_path, &EditorProtocolMessageFromEditor::Ping),
+    )
+    .await
+    {
+        // Even an error response means that a daemon is there.
+        Ok(_) => PingResult::Alive,
+        Err(_) => PingResult::NotResponding,
+    }
+}
+
+pub async fn status(socket_path: &Path) -> Result<DaemonStatus> {
+    let result = request(socket_path, &EditorProtocolMessageFromEditor::Status).await?;
+    serde_json::from_value(result).context("Failed to parse status of the daemon")
+}
+
+pub async fn restore(socket_path: &Path, uri: String, at: String) -> Result<()> {
+    request(
+        socket_path,
+        &EditorProtocolMessageFromEditor::Restore { uri, at },
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn compact(socket_path: &Path) -> Result<CompactionResult> {
+    let result = request(socket_path, &EditorProtocolMessageFromEditor::Compact).await?;
+    serde_json::from_value(result).context("Failed to parse compaction result of the daemon")
+}
+
+/// Asks the daemon to rotate its passphrase. Returns the new secret address.
+pub async fn rotate_secret(socket_path: &Path) -> Result<String> {
+    let result = request(socket_path, &EditorProtocolMessageFromEditor::RotateSecret).await?;
+    serde_json::from_value(result).context("Failed to parse secret address from the daemon")
+}
+
+/// Asks the daemon to disconnect the peer whose node ID starts with `node_id`. Returns its full
+/// node ID.
+pub async fn disconnect(socket_path: &Path, node_id: String) -> Result<String> {
+    let result = request(
+        socket_path,
+        &EditorProtocolMessageFromEditor::Disconnect { node_id },
+    )
+    .await?;
+    serde_json::from_value(result).context("Failed to parse node ID from the daemon")
+}
+
+/// Tells the daemon what to do with the waiting peer whose node ID starts with `node_id`. Returns
+/// its full node ID.
+pub async fn decide_on_peer(
+    socket_path: &Path,
+    node_id: String,
+    decision: PeerDecision,
+) -> Result<String> {
+    let result = request(
+        socket_path,
+        &EditorProtocolMessageFromEditor::DecideOnPeer { node_id, decision },
+    )
+    .await?;
+    serde_json::from_value(result).context("Failed to parse node ID from the daemon")
+}
+
+/// Asks the daemon to shut down, and waits until it has.
+pub async fn shutdown(socket_path: &Path) -> Result<()> {
+    request(socket_path, &EditorProtocolMessageFromEditor::Shutdown).await?;
+    for _ in 0..100 {
+        if !is_running(socket_path).await {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    bail!("The daemon is still running, even though it agreed to shut down");
+}
@@ -0,0 +1,116 @@
+// SPDX-FileCopyrightText: 2025 blinry <mail@blinry.org>
+// SPDX-FileCopyrightText: 2025 zormit <nt4u@kpvn.de>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Runs the daemon in the background, for `teamtype share --detach` and `teamtype join --detach`.
+//!
+//! We start a copy of ourselves without `--detach`, with its output going to `.teamtype/log`.
+//! Until the daemon is up, we show that output in the terminal, so that users see errors (and the
+//! join code) right away.
+use anyhow::{bail, Context, Result};
+use std::io::Write as _;
+use std::os::unix::process::CommandExt as _;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use teamtype::{cli_ask, config, encryption, pidfile, sandbox};
+use tracing::{info, warn};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Starts the daemon for `directory` in the background, by running the subcommand `args`.
+pub async fn spawn(directory: &Path, args: &[String], wait_for_join_code: bool) -> Result<()> {
+    let log_path = directory.join(config::CONFIG_DIR).join(config::LOG_FILE);
+    // Each run gets a fresh log.
+    sandbox::write_file(directory, &log_path, b"")?;
+    let log_file = sandbox::open_for_appending(directory, &log_path)?;
+
+    // The daemon can't ask questions in the background, so pass on how to answer them.
+    let assume_flag = match cli_ask::assumed_answer()? {
+        Some(true) => Some("--yes"),
+        Some(false) => Some("--no"),
+        None => None,
+    };
+
+    let mut command = Command::new(std::env::current_exe()?);
+    // The daemon can't ask for the passphrase either. We write it to its input, because its
+    // environment can be read by other processes of the same user.
+    let passphrase =
+        encryption::unlock(directory)?.map(|unlocked| unlocked.passphrase().to_string());
+    if passphrase.is_some() {
+        command
+            .env(encryption::PASSPHRASE_ON_STDIN_ENV_VAR, "1")
+            .stdin(Stdio::piped());
+    } else {
+        command.stdin(Stdio::null());
+    }
+
+    let mut child = command
+        .arg("--directory")
+        .arg(directory)
+        .args(assume_flag)
+        .args(args)
+        .stdout(log_file.try_clone()?)
+        .stderr(log_file)
+        // Put the daemon in its own process group, so that Ctrl+C in this terminal doesn't reach it.
+        .process_group(0)
+        .spawn()
+        .context("Failed to start the daemon in the background")?;
+    let pid = child.id();
+    if let Some(passphrase) = passphrase {
+        // Dropping the pipe closes it, so that the daemon doesn't wait for more.
+        let mut stdin = child
+            .stdin
+            .take()
+            .expect("The input of the daemon should be piped");
+        writeln!(stdin, "{passphrase}").context("Failed to hand the passphrase to the daemon")?;
+    }
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    let mut relayed_bytes = 0;
+    let mut join_code_shown = false;
+    loop {
+        // Show what the daemon logged since we last looked.
+        let log = sandbox::read_file(directory, &log_path)?;
+        if log.len() > relayed_bytes {
+            let new_output = String::from_utf8_lossy(&log[relayed_bytes..]);
+            print!("{new_output}");
+            join_code_shown |= new_output.contains("teamtype join ");
+            relayed_bytes = log.len();
+        }
+
+        if let Some(status) = child.try_wait()? {
+            bail!(
+                "The daemon exited during startup ({status}). See '{}' for details.",
+                log_path.display()
+            );
+        }
+
+        let started = pidfile::running_pid(directory) == Some(pid);
+        if started && (join_code_shown || !wait_for_join_code) {
+            break;
+        }
+        if Instant::now() > deadline {
+            if !started {
+                bail!(
+                    "The daemon (PID {pid}) didn't finish starting within {} seconds. See '{}' for details.",
+                    STARTUP_TIMEOUT.as_secs(),
+                    log_path.display()
+                );
+            }
+            warn!(
+                "Gave up waiting for a join code. It will appear in '{}'.",
+                log_path.display()
+            );
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    info!(
+        "Teamtype is running in the background (PID {pid}). Its output goes to '{}'. Stop it with `teamtype stop`.",
+        log_path.display()
+    );
+    Ok(())
+}
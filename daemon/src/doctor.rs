@@ -0,0 +1,293 @@
+// SPDX-FileCopyrightText: 2025 blinry <mail@blinry.org>
+// SPDX-FileCopyrightText: 2025 zormit <nt4u@kpvn.de>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Checks for setup problems which would otherwise stop the daemon, for `teamtype doctor`.
+use crate::{config, editor, encryption, sandbox};
+use anyhow::Result;
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// The key file holds the secret key and the passphrase, 32 bytes each.
+const KEY_FILE_LENGTH: u64 = 64;
+/// A regular file, which only the user can read and write.
+const KEY_FILE_MODE: u32 = 0o100_600;
+/// Unix socket paths are limited to this many bytes, including a terminating null byte.
+const MAX_SOCKET_PATH_LENGTH: usize = if cfg!(target_os = "macos") { 104 } else { 108 };
+
+/// The result of a single check.
+pub struct Check {
+    pub name: &'static str,
+    pub problem: Option<Problem>,
+}
+
+pub struct Problem {
+    pub description: String,
+    pub fix: Option<Fix>,
+}
+
+/// A fix `teamtype doctor` can apply itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Fix {
+    MigrateLegacyDirectory,
+    RestrictConfigDirectory,
+    RestrictKeyFile,
+    RemoveKeyFile,
+    IgnoreInGit,
+}
+
+impl Fix {
+    /// Whether the fix loses something which can't be restored. These need to be confirmed
+    /// interactively, not with `--yes`.
+    #[must_use]
+    pub const fn is_destructive(self) -> bool {
+        matches!(self, Self::RemoveKeyFile)
+    }
+
+    pub fn apply(self, base_dir: &Path) -> Result<()> {
+        let config_dir = base_dir.join(config::CONFIG_DIR);
+        let key_file = config_dir.join(config::KEY_FILE);
+        match self {
+            Self::MigrateLegacyDirectory => sandbox::rename_file(
+                base_dir,
+                &base_dir.join(config::LEGACY_CONFIG_DIR),
+                &config_dir,
+            ),
+            Self::RestrictConfigDirectory => sandbox::set_permissions(base_dir, &config_dir, 0o700),
+            Self::RestrictKeyFile => sandbox::set_permissions(base_dir, &key_file, 0o600),
+            Self::RemoveKeyFile => sandbox::remove_file(base_dir, &key_file),
+            Self::IgnoreInGit => config::ensure_teamtype_is_ignored(base_dir),
+        }
+    }
+}
+
+impl fmt::Display for Fix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Self::MigrateLegacyDirectory => {
+                format!(
+                    "Rename {}/ to {}/",
+                    config::LEGACY_CONFIG_DIR,
+                    config::CONFIG_DIR
+                )
+            }
+            Self::RestrictConfigDirectory => format!("Run `chmod 700 {}`", config::CONFIG_DIR),
+            Self::RestrictKeyFile => {
+                format!(
+                    "Run `chmod 600 {}/{}`",
+                    config::CONFIG_DIR,
+                    config::KEY_FILE
+                )
+            }
+            Self::RemoveKeyFile => format!(
+                "Remove {}/{} (a new one will be generated, so you'll have a new secret address)",
+                config::CONFIG_DIR,
+                config::KEY_FILE
+            ),
+            Self::IgnoreInGit => format!("Add {}/.gitignore", config::CONFIG_DIR),
+        };
+        write!(f, "{description}")
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.problem {
+            None => write!(f, "[ok]      {}", self.name),
+            Some(problem) => write!(f, "[problem] {}: {}", self.name, problem.description),
+        }
+    }
+}
+
+fn ok(name: &'static str) -> Check {
+    Check {
+        name,
+        problem: None,
+    }
+}
+
+fn problem(name: &'static str, description: String, fix: Option<Fix>) -> Check {
+    Check {
+        name,
+        problem: Some(Problem { description, fix }),
+    }
+}
+
+/// Runs all checks on the shared directory at `base_dir`.
+#[must_use]
+pub fn run_checks(base_dir: &Path) -> Vec<Check> {
+    let config_dir = base_dir.join(config::CONFIG_DIR);
+    let has_config_dir = config_dir.is_dir();
+
+    let mut checks = vec![check_legacy_directory(base_dir, has_config_dir)];
+    if !has_config_dir {
+        checks.push(problem(
+            "Teamtype directory",
+            format!(
+                "There is no {}/ directory. Run `teamtype share` or `teamtype join` to set it up.",
+                config::CONFIG_DIR
+            ),
+            None,
+        ));
+        return checks;
+    }
+    checks.push(ok("Teamtype directory"));
+
+    let socket_path = config_dir.join(config::DEFAULT_SOCKET_NAME);
+    checks.push(match editor::is_user_readable_only(&socket_path) {
+        Ok(()) => ok("Permissions of the Teamtype directory"),
+        Err(err) => problem(
+            "Permissions of the Teamtype directory",
+            err.to_string(),
+            Some(Fix::RestrictConfigDirectory),
+        ),
+    });
+    checks.push(check_key_file(
+        &config_dir.join(config::KEY_FILE),
+        encryption::is_enabled(base_dir),
+    ));
+    checks.push(
+        if config::teamtype_directory_should_be_ignored_but_isnt(base_dir) {
+            problem(
+                "Ignored by Git",
+                format!("Git doesn't ignore {}/.", config::CONFIG_DIR),
+                Some(Fix::IgnoreInGit),
+            )
+        } else {
+            ok("Ignored by Git")
+        },
+    );
+    checks.push(check_socket_path_length(&socket_path));
+    checks
+}
+
+fn check_legacy_directory(base_dir: &Path, has_config_dir: bool) -> Check {
+    let name = "No legacy directory";
+    if !base_dir.join(config::LEGACY_CONFIG_DIR).is_dir() {
+        return ok(name);
+    }
+    if has_config_dir {
+        problem(
+            name,
+            format!(
+                "There's an {}/ directory from when the project was called \"Ethersync\", next to {}/. Remove it when you don't need it anymore.",
+                config::LEGACY_CONFIG_DIR,
+                config::CONFIG_DIR
+            ),
+            None,
+        )
+    } else {
+        problem(
+            name,
+            format!(
+                "There's an {}/ directory from when the project was called \"Ethersync\".",
+                config::LEGACY_CONFIG_DIR
+            ),
+            Some(Fix::MigrateLegacyDirectory),
+        )
+    }
+}
+
+fn check_key_file(key_file: &Path, is_encrypted: bool) -> Check {
+    let name = "Key file";
+    let Ok(metadata) = key_file.metadata() else {
+        // It will be generated when the daemon starts.
+        return ok(name);
+    };
+    // We can't tell the length of an encrypted key without the passphrase.
+    if metadata.len() != KEY_FILE_LENGTH && !is_encrypted {
+        return problem(
+            name,
+            format!("The key file is {} bytes long instead of {KEY_FILE_LENGTH}. It was probably created by a Teamtype version older than 0.7.0.", metadata.len()),
+            Some(Fix::RemoveKeyFile),
+        );
+    }
+    let mode = metadata.permissions().mode();
+    if mode != KEY_FILE_MODE {
+        return problem(
+            name,
+            format!(
+                "Only you should be able to read and write the key file, but its permissions are {:o}.",
+                mode & 0o777
+            ),
+            Some(Fix::RestrictKeyFile),
+        );
+    }
+    ok(name)
+}
+
+fn check_socket_path_length(socket_path: &Path) -> Check {
+    let name = "Length of the socket path";
+    let length = socket_path.as_os_str().len();
+    if length < MAX_SOCKET_PATH_LENGTH {
+        ok(name)
+    } else {
+        problem(
+            name,
+            format!(
+                "The socket path '{}' is {length} bytes long, but can be at most {} bytes. Move the shared directory to a shorter path.",
+                socket_path.display(),
+                MAX_SOCKET_PATH_LENGTH - 1
+            ),
+            None,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    fn problem_of<'a>(checks: &'a [Check], name: &str) -> Option<&'a Problem> {
+        checks
+            .iter()
+            .find(|check| check.name == name)
+            .and_then(|check| check.problem.as_ref())
+    }
+
+    #[test]
+    fn migrates_legacy_directory() {
+        let dir = TempDir::new().unwrap();
+        sandbox::create_dir(dir.path(), &dir.child(config::LEGACY_CONFIG_DIR)).unwrap();
+
+        let checks = run_checks(dir.path());
+        let problem = problem_of(&checks, "No legacy directory").unwrap();
+        assert_eq!(problem.fix, Some(Fix::MigrateLegacyDirectory));
+        assert!(problem_of(&checks, "Teamtype directory").is_some());
+
+        Fix::MigrateLegacyDirectory.apply(dir.path()).unwrap();
+        let checks = run_checks(dir.path());
+        assert!(problem_of(&checks, "No legacy directory").is_none());
+        assert!(problem_of(&checks, "Teamtype directory").is_none());
+    }
+
+    #[test]
+    fn finds_problems_with_the_key_file() {
+        let dir = TempDir::new().unwrap();
+        let config_dir = dir.child(config::CONFIG_DIR);
+        sandbox::create_dir(dir.path(), &config_dir).unwrap();
+        let key_file = config_dir.join(config::KEY_FILE);
+
+        sandbox::write_file(dir.path(), &key_file, &[0; 32]).unwrap();
+        let checks = run_checks(dir.path());
+        assert_eq!(
+            problem_of(&checks, "Key file").unwrap().fix,
+            Some(Fix::RemoveKeyFile)
+        );
+        assert!(Fix::RemoveKeyFile.is_destructive());
+
+        sandbox::write_file(dir.path(), &key_file, &[0; 64]).unwrap();
+        sandbox::set_permissions(dir.path(), &key_file, 0o644).unwrap();
+        let checks = run_checks(dir.path());
+        assert_eq!(
+            problem_of(&checks, "Key file").unwrap().fix,
+            Some(Fix::RestrictKeyFile)
+        );
+
+        Fix::RestrictKeyFile.apply(dir.path()).unwrap();
+        assert!(problem_of(&run_checks(dir.path()), "Key file").is_none());
+    }
+}
@@ -5,16 +5,20 @@
 
 use crate::{
     path::RelativePath,
-    types::{EditorTextDelta, TextDelta},
+    timestamp,
+    types::{BlameLine, EditorTextDelta, TextDelta},
 };
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use automerge::{
     patches::TextRepresentation,
     sync::{Message as AutomergeSyncMessage, State as SyncState, SyncDoc},
-    transaction::Transactable,
-    AutoCommit, ChangeHash, ObjType, Patch, PatchLog, ReadDoc, TextEncoding,
+    transaction::{CommitOptions, Transactable},
+    ActorId, AutoCommit, ChangeHash, ChangeMetadata, ObjType, Patch, PatchLog, ReadDoc,
+    TextEncoding,
 };
 use dissimilar::Chunk;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use tracing::{debug, info};
 
 /// Encapsulates the Automerge `AutoCommit` and provides a generic interface,
@@ -29,6 +33,29 @@ use tracing::{debug, info};
 #[must_use]
 pub struct Document {
     doc: AutoCommit,
+    /// The name of the user making the local changes, see [`Document::set_author`].
+    author: Option<String>,
+    author_recorded: bool,
+}
+
+/// One entry in the history of a [`Document`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeInfo {
+    pub hash: ChangeHash,
+    pub actor: String,
+    /// The name of the user behind the actor, if it was recorded.
+    pub author: Option<String>,
+    /// Unix timestamp in seconds. Zero if the change didn't record a time.
+    pub timestamp: i64,
+    pub message: Option<String>,
+    pub files: Vec<RelativePath>,
+}
+
+/// The content of a file at some point in history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileContent {
+    Text(String),
+    Binary(Vec<u8>),
 }
 
 impl Default for Document {
@@ -49,11 +76,71 @@ impl Default for Document {
 }
 
 impl Document {
-    // TODO: use sth like try_load + Result to bubble up potential errors.
     pub fn load(bytes: &[u8]) -> Self {
-        let doc =
-            AutoCommit::load(bytes).expect("Failed to load Automerge document from given bytes");
-        Self { doc }
+        Self::try_load(bytes).expect("Failed to load Automerge document from given bytes")
+    }
+
+    pub fn try_load(bytes: &[u8]) -> Result<Self> {
+        let doc = AutoCommit::load(bytes)?;
+        Ok(Self {
+            doc,
+            author: None,
+            author_recorded: false,
+        })
+    }
+
+    /// Sets the name of the user making the local changes. With the next commit, the name is
+    /// recorded in the "states" map, under our actor ID, so that all peers can see who made our
+    /// changes.
+    pub fn set_author(&mut self, username: &str) {
+        self.author = Some(username.to_string());
+        self.author_recorded = false;
+    }
+
+    /// Groups all changes made since the last commit into a single change, and stamps it with the
+    /// current time and the given message. Does nothing if there are no changes.
+    pub fn commit(&mut self, message: &str) {
+        if self.doc.pending_ops() == 0 {
+            return;
+        }
+        self.record_author();
+        self.doc.commit_with(
+            CommitOptions::default()
+                .with_message(message.to_string())
+                .with_time(timestamp::now()),
+        );
+    }
+
+    fn record_author(&mut self) {
+        if self.author_recorded {
+            return;
+        }
+        if let Some(author) = &self.author {
+            let states_map = self
+                .top_level_map_obj("states")
+                .expect("Failed to get states Map object");
+            self.doc
+                .put(
+                    states_map,
+                    self.doc.get_actor().to_hex_string(),
+                    author.as_str(),
+                )
+                .expect("Failed to record author in Automerge document");
+        }
+        self.author_recorded = true;
+    }
+
+    /// Looks up the name of the user behind an actor, as recorded by [`Document::set_author`].
+    #[must_use]
+    pub fn author(&self, actor_id: &str) -> Option<String> {
+        let states_map = self.top_level_map_obj("states").ok()?;
+        match self.doc.get(states_map, actor_id) {
+            Ok(Some((automerge::Value::Scalar(value), _))) => match value.as_ref() {
+                automerge::ScalarValue::Str(author) => Some(author.to_string()),
+                _ => None,
+            },
+            _ => None,
+        }
     }
 
     #[must_use]
@@ -66,6 +153,36 @@ impl Document {
         self.doc.save_incremental()
     }
 
+    /// The identity of the shared directory this history belongs to. Histories with different
+    /// identities are unrelated, and must not be synced. Peers which joined adopt the identity of
+    /// the host when they sync.
+    #[must_use]
+    pub fn identity(&self) -> Option<String> {
+        match self.doc.get(automerge::ROOT, "id") {
+            Ok(Some((automerge::Value::Scalar(value), _))) => value.to_str().map(str::to_string),
+            _ => None,
+        }
+    }
+
+    /// Whether the history contains nothing but the initial change, like the one of a peer which
+    /// joins, and hasn't synced yet.
+    pub fn is_empty(&mut self) -> bool {
+        self.get_heads() == Self::default().get_heads()
+    }
+
+    /// Gives the history a random identity, unless it has one.
+    pub fn ensure_identity(&mut self) {
+        if self.identity().is_some() {
+            return;
+        }
+        let identity = format!("{:032x}", rand::random::<u128>());
+        debug!("Giving the history the identity {identity}.");
+        self.doc
+            .put(automerge::ROOT, "id", identity)
+            .expect("Failed to put identity into Automerge document");
+        self.commit("Give the shared directory an identity");
+    }
+
     #[must_use]
     pub fn actor_id(&self) -> String {
         self.doc.get_actor().to_hex_string()
@@ -139,6 +256,17 @@ impl Document {
         })
     }
 
+    /// Used to get the contents of a text or binary file at a specific state.
+    pub fn file_at(&self, file_path: &RelativePath, heads: &[ChangeHash]) -> Result<FileContent> {
+        if let Ok(text) = self.file_content_at(file_path, heads) {
+            return Ok(FileContent::Text(text));
+        }
+        if let Ok(bytes) = self.get_bytes_at(file_path, heads) {
+            return Ok(FileContent::Binary(bytes));
+        }
+        bail!("{file_path} didn't exist at that point in history");
+    }
+
     /// Used to get the contents of a binary file.
     pub fn get_bytes(&mut self, file_path: &RelativePath) -> Result<Vec<u8>> {
         let heads = self.get_heads();
@@ -345,12 +473,201 @@ impl Document {
     pub fn get_heads(&mut self) -> Vec<ChangeHash> {
         self.doc.get_heads()
     }
+
+    /// Lists all changes in the history. Each change comes after the changes it depends on.
+    ///
+    /// The files a change touched are found by looking at its operations. Diffing each change
+    /// against its dependencies would tell the same, but takes time proportional to the whole
+    /// history, for every change.
+    pub fn changes(&mut self) -> Result<Vec<ChangeInfo>> {
+        let changes = self.doc.get_changes(&[]);
+
+        // Which files the objects in the "files" map belong to, by their IDs. The objects are
+        // created before they're changed, so we learn about them in time.
+        let mut file_objects: HashMap<String, RelativePath> = HashMap::new();
+        let mut files_maps: HashSet<String> = HashSet::new();
+
+        changes
+            .iter()
+            .map(|change| {
+                let actor = change.actor_id().to_hex_string();
+                let mut files = vec![];
+                for (index, op) in change_operations(change)?.into_iter().enumerate() {
+                    let op_id = format!("{}@{actor}", change.start_op().get() + index as u64);
+                    match op.key {
+                        Some(key) if op.obj == "_root" && key == "files" => {
+                            files_maps.insert(op_id);
+                        }
+                        Some(key) if files_maps.contains(&op.obj) => {
+                            let file_path = RelativePath::new(&key);
+                            file_objects.insert(op_id, file_path.clone());
+                            files.push(file_path);
+                        }
+                        _ => {
+                            if let Some(file_path) = file_objects.get(&op.obj) {
+                                files.push(file_path.clone());
+                            }
+                        }
+                    }
+                }
+                files.sort_by(|a, b| a.as_path().cmp(b.as_path()));
+                files.dedup();
+
+                Ok(ChangeInfo {
+                    hash: change.hash(),
+                    author: self.author(&actor),
+                    actor,
+                    timestamp: change.timestamp(),
+                    message: change.message().cloned(),
+                    files,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the heads of the history as it was at the given time: only changes made at or
+    /// before that time, and which only depend on such changes, are included.
+    #[must_use]
+    pub fn heads_at_time(&mut self, timestamp: i64) -> Vec<ChangeHash> {
+        let mut included: HashSet<ChangeHash> = HashSet::new();
+        let mut heads: Vec<ChangeHash> = vec![];
+        // Changes come after their dependencies, so we can decide in a single pass.
+        for meta in self.doc.get_changes_meta(&[]) {
+            if meta.timestamp <= timestamp && meta.deps.iter().all(|dep| included.contains(dep)) {
+                heads.retain(|head| !meta.deps.contains(head));
+                heads.push(meta.hash);
+                included.insert(meta.hash);
+            }
+        }
+        heads
+    }
+
+    /// Finds out which change last inserted text into each line of a text file. Deletions are not
+    /// taken into account.
+    pub fn blame(&mut self, file_path: &RelativePath) -> Result<Vec<BlameLine>> {
+        let text_obj = self.text_obj(file_path)?;
+
+        // Changes come after their dependencies, so a higher index means a later change.
+        let metadata: Vec<ChangeMetadata<'static>> = self
+            .doc
+            .get_changes_meta(&[])
+            .into_iter()
+            .map(ChangeMetadata::into_owned)
+            .collect();
+
+        // We'd like to use `AutoCommit::hash_for_opid` to find the change of each character, but
+        // it misses the last op of each change. So we look up the ops' ranges ourselves.
+        let mut op_ranges: HashMap<&ActorId, Vec<(u64, u64, usize)>> = HashMap::new();
+        for (index, meta) in metadata.iter().enumerate() {
+            op_ranges.entry(meta.actor.as_ref()).or_default().push((
+                meta.start_op,
+                meta.max_op,
+                index,
+            ));
+        }
+        for ranges in op_ranges.values_mut() {
+            ranges.sort_unstable();
+        }
+        let change_index = |op_id: &automerge::ObjId| {
+            let automerge::ObjId::Id(counter, actor, _) = op_id else {
+                return None;
+            };
+            let ranges = op_ranges.get(actor)?;
+            let position = ranges.partition_point(|(_, max_op, _)| max_op < counter);
+            ranges
+                .get(position)
+                .filter(|(start_op, _, _)| start_op <= counter)
+                .map(|(_, _, index)| *index)
+        };
+
+        let mut line_changes = vec![];
+        let mut latest: Option<usize> = None;
+        let mut line_is_empty = true;
+        for item in self.doc.list_range(&text_obj, ..) {
+            latest = latest.max(change_index(&item.id()));
+            line_is_empty = false;
+            if let automerge::ValueRef::Scalar(automerge::ScalarValueRef::Str(character)) =
+                &item.value
+            {
+                if character.contains('\n') {
+                    line_changes.push(latest.take());
+                    line_is_empty = true;
+                }
+            }
+        }
+        if !line_is_empty {
+            line_changes.push(latest);
+        }
+
+        line_changes
+            .into_iter()
+            .enumerate()
+            .map(|(line, index)| {
+                let Some(meta) = index.map(|index| &metadata[index]) else {
+                    bail!(
+                        "Couldn't find the change which inserted line {} of {file_path}",
+                        line + 1
+                    );
+                };
+                let actor = meta.actor.to_hex_string();
+                Ok(BlameLine {
+                    hash: meta.hash.to_string(),
+                    author: self.author(&actor),
+                    actor,
+                    timestamp: meta.timestamp,
+                })
+            })
+            .collect()
+    }
+
+    /// Finds the change whose hash starts with `prefix`.
+    pub fn change_hash_by_prefix(&mut self, prefix: &str) -> Result<ChangeHash> {
+        let prefix = prefix.to_lowercase();
+        let matches: Vec<ChangeHash> = self
+            .doc
+            .get_changes_meta(&[])
+            .iter()
+            .map(|meta| meta.hash)
+            .filter(|hash| hash.to_string().starts_with(&prefix))
+            .collect();
+        match matches.as_slice() {
+            [hash] => Ok(*hash),
+            [] => bail!("There is no change with hash {prefix}"),
+            _ => bail!("The hash {prefix} is ambiguous, please provide more characters"),
+        }
+    }
+}
+
+/// Which object an operation in a change touches, and under which key, if it's in a map.
+#[derive(Deserialize)]
+struct OperationTarget {
+    obj: String,
+    key: Option<String>,
+}
+
+/// Lists the targets of the operations in a change, in order. Automerge doesn't expose the types
+/// of decoded operations, so we read them from its JSON representation.
+fn change_operations(change: &automerge::Change) -> Result<Vec<OperationTarget>> {
+    #[derive(Deserialize)]
+    struct Operations {
+        ops: Vec<OperationTarget>,
+    }
+
+    let operations = serde_json::to_value(change.decode())
+        .and_then(serde_json::from_value::<Operations>)
+        .with_context(|| {
+            format!(
+                "Failed to decode the operations of change {}",
+                change.hash()
+            )
+        })?;
+    Ok(operations.ops)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::factories::*;
+    use crate::types::{factories::*, PatchEffect};
 
     impl Document {
         fn assert_file_content(&self, file_path: &RelativePath, content: &str) {
@@ -370,6 +687,55 @@ mod tests {
         document.assert_file_content(&file, text);
     }
 
+    #[test]
+    fn identity_is_adopted_when_syncing() {
+        let mut host = Document::default();
+        let mut joiner = Document::default();
+        assert_eq!(host.identity(), None);
+        assert!(host.is_empty());
+        host.ensure_identity();
+        assert!(!host.is_empty());
+        let identity = host.identity().unwrap();
+        host.ensure_identity();
+        assert_eq!(host.identity(), Some(identity.clone()));
+
+        let mut host_state = SyncState::new();
+        let mut joiner_state = SyncState::new();
+        loop {
+            let host_message = host.generate_sync_message(&mut host_state);
+            let joiner_message = joiner.generate_sync_message(&mut joiner_state);
+            if host_message.is_none() && joiner_message.is_none() {
+                break;
+            }
+            if let Some(message) = host_message {
+                for patch in joiner.receive_sync_message_log_patches(message, &mut joiner_state) {
+                    assert!(PatchEffect::try_from(patch).is_ok());
+                }
+            }
+            if let Some(message) = joiner_message {
+                let _ = host.receive_sync_message_log_patches(message, &mut host_state);
+            }
+        }
+        assert_eq!(joiner.identity(), Some(identity));
+    }
+
+    #[test]
+    fn patches_from_newer_daemons_are_skipped() {
+        let mut document = Document::default();
+        let file = RelativePath::new("text");
+        let heads = document.get_heads();
+        document
+            .doc
+            .put_object(automerge::ROOT, "newer", ObjType::Map)
+            .unwrap();
+        document.initialize_text("hello", &file);
+        let new_heads = document.get_heads();
+
+        let effects = PatchEffect::from_crdt_patches(document.doc.diff(&heads, &new_heads));
+        assert_eq!(effects.len(), 2);
+        assert!(matches!(&effects[0], PatchEffect::FileChange(delta) if delta.file_path == file));
+    }
+
     #[test]
     fn can_initialize_content_multifile() {
         let mut document = Document::default();
@@ -387,6 +753,138 @@ mod tests {
         document.assert_file_content(&file2, text2);
     }
 
+    #[test]
+    fn changes_list_touched_files() {
+        let mut document = Document::default();
+        let file1 = RelativePath::new("text");
+        let file2 = RelativePath::new("dir/text2");
+
+        document.initialize_text("hello", &file1);
+        document.initialize_text("world", &file2);
+        let _ = document.get_heads(); // Commits the pending transaction.
+        document.apply_delta_to_doc(&insert(5, "!"), &file1);
+        let heads = document.get_heads();
+        document.remove_file(&file2);
+
+        let changes = document.changes().unwrap();
+        assert_eq!(changes.len(), 4);
+        assert_eq!(changes[0].files, vec![]);
+        assert_eq!(changes[1].files, vec![file2.clone(), file1.clone()]);
+        assert_eq!(changes[2].files, vec![file1]);
+        assert_eq!(changes[2].actor, document.actor_id());
+        assert_eq!(changes[2].hash, heads[0]);
+        assert_eq!(changes[3].files, vec![file2]);
+    }
+
+    #[test]
+    fn commits_have_metadata_and_author() {
+        let mut document = Document::default();
+        let file = RelativePath::new("text");
+        document.set_author("Ada");
+        let heads = document.get_heads();
+
+        document.initialize_text("hello", &file);
+        document.commit("Create text");
+        document.commit("Nothing to commit");
+
+        // Recording the author doesn't affect any files.
+        let new_heads = document.get_heads();
+        let effects = PatchEffect::from_crdt_patches(document.doc.diff(&heads, &new_heads));
+        assert_eq!(
+            effects
+                .iter()
+                .filter(|effect| matches!(effect, PatchEffect::NoEffect))
+                .count(),
+            1
+        );
+
+        let changes = document.changes().unwrap();
+        assert_eq!(changes.len(), 2);
+        let change = &changes[1];
+        assert_eq!(change.message.as_deref(), Some("Create text"));
+        assert!(change.timestamp > 0);
+        assert_eq!(change.author.as_deref(), Some("Ada"));
+        assert_eq!(change.files, vec![file]);
+    }
+
+    #[test]
+    fn blame_finds_the_latest_change_per_line() {
+        let mut document = Document::default();
+        let file = RelativePath::new("text");
+        document.set_author("Ada");
+
+        document.initialize_text("one\ntwo\nthree", &file);
+        document.commit("Create text");
+        let first = document.get_heads()[0].to_string();
+        document.update_text("one\ntwo!\nthree\n", &file);
+        document.commit("Edit text");
+        let second = document.get_heads()[0].to_string();
+
+        let blame = document.blame(&file).unwrap();
+        let hashes: Vec<&str> = blame.iter().map(|line| line.hash.as_str()).collect();
+        assert_eq!(hashes, vec![&first, &second, &second]);
+        assert_eq!(blame[0].author.as_deref(), Some("Ada"));
+        assert_eq!(blame[0].actor, document.actor_id());
+
+        document.update_text("", &file);
+        assert_eq!(document.blame(&file).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn can_go_back_in_time() {
+        let mut document = Document::default();
+        let text_file = RelativePath::new("text");
+        let binary_file = RelativePath::new("binary");
+
+        document.initialize_text("hello", &text_file);
+        document.set_bytes(&[1, 2, 3], &binary_file);
+        let heads_before = document.get_heads();
+        document.update_text("goodbye", &text_file);
+        document.remove_file(&binary_file);
+
+        assert_eq!(
+            document.file_at(&text_file, &heads_before).unwrap(),
+            FileContent::Text("hello".to_string())
+        );
+        assert_eq!(
+            document.file_at(&binary_file, &heads_before).unwrap(),
+            FileContent::Binary(vec![1, 2, 3])
+        );
+        let heads_now = document.get_heads();
+        document
+            .file_at(&binary_file, &heads_now)
+            .expect_err("File should have been removed");
+
+        let prefix = heads_before[0].to_string()[..8].to_string();
+        assert_eq!(
+            document.change_hash_by_prefix(&prefix).unwrap(),
+            heads_before[0]
+        );
+        document
+            .change_hash_by_prefix("0000000000")
+            .expect_err("Hash shouldn't exist");
+    }
+
+    #[test]
+    fn heads_at_time_only_include_earlier_changes() {
+        let mut document = Document::default();
+        let file = RelativePath::new("text");
+
+        document.initialize_text("hello", &file);
+        document
+            .doc
+            .commit_with(CommitOptions::default().with_time(100));
+        let heads_at_100 = document.get_heads();
+        document.update_text("hello world", &file);
+        document
+            .doc
+            .commit_with(CommitOptions::default().with_time(200));
+
+        assert_eq!(document.heads_at_time(150), heads_at_100);
+        assert_eq!(document.heads_at_time(200), document.get_heads());
+        assert_eq!(document.heads_at_time(-1), vec![]);
+    }
+
     #[test]
     fn retrieve_content_file_nonexistent_errs() {
         let document = Document::default();
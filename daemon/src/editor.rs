@@ -4,8 +4,8 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 //! This module is all about daemon to editor communication.
-use crate::cli_ask::ask;
 use crate::daemon::{DocMessage, DocumentActorHandle};
+use crate::daemon_client::{self, PingResult};
 use crate::sandbox;
 use crate::types::EditorProtocolObject;
 use anyhow::{bail, Context, Result};
@@ -42,7 +42,7 @@ impl Encoder<EditorProtocolObject> for EditorProtocolCodec {
     }
 }
 
-fn is_user_readable_only(socket_path: &Path) -> Result<()> {
+pub(crate) fn is_user_readable_only(socket_path: &Path) -> Result<()> {
     let parent_dir = socket_path
         .parent()
         .context("The socket path should not be the root directory")?;
@@ -61,7 +61,7 @@ fn is_user_readable_only(socket_path: &Path) -> Result<()> {
 /// # Panics
 ///
 /// Will panic if we fail to listen on the socket, or if we fail to accept an incoming connection.
-pub fn spawn_socket_listener(
+pub async fn spawn_socket_listener(
     socket_path: &Path,
     document_handle: DocumentActorHandle,
 ) -> Result<()> {
@@ -77,11 +77,21 @@ pub fn spawn_socket_listener(
         .expect("Failed to check existence of path")
     {
         let socket_path_display = socket_path.display();
-        let remove_socket = ask(&format!("Detected an existing socket '{socket_path_display}'. There might be a daemon running already for this directory, or the previous one crashed. Do you want to continue?"));
-        if remove_socket? {
-            sandbox::remove_file(Path::new("/"), socket_path).expect("Could not remove socket");
-        } else {
-            bail!("Not continuing, make sure to stop all other daemons on this directory");
+        match daemon_client::ping(socket_path).await {
+            PingResult::Dead => {
+                info!("Removing the socket '{socket_path_display}', which was left behind by a previous daemon.");
+                sandbox::remove_file(Path::new("/"), socket_path).expect("Could not remove socket");
+            }
+            PingResult::Alive => {
+                let status = daemon_client::status(socket_path)
+                    .await
+                    .map(|status| format!("\n\n{status}"))
+                    .unwrap_or_default();
+                bail!("Another daemon is already running for this directory, on '{socket_path_display}'. Stop it with `teamtype stop` first.{status}");
+            }
+            PingResult::NotResponding => {
+                bail!("Another daemon is listening on '{socket_path_display}', but doesn't respond. Stop it first, or remove the socket if you're sure that it's not running.");
+            }
         }
     }
 
@@ -46,6 +46,18 @@ impl EditorConnection {
         self.ot_servers.contains_key(file_path)
     }
 
+    #[must_use]
+    pub fn owned_files(&self) -> Vec<RelativePath> {
+        let mut file_paths: Vec<RelativePath> = self.ot_servers.keys().cloned().collect();
+        file_paths.sort_by(|a, b| a.as_path().cmp(b.as_path()));
+        file_paths
+    }
+
+    #[must_use]
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
     /// A message from inside is either an edit from another local editor or an edit that came
     /// from another peer but is prepared to be applied to all components.
     #[must_use]
@@ -249,6 +261,21 @@ impl EditorConnection {
                     vec![],
                 ))
             }
+            EditorProtocolMessageFromEditor::Status
+            | EditorProtocolMessageFromEditor::Blame { .. }
+            | EditorProtocolMessageFromEditor::Restore { .. }
+            | EditorProtocolMessageFromEditor::Shutdown
+            | EditorProtocolMessageFromEditor::Ping
+            | EditorProtocolMessageFromEditor::Compact
+            | EditorProtocolMessageFromEditor::RotateSecret
+            | EditorProtocolMessageFromEditor::Disconnect { .. }
+            | EditorProtocolMessageFromEditor::DecideOnPeer { .. } => {
+                Err(EditorProtocolMessageError {
+                    code: -1,
+                    message: "Not an editor message".into(),
+                    data: Some("This request is answered by the daemon itself.".into()),
+                })
+            }
         }
     }
 }
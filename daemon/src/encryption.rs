@@ -0,0 +1,437 @@
+// SPDX-FileCopyrightText: 2025 blinry <mail@blinry.org>
+// SPDX-FileCopyrightText: 2025 zormit <nt4u@kpvn.de>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Optional encryption of `.teamtype/doc` and `.teamtype/key` at rest, with a passphrase.
+//!
+//! When a directory is encrypted, `.teamtype/encryption` holds the parameters to derive a key from
+//! the passphrase (PBKDF2-HMAC-SHA256 with a random salt), and a record which lets us tell whether
+//! a passphrase is correct. The encrypted files are sequences of records, each sealed with
+//! XChaCha20-Poly1305 and a random nonce. Appending to the history adds a record, so a crash can
+//! only damage the last one.
+use crate::persistence::{FilePersistence, Persistence};
+use crate::{config, sandbox};
+use anyhow::{bail, Context, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::io::IsTerminal as _;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Set this environment variable to the passphrase, to unlock encrypted directories without being
+/// asked.
+pub const PASSPHRASE_ENV_VAR: &str = "TEAMTYPE_PASSPHRASE";
+
+/// Set for daemons started in the background, which read the passphrase from their input instead,
+/// so that it doesn't show up in their environment.
+pub const PASSPHRASE_ON_STDIN_ENV_VAR: &str = "TEAMTYPE_PASSPHRASE_ON_STDIN";
+
+const MAGIC_BYTES: &[u8; 8] = b"TTCRYPT1";
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+const TAG_LENGTH: usize = 16;
+/// As recommended by OWASP for PBKDF2-HMAC-SHA256 in 2023.
+const ITERATIONS: u32 = 600_000;
+
+/// Directories unlocked by this process, so that we ask for each passphrase only once.
+static UNLOCKED: Mutex<BTreeMap<PathBuf, Unlocked>> = Mutex::new(BTreeMap::new());
+
+/// What an encrypted record contains. A record only decrypts for the purpose it was sealed for,
+/// so that encrypted files can't be swapped for each other.
+#[derive(Debug, Clone, Copy)]
+pub enum Purpose {
+    Verification,
+    Document,
+    KeyFile,
+}
+
+impl Purpose {
+    const fn associated_data(self) -> &'static [u8] {
+        match self {
+            Self::Verification => b"teamtype verification",
+            Self::Document => b"teamtype doc",
+            Self::KeyFile => b"teamtype key",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn derive(passphrase: &str, salt: &[u8], iterations: u32) -> Self {
+        let key = derive_key(passphrase.as_bytes(), salt, iterations);
+        Self {
+            aead: XChaCha20Poly1305::new(&key.into()),
+        }
+    }
+
+    /// Encrypts `plaintext` into a record: Its length as a 4-byte big-endian number, a random
+    /// nonce, and the ciphertext.
+    #[must_use]
+    pub fn seal(&self, purpose: Purpose, plaintext: &[u8]) -> Vec<u8> {
+        let mut nonce = XNonce::default();
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self
+            .aead
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: purpose.associated_data(),
+                },
+            )
+            .expect("Encryption should never fail");
+        let length = u32::try_from(NONCE_LENGTH + ciphertext.len())
+            .expect("Records should be smaller than 4 GiB");
+
+        let mut record = length.to_be_bytes().to_vec();
+        record.extend_from_slice(&nonce);
+        record.extend(ciphertext);
+        record
+    }
+
+    /// Decrypts `bytes`, which must be exactly one record.
+    pub fn open(&self, purpose: Purpose, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self.open_record(purpose, bytes, 0) {
+            Some((plaintext, end)) if end == bytes.len() => Ok(plaintext),
+            _ => bail!("Failed to decrypt. The data is damaged, or was encrypted with another key"),
+        }
+    }
+
+    /// Decrypts as many records at the start of `bytes` as possible. Returns their concatenated
+    /// plaintexts, and how many bytes they took up.
+    #[must_use]
+    pub fn open_records(&self, purpose: Purpose, bytes: &[u8]) -> (Vec<u8>, usize) {
+        let mut plaintext = vec![];
+        let mut start = 0;
+        while let Some((record_plaintext, end)) = self.open_record(purpose, bytes, start) {
+            plaintext.extend(record_plaintext);
+            start = end;
+        }
+        (plaintext, start)
+    }
+
+    /// Decrypts the record starting at `start`. Returns its plaintext, and where it ends.
+    fn open_record(
+        &self,
+        purpose: Purpose,
+        bytes: &[u8],
+        start: usize,
+    ) -> Option<(Vec<u8>, usize)> {
+        let length_bytes = bytes.get(start..start + 4)?;
+        let length = u32::from_be_bytes(length_bytes.try_into().ok()?) as usize;
+        if length < NONCE_LENGTH + TAG_LENGTH {
+            return None;
+        }
+        let end = (start + 4).checked_add(length)?;
+        let record = bytes.get(start + 4..end)?;
+        let (nonce, ciphertext) = record.split_at(NONCE_LENGTH);
+        let plaintext = self
+            .aead
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: purpose.associated_data(),
+                },
+            )
+            .ok()?;
+        Some((plaintext, end))
+    }
+}
+
+/// The length of a record holding `plaintext_length` bytes.
+#[must_use]
+pub const fn sealed_length(plaintext_length: usize) -> usize {
+    4 + NONCE_LENGTH + plaintext_length + TAG_LENGTH
+}
+
+/// A directory which we know the passphrase of.
+#[derive(Clone)]
+pub struct Unlocked {
+    passphrase: String,
+    cipher: Cipher,
+}
+
+impl Unlocked {
+    #[must_use]
+    pub fn passphrase(&self) -> &str {
+        &self.passphrase
+    }
+}
+
+fn encryption_file(base_dir: &Path) -> PathBuf {
+    base_dir
+        .join(config::CONFIG_DIR)
+        .join(config::ENCRYPTION_FILE)
+}
+
+/// Whether the history and the key file of `base_dir` are encrypted.
+#[must_use]
+pub fn is_enabled(base_dir: &Path) -> bool {
+    sandbox::exists(base_dir, &encryption_file(base_dir)).unwrap_or(false)
+}
+
+/// Returns the cipher for the files in `base_dir`, or `None` if they aren't encrypted. The first
+/// time, this reads the passphrase from [`PASSPHRASE_ENV_VAR`], or asks for it.
+pub fn cipher(base_dir: &Path) -> Result<Option<Cipher>> {
+    Ok(unlock(base_dir)?.map(|unlocked| unlocked.cipher))
+}
+
+/// Like [`cipher`], but also returns the passphrase, so that it can be handed to a daemon running
+/// in the background.
+pub fn unlock(base_dir: &Path) -> Result<Option<Unlocked>> {
+    if !is_enabled(base_dir) {
+        return Ok(None);
+    }
+    if let Some(unlocked) = UNLOCKED.lock().expect("Lock was poisoned").get(base_dir) {
+        return Ok(Some(unlocked.clone()));
+    }
+
+    let passphrase = read_passphrase(&format!(
+        "Passphrase for '{}': ",
+        base_dir.join(config::CONFIG_DIR).display()
+    ))?;
+    let cipher = unlock_with(base_dir, &passphrase)?;
+    let unlocked = Unlocked { passphrase, cipher };
+    UNLOCKED
+        .lock()
+        .expect("Lock was poisoned")
+        .insert(base_dir.to_path_buf(), unlocked.clone());
+    Ok(Some(unlocked))
+}
+
+fn unlock_with(base_dir: &Path, passphrase: &str) -> Result<Cipher> {
+    let file = encryption_file(base_dir);
+    let header = sandbox::read_file(base_dir, &file)?;
+    let salt_start = MAGIC_BYTES.len() + 4;
+    let verification_start = salt_start + SALT_LENGTH;
+    if header.len() < verification_start || !header.starts_with(MAGIC_BYTES) {
+        bail!(
+            "'{}' is damaged, or was written by a newer version of Teamtype",
+            file.display()
+        );
+    }
+    let iterations = u32::from_be_bytes(
+        header[MAGIC_BYTES.len()..salt_start]
+            .try_into()
+            .expect("Slice should have 4 bytes"),
+    );
+
+    let cipher = Cipher::derive(
+        passphrase,
+        &header[salt_start..verification_start],
+        iterations,
+    );
+    if cipher
+        .open(Purpose::Verification, &header[verification_start..])
+        .is_err()
+    {
+        bail!("Wrong passphrase for '{}'", base_dir.display());
+    }
+    Ok(cipher)
+}
+
+/// Sets up encryption for `base_dir` with a new passphrase. Files written afterwards are
+/// encrypted, existing files are not touched.
+fn enable(base_dir: &Path, passphrase: &str, iterations: u32) -> Result<Cipher> {
+    let mut salt = [0; SALT_LENGTH];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    let cipher = Cipher::derive(passphrase, &salt, iterations);
+
+    let mut header = MAGIC_BYTES.to_vec();
+    header.extend_from_slice(&iterations.to_be_bytes());
+    header.extend_from_slice(&salt);
+    header.extend(cipher.seal(Purpose::Verification, b""));
+    sandbox::write_file_atomically(base_dir, &encryption_file(base_dir), &header)?;
+
+    UNLOCKED.lock().expect("Lock was poisoned").insert(
+        base_dir.to_path_buf(),
+        Unlocked {
+            passphrase: passphrase.to_string(),
+            cipher: cipher.clone(),
+        },
+    );
+    Ok(cipher)
+}
+
+/// Encrypts the history and the key file of `base_dir`, which must not be encrypted yet. Also
+/// works before they exist: They'll be encrypted when they're created.
+pub fn encrypt_directory(base_dir: &Path, passphrase: &str) -> Result<()> {
+    encrypt_directory_with_iterations(base_dir, passphrase, ITERATIONS)
+}
+
+fn encrypt_directory_with_iterations(
+    base_dir: &Path,
+    passphrase: &str,
+    iterations: u32,
+) -> Result<()> {
+    if is_enabled(base_dir) {
+        bail!("'{}' is encrypted already", base_dir.display());
+    }
+
+    // Read everything before enabling encryption, while we can still read the plaintext.
+    let mut persistence = FilePersistence::new(base_dir)?;
+    let mut document = if persistence.exists()? {
+        Some(persistence.load()?)
+    } else {
+        None
+    };
+    let key_file = base_dir.join(config::CONFIG_DIR).join(config::KEY_FILE);
+    let key = if sandbox::exists(base_dir, &key_file)? {
+        Some(sandbox::read_file(base_dir, &key_file)?)
+    } else {
+        None
+    };
+
+    let cipher = enable(base_dir, passphrase, iterations)?;
+    if let Some(document) = &mut document {
+        FilePersistence::new(base_dir)?.save(&document.save())?;
+    }
+    if let Some(key) = key {
+        sandbox::write_private_file_atomically(
+            base_dir,
+            &key_file,
+            &cipher.seal(Purpose::KeyFile, &key),
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads a passphrase from [`PASSPHRASE_ENV_VAR`] or, if [`PASSPHRASE_ON_STDIN_ENV_VAR`] is set,
+/// from the first line of the input. Otherwise, asks for it without showing what is typed.
+fn read_passphrase(prompt: &str) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV_VAR) {
+        // Processes we start (like Git) don't need to see it.
+        std::env::remove_var(PASSPHRASE_ENV_VAR);
+        return Ok(passphrase);
+    }
+    if std::env::var_os(PASSPHRASE_ON_STDIN_ENV_VAR).is_some() {
+        std::env::remove_var(PASSPHRASE_ON_STDIN_ENV_VAR);
+        let mut line = String::new();
+        std::io::stdin()
+            .read_line(&mut line)
+            .context("Failed to read the passphrase from the input")?;
+        return Ok(line.strip_suffix('\n').unwrap_or(&line).to_string());
+    }
+    if !std::io::stdin().is_terminal() {
+        bail!("Can't ask for the passphrase, because the input is not a terminal. Set {PASSPHRASE_ENV_VAR} instead.");
+    }
+
+    // Turns the echo back on when it's done, also if reading fails.
+    rpassword::prompt_password(prompt).context("Failed to read the passphrase")
+}
+
+/// Reads a new passphrase, and asks for it twice to avoid typos.
+pub fn read_new_passphrase() -> Result<String> {
+    let asked = std::env::var_os(PASSPHRASE_ENV_VAR).is_none();
+    let passphrase = read_passphrase("New passphrase: ")?;
+    if passphrase.is_empty() {
+        bail!("The passphrase can't be empty");
+    }
+    if asked && read_passphrase("Repeat the passphrase: ")? != passphrase {
+        bail!("The passphrases don't match");
+    }
+    Ok(passphrase)
+}
+
+/// PBKDF2 (RFC 8018) with HMAC-SHA256, for a 32-byte key.
+fn derive_key(passphrase: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut key = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt, iterations, &mut key);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Document;
+    use crate::path::RelativePath;
+    use crate::persistence;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn derives_keys_like_pbkdf2() {
+        // Test vectors from RFC 7914, section 11.
+        let key = derive_key(b"passwd", b"salt", 1);
+        assert_eq!(
+            key[..],
+            [
+                0x55, 0xac, 0x04, 0x6e, 0x56, 0xe3, 0x08, 0x9f, 0xec, 0x16, 0x91, 0xc2, 0x25, 0x44,
+                0xb6, 0x05, 0xf9, 0x41, 0x85, 0x21, 0x6d, 0xde, 0x04, 0x65, 0xe6, 0x8b, 0x9d, 0x57,
+                0xc2, 0x0d, 0xac, 0xbc
+            ]
+        );
+    }
+
+    #[test]
+    fn opens_only_intact_records() {
+        let cipher = Cipher::derive("secret", b"salt", 1);
+        let mut bytes = cipher.seal(Purpose::Document, b"hello ");
+        bytes.extend(cipher.seal(Purpose::Document, b"world"));
+        let complete_length = bytes.len();
+        bytes.extend(cipher.seal(Purpose::Document, b"!"));
+        bytes.truncate(bytes.len() - 1);
+
+        assert_eq!(
+            cipher.open_records(Purpose::Document, &bytes),
+            (b"hello world".to_vec(), complete_length)
+        );
+        assert_eq!(cipher.open_records(Purpose::KeyFile, &bytes).1, 0);
+        assert!(Cipher::derive("other", b"salt", 1)
+            .open(Purpose::Document, &bytes[..sealed_length(6)])
+            .is_err());
+    }
+
+    #[test]
+    fn migrates_plaintext_directories() {
+        let dir = TempDir::new().unwrap();
+        sandbox::create_dir(dir.path(), &dir.child(config::CONFIG_DIR)).unwrap();
+        let file_path = RelativePath::new("file");
+        let mut document = Document::default();
+        document.update_text("top secret", &file_path);
+        let mut persistence = FilePersistence::new(dir.path()).unwrap();
+        persistence.save(&document.save()).unwrap();
+        let key_file = dir.child(config::CONFIG_DIR).join(config::KEY_FILE);
+        sandbox::write_file(dir.path(), &key_file, &[42; 64]).unwrap();
+
+        encrypt_directory_with_iterations(dir.path(), "secret", 10).unwrap();
+
+        let doc_bytes =
+            sandbox::read_file(dir.path(), &persistence::persistence_file(dir.path())).unwrap();
+        assert!(!doc_bytes.windows(3).any(|window| window == b"top"));
+        let cipher = cipher(dir.path()).unwrap().unwrap();
+        let key = sandbox::read_file(dir.path(), &key_file).unwrap();
+        assert_eq!(key.len(), sealed_length(64));
+        assert_eq!(cipher.open(Purpose::KeyFile, &key).unwrap(), [42; 64]);
+
+        // Appending adds encrypted records, which load together with the full save.
+        let mut persistence = FilePersistence::new(dir.path()).unwrap();
+        document.update_text("top secret!", &file_path);
+        persistence.append(&document.save_incremental()).unwrap();
+        let loaded = persistence.load().unwrap();
+        assert_eq!(
+            loaded.current_file_content(&file_path).unwrap(),
+            "top secret!"
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_passphrases() {
+        let dir = TempDir::new().unwrap();
+        sandbox::create_dir(dir.path(), &dir.child(config::CONFIG_DIR)).unwrap();
+        encrypt_directory_with_iterations(dir.path(), "secret", 10).unwrap();
+
+        assert!(is_enabled(dir.path()));
+        assert!(unlock_with(dir.path(), "secret").is_ok());
+        assert!(unlock_with(dir.path(), "wrong").is_err());
+        assert!(encrypt_directory_with_iterations(dir.path(), "secret", 10).is_err());
+    }
+}
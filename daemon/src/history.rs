@@ -0,0 +1,261 @@
+// SPDX-FileCopyrightText: 2025 blinry <mail@blinry.org>
+// SPDX-FileCopyrightText: 2025 zormit <nt4u@kpvn.de>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Browsing the history persisted in `.teamtype/doc`, for commands like `teamtype log`.
+//!
+//! These work without a running daemon, by loading the persisted document directly.
+use crate::{
+    config,
+    document::{ChangeInfo, Document, FileContent},
+    path::{AbsolutePath, RelativePath},
+    persistence::{self, FilePersistence, Persistence},
+    pidfile::{self, Pidfile},
+    sandbox, timestamp,
+    types::{BlameLine, CompactionResult},
+};
+use anyhow::{bail, Result};
+use automerge::ChangeHash;
+use std::{fmt, path::Path};
+
+/// Change hashes given by users need at least this many characters, so that they can be told
+/// apart from times like "3d".
+const MIN_HASH_PREFIX_LENGTH: usize = 7;
+
+/// Loads the history of the shared directory at `base_dir`. A daemon may be running, so this
+/// doesn't change the persisted history.
+pub fn load_document(base_dir: &Path) -> Result<Document> {
+    ensure_history_exists(base_dir)?;
+    persistence::load_document(base_dir)
+}
+
+/// Loads the history of the shared directory at `base_dir` for changing it. Holding the returned
+/// pidfile keeps a daemon from starting in the meantime.
+fn load_document_for_writing(base_dir: &Path) -> Result<(Pidfile, FilePersistence, Document)> {
+    let pidfile = pidfile::acquire(base_dir)?;
+    ensure_history_exists(base_dir)?;
+    let mut persistence = FilePersistence::new(base_dir)?;
+    let document = persistence.load()?;
+    Ok((pidfile, persistence, document))
+}
+
+fn ensure_history_exists(base_dir: &Path) -> Result<()> {
+    let persistence_file = base_dir.join(config::CONFIG_DIR).join(config::DOC_FILE);
+    if !sandbox::exists(base_dir, &persistence_file)? {
+        bail!(
+            "There is no history at '{}'. Has Teamtype been run in this directory?",
+            persistence_file.display()
+        );
+    }
+    Ok(())
+}
+
+/// Rewrites the persisted history as a single compacted save, while no daemon is running.
+pub fn compact_offline(base_dir: &Path) -> Result<CompactionResult> {
+    let (_pidfile, mut persistence, mut document) = load_document_for_writing(base_dir)?;
+    let size_before = persistence.size()?;
+    persistence.save(&document.save())?;
+    Ok(CompactionResult {
+        size_before,
+        size_after: persistence.size()?,
+    })
+}
+
+/// Finds the point in history a user refers to with `at`: Either a change hash (or a unique prefix
+/// of one), or a time as understood by [`timestamp::parse`].
+pub fn resolve_at(document: &mut Document, at: &str) -> Result<Vec<ChangeHash>> {
+    let looks_like_hash =
+        at.len() >= MIN_HASH_PREFIX_LENGTH && at.chars().all(|c| c.is_ascii_hexdigit());
+    if looks_like_hash {
+        return Ok(vec![document.change_hash_by_prefix(at)?]);
+    }
+    let heads = document.heads_at_time(timestamp::parse(at)?);
+    if heads.is_empty() {
+        bail!("There are no changes before '{at}'");
+    }
+    Ok(heads)
+}
+
+/// Restores a file to its content `at` an earlier point, while no daemon is running. The change is
+/// made in the persisted history and on disk, so that the daemon picks it up when it starts.
+pub fn restore_offline(base_dir: &Path, file_path: &RelativePath, at: &str) -> Result<()> {
+    let (_pidfile, mut persistence, mut document) = load_document_for_writing(base_dir)?;
+    let heads = resolve_at(&mut document, at)?;
+    if let Some(username) = config::get_username(base_dir) {
+        document.set_author(&username);
+    }
+    let bytes = match document.file_at(file_path, &heads)? {
+        FileContent::Text(text) => {
+            document.update_text(&text, file_path);
+            text.into_bytes()
+        }
+        FileContent::Binary(bytes) => {
+            document.set_bytes(&bytes, file_path);
+            bytes
+        }
+    };
+    document.commit(&format!("Restore {file_path} to its content at '{at}'"));
+    persistence.save(&document.save())?;
+    sandbox::write_file(
+        base_dir,
+        &AbsolutePath::from_parts(base_dir, file_path)?,
+        &bytes,
+    )
+}
+
+/// Restricts which changes `teamtype log` shows.
+#[derive(Debug, Default)]
+pub struct LogFilter {
+    /// Only show changes touching this file.
+    pub file_path: Option<RelativePath>,
+    /// Only show changes made at or after this timestamp.
+    pub since: Option<i64>,
+    /// Only show changes made at or before this timestamp.
+    pub until: Option<i64>,
+}
+
+impl LogFilter {
+    fn matches(&self, change: &ChangeInfo) -> bool {
+        if let Some(file_path) = &self.file_path {
+            if !change.files.contains(file_path) {
+                return false;
+            }
+        }
+        // Changes without a timestamp can't be placed in a time range.
+        let has_time_range = self.since.is_some() || self.until.is_some();
+        if has_time_range && change.timestamp == 0 {
+            return false;
+        }
+        self.since.map_or(true, |since| change.timestamp >= since)
+            && self.until.map_or(true, |until| change.timestamp <= until)
+    }
+}
+
+/// Returns the changes matching the filter, newest first.
+pub fn log(document: &mut Document, filter: &LogFilter) -> Result<Vec<ChangeInfo>> {
+    let mut changes: Vec<ChangeInfo> = document
+        .changes()?
+        .into_iter()
+        .filter(|change| filter.matches(change))
+        .collect();
+    changes.reverse();
+    Ok(changes)
+}
+
+/// Formats a change for `teamtype log`, similar to how `git log --stat` looks.
+pub struct LogEntry<'a>(pub &'a ChangeInfo);
+
+impl fmt::Display for LogEntry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let change = self.0;
+        writeln!(f, "change {}", change.hash)?;
+        match &change.author {
+            Some(author) => writeln!(f, "Author: {author} ({})", change.actor)?,
+            None => writeln!(f, "Author: unknown ({})", change.actor)?,
+        }
+        if change.timestamp == 0 {
+            writeln!(f, "Date:   unknown")?;
+        } else {
+            writeln!(f, "Date:   {}", timestamp::format(change.timestamp))?;
+        }
+        if let Some(message) = &change.message {
+            writeln!(f)?;
+            writeln!(f, "    {message}")?;
+        }
+        if !change.files.is_empty() {
+            writeln!(f)?;
+            for file_path in &change.files {
+                writeln!(f, "    {}", file_path.display())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats the result of [`Document::blame`] for `teamtype blame`, similar to how `git blame`
+/// looks.
+pub struct BlameView<'a> {
+    pub blame: &'a [BlameLine],
+    pub content: &'a str,
+}
+
+impl fmt::Display for BlameView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const SHORT_LENGTH: usize = 8;
+        let names: Vec<&str> = self
+            .blame
+            .iter()
+            .map(|line| {
+                line.author
+                    .as_deref()
+                    .unwrap_or_else(|| &line.actor[..SHORT_LENGTH.min(line.actor.len())])
+            })
+            .collect();
+        let name_width = names
+            .iter()
+            .map(|name| name.chars().count())
+            .max()
+            .unwrap_or(0);
+        let number_width = self.blame.len().to_string().len();
+
+        for (i, (line, text)) in self.blame.iter().zip(self.content.lines()).enumerate() {
+            let date = if line.timestamp == 0 {
+                "unknown".to_string()
+            } else {
+                timestamp::format(line.timestamp)
+            };
+            writeln!(
+                f,
+                "{} ({:<name_width$} {date:<23} {:>number_width$}) {text}",
+                &line.hash[..SHORT_LENGTH],
+                names[i],
+                i + 1,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use automerge::ChangeHash;
+
+    fn change(timestamp: i64, files: &[&str]) -> ChangeInfo {
+        ChangeInfo {
+            hash: ChangeHash([0; 32]),
+            actor: "abc".to_string(),
+            author: None,
+            timestamp,
+            message: None,
+            files: files.iter().map(|f| RelativePath::new(f)).collect(),
+        }
+    }
+
+    #[test]
+    fn filter_by_path() {
+        let filter = LogFilter {
+            file_path: Some(RelativePath::new("a")),
+            ..Default::default()
+        };
+        assert!(filter.matches(&change(0, &["a", "b"])));
+        assert!(!filter.matches(&change(0, &["b"])));
+        assert!(!filter.matches(&change(0, &[])));
+    }
+
+    #[test]
+    fn filter_by_time_range() {
+        let filter = LogFilter {
+            since: Some(100),
+            until: Some(200),
+            ..Default::default()
+        };
+        assert!(filter.matches(&change(100, &[])));
+        assert!(filter.matches(&change(200, &[])));
+        assert!(!filter.matches(&change(99, &[])));
+        assert!(!filter.matches(&change(201, &[])));
+        assert!(!filter.matches(&change(0, &[])));
+        assert!(LogFilter::default().matches(&change(0, &[])));
+    }
+}
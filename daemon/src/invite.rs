@@ -0,0 +1,341 @@
+// SPDX-FileCopyrightText: 2025 blinry <mail@blinry.org>
+// SPDX-FileCopyrightText: 2025 zormit <nt4u@kpvn.de>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Invitations, which let peers join without learning our passphrase, in `.teamtype/invites`.
+//!
+//! Each invite has a passphrase of its own, derived from our passphrase and the invite's ID. So it
+//! stops working when the invite expires, when it's used up, when it's revoked, or when our
+//! passphrase is rotated. A peer which joined with an invite is trusted from then on, so it
+//! doesn't need the invite anymore.
+//!
+//! Each line of the file has the form
+//! `<ID> <full|read-only> <created> <expires|never> <uses left|unlimited>`, with Unix timestamps.
+use crate::peer;
+use crate::types::Access;
+use crate::{config, sandbox, timestamp};
+use anyhow::{bail, Context, Result};
+use iroh::SecretKey;
+use nix::fcntl::Flock;
+use rand::RngCore;
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Invites handed out by the join codes of `teamtype share` can be used once, and expire after a
+/// day, in case the peer doesn't connect right away.
+pub const JOIN_CODE_INVITE_LIFETIME: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    pub id: String,
+    pub access: Access,
+    pub created: i64,
+    pub expires: Option<i64>,
+    /// How many more peers can join with it. `None` if there's no limit.
+    pub uses_left: Option<u32>,
+}
+
+impl Invite {
+    /// The passphrase which peers have to send when they join with this invite.
+    #[must_use]
+    pub fn passphrase(&self, my_passphrase: &SecretKey) -> SecretKey {
+        peer::derive_passphrase(my_passphrase, &format!("teamtype invite {}", self.id))
+    }
+
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+impl fmt::Display for Invite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id)?;
+        if self.access == Access::ReadOnly {
+            write!(f, " (read-only)")?;
+        }
+        write!(f, ", created {}", timestamp::format(self.created))?;
+        match self.expires {
+            Some(expires) if self.is_expired(timestamp::now()) => {
+                write!(f, ", expired {}", timestamp::format(expires))?;
+            }
+            Some(expires) => write!(f, ", expires {}", timestamp::format(expires))?,
+            None => write!(f, ", never expires")?,
+        }
+        match self.uses_left {
+            Some(0) => write!(f, ", used up"),
+            Some(1) => write!(f, ", 1 use left"),
+            Some(uses_left) => write!(f, ", {uses_left} uses left"),
+            None => write!(f, ", unlimited uses"),
+        }
+    }
+}
+
+/// What happened when a peer tried to join with one of our invites.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Redemption {
+    Accepted(Invite),
+    Expired(Invite),
+    UsedUp(Invite),
+}
+
+fn invites_file(base_dir: &Path) -> PathBuf {
+    base_dir.join(config::CONFIG_DIR).join(config::INVITES_FILE)
+}
+
+/// Peers redeem invites concurrently, and the CLI creates and revokes them while the daemon runs,
+/// so they take this lock around reading and rewriting the invites file.
+fn lock(base_dir: &Path) -> Result<Flock<File>> {
+    let lock_file = base_dir
+        .join(config::CONFIG_DIR)
+        .join(format!("{}.lock", config::INVITES_FILE));
+    sandbox::lock_exclusively(base_dir, &lock_file)
+}
+
+/// Returns all invites, in the order they were created.
+pub fn invites(base_dir: &Path) -> Result<Vec<Invite>> {
+    let file = invites_file(base_dir);
+    if !sandbox::exists(base_dir, &file)? {
+        return Ok(vec![]);
+    }
+    let content = String::from_utf8(sandbox::read_file(base_dir, &file)?)?;
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            parse_line(line).with_context(|| format!("Invalid line in '{}'", file.display()))
+        })
+        .collect()
+}
+
+fn parse_line(line: &str) -> Result<Invite> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    let [id, access, created, expires, uses_left] = parts[..] else {
+        bail!("Expected '<ID> <access> <created> <expires> <uses left>', got '{line}'");
+    };
+    let access = match access {
+        "full" => Access::Full,
+        "read-only" => Access::ReadOnly,
+        _ => bail!("Unknown access '{access}'"),
+    };
+    Ok(Invite {
+        id: id.to_string(),
+        access,
+        created: created.parse()?,
+        expires: match expires {
+            "never" => None,
+            expires => Some(expires.parse()?),
+        },
+        uses_left: match uses_left {
+            "unlimited" => None,
+            uses_left => Some(uses_left.parse()?),
+        },
+    })
+}
+
+fn write_invites(base_dir: &Path, invites: &[Invite]) -> Result<()> {
+    let mut content = String::new();
+    for invite in invites {
+        let access = match invite.access {
+            Access::Full => "full",
+            Access::ReadOnly => "read-only",
+        };
+        let expires = invite
+            .expires
+            .map_or_else(|| "never".to_string(), |expires| expires.to_string());
+        let uses_left = invite.uses_left.map_or_else(
+            || "unlimited".to_string(),
+            |uses_left| uses_left.to_string(),
+        );
+        let _ = writeln!(
+            content,
+            "{} {access} {} {expires} {uses_left}",
+            invite.id, invite.created
+        );
+    }
+    sandbox::write_private_file_atomically(base_dir, &invites_file(base_dir), content.as_bytes())
+}
+
+/// Creates a new invite, which grants `access` to at most `uses` peers, until `expires`.
+pub fn create(
+    base_dir: &Path,
+    access: Access,
+    expires: Option<i64>,
+    uses: Option<u32>,
+) -> Result<Invite> {
+    let invite = Invite {
+        id: format!("{:08x}", rand::rngs::OsRng.next_u32()),
+        access,
+        created: timestamp::now(),
+        expires,
+        uses_left: uses,
+    };
+    let _lock = lock(base_dir)?;
+    let mut invites = invites(base_dir)?;
+    invites.push(invite.clone());
+    write_invites(base_dir, &invites)?;
+    Ok(invite)
+}
+
+/// Deletes the invite whose ID starts with `prefix`. Returns its full ID.
+pub fn revoke(base_dir: &Path, prefix: &str) -> Result<String> {
+    let _lock = lock(base_dir)?;
+    let mut invites = invites(base_dir)?;
+    let matching: Vec<usize> = invites
+        .iter()
+        .enumerate()
+        .filter(|(_, invite)| invite.id.starts_with(prefix))
+        .map(|(index, _)| index)
+        .collect();
+    let index = match matching[..] {
+        [index] => index,
+        [] => bail!("There is no invite with an ID starting with '{prefix}'"),
+        _ => bail!("Several invites have an ID starting with '{prefix}'"),
+    };
+    let invite = invites.remove(index);
+    write_invites(base_dir, &invites)?;
+    Ok(invite.id)
+}
+
+/// Finds the invite whose passphrase is `received`, if any, and checks whether it's still valid.
+/// Doesn't use it up yet, see `redeem`.
+pub fn check(
+    base_dir: &Path,
+    received: &[u8; 32],
+    my_passphrase: &SecretKey,
+) -> Result<Option<Redemption>> {
+    let invites = invites(base_dir)?;
+    let Some(invite) = invites.into_iter().find(|invite| {
+        // Guard against timing attacks.
+        constant_time_eq::constant_time_eq(received, &invite.passphrase(my_passphrase).to_bytes())
+    }) else {
+        return Ok(None);
+    };
+    Ok(Some(validate(invite)))
+}
+
+/// Uses up the invite with `id` once, if it's still valid. Call this when the peer which joined
+/// with it was let in, because it might have used the last use in the meantime.
+pub fn redeem(base_dir: &Path, id: &str) -> Result<Redemption> {
+    let _lock = lock(base_dir)?;
+    let mut invites = invites(base_dir)?;
+    let Some(invite) = invites.iter_mut().find(|invite| invite.id == id) else {
+        bail!("Invite {id} was revoked");
+    };
+    let redemption = validate(invite.clone());
+    if let Redemption::Accepted(_) = redemption {
+        if let Some(uses_left) = invite.uses_left.as_mut() {
+            *uses_left -= 1;
+        }
+        write_invites(base_dir, &invites)?;
+    }
+    Ok(redemption)
+}
+
+fn validate(invite: Invite) -> Redemption {
+    if invite.is_expired(timestamp::now()) {
+        Redemption::Expired(invite)
+    } else if invite.uses_left == Some(0) {
+        Redemption::UsedUp(invite)
+    } else {
+        Redemption::Accepted(invite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    fn setup() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        sandbox::create_dir(dir.path(), &dir.child(config::CONFIG_DIR)).unwrap();
+        dir
+    }
+
+    #[test]
+    fn single_use_invites_are_used_up() {
+        let dir = setup();
+        let my_passphrase = SecretKey::generate(rand::rngs::OsRng);
+        let invite = create(dir.path(), Access::ReadOnly, None, Some(1)).unwrap();
+        let received = invite.passphrase(&my_passphrase).to_bytes();
+
+        assert_eq!(check(dir.path(), &[0; 32], &my_passphrase).unwrap(), None);
+        // Checking doesn't use it up.
+        for _ in 0..2 {
+            assert!(matches!(
+                check(dir.path(), &received, &my_passphrase).unwrap(),
+                Some(Redemption::Accepted(Invite {
+                    access: Access::ReadOnly,
+                    ..
+                }))
+            ));
+        }
+        assert!(matches!(
+            redeem(dir.path(), &invite.id).unwrap(),
+            Redemption::Accepted(_)
+        ));
+        assert!(matches!(
+            check(dir.path(), &received, &my_passphrase).unwrap(),
+            Some(Redemption::UsedUp(_))
+        ));
+        assert!(matches!(
+            redeem(dir.path(), &invite.id).unwrap(),
+            Redemption::UsedUp(_)
+        ));
+
+        // Rotating our passphrase invalidates the invite.
+        let new_passphrase = SecretKey::generate(rand::rngs::OsRng);
+        assert_eq!(check(dir.path(), &received, &new_passphrase).unwrap(), None);
+    }
+
+    #[test]
+    fn concurrent_redemptions_use_an_invite_once() {
+        let dir = setup();
+        let invite = create(dir.path(), Access::Full, None, Some(1)).unwrap();
+
+        let redemptions = std::thread::scope(|scope| {
+            let mut handles = vec![];
+            for _ in 0..8 {
+                handles.push(scope.spawn(|| redeem(dir.path(), &invite.id)));
+            }
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap().unwrap())
+                .collect::<Vec<_>>()
+        });
+        let accepted = redemptions
+            .iter()
+            .filter(|redemption| matches!(redemption, Redemption::Accepted(_)))
+            .count();
+        assert_eq!(accepted, 1);
+    }
+
+    #[test]
+    fn expired_invites_are_rejected() {
+        let dir = setup();
+        let my_passphrase = SecretKey::generate(rand::rngs::OsRng);
+        let expired = create(dir.path(), Access::Full, Some(timestamp::now() - 1), None).unwrap();
+        let unlimited = create(dir.path(), Access::Full, None, None).unwrap();
+        assert_eq!(
+            invites(dir.path()).unwrap(),
+            vec![expired.clone(), unlimited]
+        );
+
+        let received = expired.passphrase(&my_passphrase).to_bytes();
+        assert_eq!(
+            check(dir.path(), &received, &my_passphrase).unwrap(),
+            Some(Redemption::Expired(expired.clone()))
+        );
+        assert_eq!(
+            redeem(dir.path(), &expired.id).unwrap(),
+            Redemption::Expired(expired.clone())
+        );
+
+        assert_eq!(revoke(dir.path(), &expired.id).unwrap(), expired.id);
+        assert_eq!(check(dir.path(), &received, &my_passphrase).unwrap(), None);
+        assert!(redeem(dir.path(), &expired.id).is_err());
+        assert_eq!(invites(dir.path()).unwrap().len(), 1);
+    }
+}
@@ -6,14 +6,23 @@
 pub mod cli_ask;
 pub mod config;
 pub mod daemon;
+pub mod daemon_client;
+pub mod doctor;
 pub mod document;
 pub mod editor;
 pub mod editor_connection;
+pub mod encryption;
+pub mod history;
+pub mod invite;
 pub mod logging;
 pub mod ot;
 pub mod path;
 pub mod peer;
+pub mod persistence;
+pub mod pidfile;
 pub mod sandbox;
+pub mod timestamp;
+pub mod trust;
 pub mod types;
 pub mod watcher;
 pub mod wormhole;
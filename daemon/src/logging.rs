@@ -4,12 +4,15 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use anyhow::Result;
+use std::io::IsTerminal as _;
 use time::macros::format_description;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{fmt::time::UtcTime, EnvFilter, FmtSubscriber};
 
 pub fn initialize() -> Result<()> {
     let simplified_logging = std::env::var("RUST_LOG").is_err();
+    // Don't put color codes into log files, like the one of a detached daemon.
+    let ansi = std::io::stdout().is_terminal();
 
     if simplified_logging {
         let subscriber = FmtSubscriber::builder()
@@ -17,6 +20,7 @@ pub fn initialize() -> Result<()> {
             .without_time()
             .with_level(false)
             .with_target(false)
+            .with_ansi(ansi)
             .finish();
 
         tracing::subscriber::set_global_default(subscriber)
@@ -30,6 +34,7 @@ pub fn initialize() -> Result<()> {
             .with_env_filter(filter)
             .with_thread_ids(true)
             .with_timer(timer)
+            .with_ansi(ansi)
             .finish();
 
         tracing::subscriber::set_global_default(subscriber)
@@ -3,20 +3,34 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use self::cli::{Cli, Commands, SyncVcsFlag};
+use self::cli::{Cli, Commands, DetachFlag, InviteCommand, SyncVcsFlag, TrustCommand};
 use anyhow::{bail, Context, Result};
 use clap::{CommandFactory as _, FromArgMatches as _};
+use std::collections::HashSet;
+use std::io::{IsTerminal as _, Write as _};
 use std::path::{Path, PathBuf};
 use teamtype::{
-    cli_ask::ask,
-    config::{self, AppConfig},
-    daemon::Daemon,
-    logging, sandbox,
+    cli_ask::{self, ask},
+    config::{self, AppConfig, Discovery, PeerApproval, Reconnect, Relays},
+    daemon::{Daemon, DocumentActorHandle},
+    daemon_client,
+    doctor::{self, Problem},
+    document::FileContent,
+    encryption,
+    history::{self, BlameView, LogEntry, LogFilter},
+    invite, logging,
+    path::{AbsolutePath, RelativePath},
+    peer,
+    persistence::{FilePersistence, Persistence},
+    pidfile, sandbox, timestamp, trust,
+    types::{Access, PeerDecision},
+    wormhole,
 };
-use tokio::signal;
+use tokio::{signal, sync::broadcast};
 use tracing::{debug, info, warn};
 
 mod cli;
+mod detach;
 mod jsonrpc_forwarder;
 
 fn has_ethersync_directory(dir: &Path) -> bool {
@@ -49,7 +63,32 @@ async fn main() -> Result<()> {
 
     logging::initialize().context("Failed to initialize logging")?;
 
-    let directory = get_directory(cli.directory).context("Failed to find .teamtype/ directory")?;
+    if cli.yes || cli.no {
+        cli_ask::assume_answer(cli.yes);
+    }
+
+    let directory = if matches!(
+        cli.command,
+        Commands::Status { .. }
+            | Commands::Log { .. }
+            | Commands::Show { .. }
+            | Commands::Blame { .. }
+            | Commands::Restore { .. }
+            | Commands::Stop
+            | Commands::Doctor
+            | Commands::Gc
+            | Commands::Trust { .. }
+            | Commands::Invite { .. }
+            | Commands::RotateSecret
+            | Commands::Disconnect { .. }
+            | Commands::Approve { .. }
+            | Commands::Reject { .. }
+    ) {
+        // Commands that only inspect an existing directory shouldn't set up a new one.
+        canonical_directory(cli.directory)
+    } else {
+        get_directory(cli.directory).context("Failed to find .teamtype/ directory")?
+    };
 
     let config_file = directory.join(config::CONFIG_DIR).join(config::CONFIG_FILE);
 
@@ -69,55 +108,140 @@ async fn main() -> Result<()> {
 
             config::ensure_teamtype_is_ignored(&directory)?;
 
-            let mut init_doc = false;
+            // Check this early, so that we don't use up a join code.
+            pidfile::ensure_not_running(&directory)?;
+            // Ask for the passphrase now, before the daemon starts logging.
+            encryption::unlock(&directory)?;
+
+            let init_doc;
             let mut app_config;
+            // If the daemon should run in the background, these are the arguments to start it with.
+            let mut detached_args = None;
 
             match cli.command {
                 Commands::Share {
                     init,
                     no_join_code,
                     show_secret_address,
+                    read_only,
+                    approve_peers,
+                    listen_tcp,
                     sync_vcs: SyncVcsFlag { sync_vcs },
-                    ..
+                    detach: DetachFlag { detach },
                 } => {
+                    if detach {
+                        let flags = [
+                            (init, "--init"),
+                            (no_join_code, "--no-join-code"),
+                            (show_secret_address, "--show-secret-address"),
+                            (read_only, "--read-only"),
+                            (approve_peers, "--approve-peers"),
+                            (sync_vcs, "--sync-vcs"),
+                        ];
+                        let mut args = std::iter::once("share")
+                            .chain(flags.iter().filter(|(set, _)| *set).map(|(_, flag)| *flag))
+                            .map(str::to_string)
+                            .collect::<Vec<_>>();
+                        if let Some(address) = &listen_tcp {
+                            args.extend(["--listen-tcp".to_string(), address.clone()]);
+                        }
+                        detached_args = Some(args);
+                    }
                     init_doc = init;
                     let app_config_cli = AppConfig {
                         base_dir: directory,
-                        peer: None,
+                        peers: vec![],
                         emit_join_code: !no_join_code,
                         emit_secret_address: show_secret_address,
+                        share_access: if read_only {
+                            Access::ReadOnly
+                        } else {
+                            Access::Full
+                        },
+                        peer_approval: if approve_peers {
+                            PeerApproval::Required
+                        } else {
+                            PeerApproval::Automatic
+                        },
+                        listen_tcp,
+                        relays: Relays::Default,
+                        discovery: Discovery::N0,
+                        bind_port: None,
+                        reconnect: Reconnect::default(),
+                        read_only_peers: vec![],
                         sync_vcs,
                     };
                     app_config = app_config_cli.merge(AppConfig::from_config_file(&config_file));
 
                     // Because of the "share" subcommand, explicitly don't connect anywhere.
-                    app_config.peer = None;
+                    app_config.peers = vec![];
                 }
                 Commands::Join {
                     join_code,
+                    discard_history,
                     sync_vcs: SyncVcsFlag { sync_vcs },
-                    ..
+                    detach: DetachFlag { detach },
                 } => {
+                    if detach {
+                        // The join code is resolved right here, and stored in the config. So the
+                        // detached daemon can simply connect to the configured peers.
+                        let mut args = vec!["join".to_string()];
+                        if discard_history {
+                            args.push("--discard-history".to_string());
+                        }
+                        if sync_vcs {
+                            args.push("--sync-vcs".to_string());
+                        }
+                        detached_args = Some(args);
+                    }
+                    init_doc = discard_history;
                     let app_config_cli = AppConfig {
                         base_dir: directory,
-                        peer: join_code.map(config::Peer::JoinCode),
+                        peers: join_code.map(config::Peer::JoinCode).into_iter().collect(),
                         emit_join_code: false,
                         emit_secret_address: false,
+                        share_access: Access::Full,
+                        peer_approval: PeerApproval::Automatic,
+                        listen_tcp: None,
+                        relays: Relays::Default,
+                        discovery: Discovery::N0,
+                        bind_port: None,
+                        reconnect: Reconnect::default(),
+                        read_only_peers: vec![],
                         sync_vcs,
                     };
 
                     app_config = app_config_cli.merge(AppConfig::from_config_file(&config_file));
 
                     app_config = app_config
-                        .resolve_peer()
+                        .resolve_peers()
                         .await
-                        .context("Failed to resolve peer")?;
+                        .context("Failed to resolve peers")?;
                 }
-                Commands::Client => {
+                Commands::Client
+                | Commands::Stop
+                | Commands::Doctor
+                | Commands::Encrypt
+                | Commands::Trust { .. }
+                | Commands::Invite { .. }
+                | Commands::RotateSecret
+                | Commands::Disconnect { .. }
+                | Commands::Approve { .. }
+                | Commands::Reject { .. }
+                | Commands::Gc
+                | Commands::Status { .. }
+                | Commands::Log { .. }
+                | Commands::Show { .. }
+                | Commands::Blame { .. }
+                | Commands::Restore { .. } => {
                     panic!("This can't happen, as we earlier matched on Share|Join.")
                 }
             }
 
+            if let Some(args) = detached_args {
+                return detach::spawn(&app_config.base_dir, &args, app_config.emit_join_code).await;
+            }
+
             if app_config.sync_vcs
                 && config::has_local_user_config(&app_config.base_dir).is_ok_and(|v| v)
             {
@@ -126,26 +250,311 @@ async fn main() -> Result<()> {
 
             debug!("Starting Teamtype on {}.", app_config.base_dir.display());
 
+            let ask_about_peers = app_config.peer_approval == PeerApproval::Required
+                && std::io::stdin().is_terminal();
+
             // TODO: Derive socket_path inside the constructor.
-            let _daemon = Daemon::new(app_config, &socket_path, init_doc, persist)
+            let persistence = if persist {
+                Some(Box::new(FilePersistence::new(&app_config.base_dir)?) as Box<dyn Persistence>)
+            } else {
+                None
+            };
+            let daemon = Daemon::new(app_config, &socket_path, init_doc, persistence)
                 .await
                 .context("Failed to launch the daemon")?;
-            wait_for_shutdown().await;
+            if ask_about_peers {
+                spawn_approval_prompt(daemon.document_handle.clone());
+            }
+            wait_for_shutdown(daemon.document_handle.subscribe_shutdown_requests()).await;
+            daemon.shutdown().await;
+        }
+        Commands::Stop => {
+            if !daemon_client::is_running(&socket_path).await {
+                if let Some(pid) = pidfile::running_pid(&directory) {
+                    bail!(
+                        "The daemon (PID {pid}) doesn't respond on '{}'.",
+                        socket_path.display()
+                    );
+                }
+                info!("No daemon is running in '{}'.", directory.display());
+                return Ok(());
+            }
+            daemon_client::shutdown(&socket_path)
+                .await
+                .context("Failed to stop the daemon")?;
+            info!("Stopped the daemon.");
+        }
+        Commands::Gc => {
+            let compaction = if daemon_client::is_running(&socket_path).await {
+                daemon_client::compact(&socket_path)
+                    .await
+                    .context("The daemon failed to compact the history")?
+            } else {
+                history::compact_offline(&directory)?
+            };
+            info!("Compacted the history {compaction}.");
+        }
+        Commands::Doctor => {
+            // Fixing one problem can uncover others, so check again after fixing something.
+            let mut applied_fixes = HashSet::new();
+            let problem_count = loop {
+                let mut applied_fix = false;
+                let checks = doctor::run_checks(&directory);
+                for check in &checks {
+                    println!("{check}");
+                    let Some(Problem { fix: Some(fix), .. }) = &check.problem else {
+                        continue;
+                    };
+                    if applied_fixes.contains(fix) {
+                        continue;
+                    }
+                    let question = format!("          Fix it? {fix}.");
+                    let confirmed = if fix.is_destructive() {
+                        if std::io::stdin().is_terminal() {
+                            cli_ask::ask_interactively(&question)?
+                        } else {
+                            println!("          To fix it, run `teamtype doctor` in a terminal. This fix can't be confirmed up front.");
+                            false
+                        }
+                    } else {
+                        cli_ask::can_ask() && ask(&question)?
+                    };
+                    if confirmed {
+                        fix.apply(&directory)
+                            .with_context(|| format!("Failed to fix '{}'", check.name))?;
+                        applied_fixes.insert(*fix);
+                        applied_fix = true;
+                    }
+                }
+                if !applied_fix {
+                    break checks
+                        .iter()
+                        .filter(|check| check.problem.is_some())
+                        .count();
+                }
+                println!("\nChecking again:");
+            };
+            if problem_count > 0 {
+                bail!("Found {problem_count} problem(s)");
+            }
+        }
+        Commands::Trust { command } => match command {
+            TrustCommand::List => {
+                let peers = trust::peers(&directory)?;
+                if peers.is_empty() {
+                    println!("No peers are trusted yet.");
+                }
+                for peer in peers {
+                    println!("{peer}");
+                }
+            }
+            TrustCommand::Add { node_id, read_only } => {
+                if read_only {
+                    trust::trust(&directory, &node_id, Access::ReadOnly)?;
+                    info!("Trusting peer {node_id} with read-only access.");
+                } else {
+                    trust::trust(&directory, &node_id, Access::Full)?;
+                    info!("Trusting peer {node_id}.");
+                }
+            }
+            TrustCommand::Revoke { node_id } => {
+                let node_id = trust::revoke(&directory, &node_id)?;
+                info!("Revoked the trust in peer {node_id}. It can't connect anymore.");
+                if daemon_client::is_running(&socket_path).await
+                    && daemon_client::disconnect(&socket_path, node_id)
+                        .await
+                        .is_ok()
+                {
+                    info!("Disconnected it from the running daemon.");
+                }
+            }
+        },
+        Commands::Invite { command } => match command {
+            InviteCommand::Create {
+                expires,
+                uses,
+                read_only,
+                join_code,
+            } => {
+                let expires = expires
+                    .as_deref()
+                    .map(timestamp::parse_future)
+                    .transpose()?;
+                let access = if read_only {
+                    Access::ReadOnly
+                } else {
+                    Access::Full
+                };
+                let (invite, secret_address) =
+                    peer::create_invite(&directory, access, expires, uses)?;
+                info!("Created invite {invite}. Peers can join with this secret address, by putting it in their .teamtype/config:\n\n\tpeer={secret_address}\n");
+                if join_code {
+                    wormhole::put_secret_address_into_wormhole(secret_address).await?;
+                    info!("Handed out the invite.");
+                }
+            }
+            InviteCommand::List => {
+                let invites = invite::invites(&directory)?;
+                if invites.is_empty() {
+                    println!("There are no invites.");
+                }
+                for invite in invites {
+                    println!("{invite}");
+                }
+            }
+            InviteCommand::Revoke { id } => {
+                let id = invite::revoke(&directory, &id)?;
+                info!("Revoked invite {id}. Peers which joined with it are still trusted.");
+            }
+        },
+        Commands::RotateSecret => {
+            let secret_address = if daemon_client::is_running(&socket_path).await {
+                daemon_client::rotate_secret(&socket_path)
+                    .await
+                    .context("The daemon failed to rotate the secret")?
+            } else {
+                peer::rotate_passphrase(&directory)?.0
+            };
+            info!("Rotated the passphrase. The new secret address is:\n\n\t{secret_address}\n");
+        }
+        Commands::Disconnect { node_id } => {
+            if !daemon_client::is_running(&socket_path).await {
+                bail!("No daemon is running in '{}'", directory.display());
+            }
+            let node_id = daemon_client::disconnect(&socket_path, node_id).await?;
+            info!("Disconnected peer {node_id}.");
+        }
+        Commands::Approve { node_id, trust } => {
+            if !daemon_client::is_running(&socket_path).await {
+                bail!("No daemon is running in '{}'", directory.display());
+            }
+            let decision = if trust {
+                PeerDecision::AlwaysTrust
+            } else {
+                PeerDecision::Accept
+            };
+            let node_id = daemon_client::decide_on_peer(&socket_path, node_id, decision).await?;
+            if trust {
+                info!("Approved peer {node_id}, and trusting it from now on.");
+            } else {
+                info!("Approved peer {node_id}.");
+            }
+        }
+        Commands::Reject { node_id } => {
+            if !daemon_client::is_running(&socket_path).await {
+                bail!("No daemon is running in '{}'", directory.display());
+            }
+            let node_id =
+                daemon_client::decide_on_peer(&socket_path, node_id, PeerDecision::Reject).await?;
+            info!("Rejected peer {node_id}.");
+        }
+        Commands::Encrypt => {
+            // The daemon would keep writing plaintext.
+            let _pidfile = pidfile::acquire(&directory)?;
+            if encryption::is_enabled(&directory) {
+                bail!("'{}' is encrypted already", directory.display());
+            }
+            let passphrase = encryption::read_new_passphrase()?;
+            encryption::encrypt_directory(&directory, &passphrase)?;
+            info!(
+                "Encrypted the history and the key file. Teamtype will ask for the passphrase when it starts, unless you set {}.",
+                encryption::PASSPHRASE_ENV_VAR
+            );
         }
         Commands::Client => {
             jsonrpc_forwarder::connection(&socket_path)
                 .await
                 .context("JSON-RPC forwarder failed")?;
         }
+        Commands::Status { json } => {
+            let status = daemon_client::status(&socket_path)
+                .await
+                .context("Failed to get the status of the daemon")?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&status)?);
+            } else {
+                print!("{status}");
+            }
+        }
+        Commands::Log { path, since, until } => {
+            let filter = LogFilter {
+                file_path: path
+                    .map(|path| relative_path_argument(&directory, &path))
+                    .transpose()?,
+                since: since.as_deref().map(timestamp::parse).transpose()?,
+                until: until.as_deref().map(timestamp::parse).transpose()?,
+            };
+            let mut document = history::load_document(&directory)?;
+            for (i, change) in history::log(&mut document, &filter)?.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                print!("{}", LogEntry(change));
+            }
+        }
+        Commands::Show { path, at } => {
+            let file_path = relative_path_argument(&directory, &path)?;
+            let mut document = history::load_document(&directory)?;
+            let heads = history::resolve_at(&mut document, &at)?;
+            match document.file_at(&file_path, &heads)? {
+                FileContent::Text(text) => print!("{text}"),
+                FileContent::Binary(bytes) => std::io::stdout().write_all(&bytes)?,
+            }
+        }
+        Commands::Blame { path } => {
+            let file_path = relative_path_argument(&directory, &path)?;
+            let mut document = history::load_document(&directory)?;
+            let blame = document.blame(&file_path)?;
+            let content = document.current_file_content(&file_path)?;
+            print!(
+                "{}",
+                BlameView {
+                    blame: &blame,
+                    content: &content
+                }
+            );
+        }
+        Commands::Restore { path, at } => {
+            let file_path = relative_path_argument(&directory, &path)?;
+            if daemon_client::is_running(&socket_path).await {
+                let uri = AbsolutePath::from_parts(&directory, &file_path)?.to_file_uri();
+                daemon_client::restore(&socket_path, uri.to_string(), at)
+                    .await
+                    .context("The daemon failed to restore the file")?;
+            } else {
+                history::restore_offline(&directory, &file_path, &at)?;
+            }
+            info!("Restored {file_path}.");
+        }
     }
     Ok(())
 }
 
-fn get_directory(directory: Option<PathBuf>) -> Result<PathBuf> {
-    let directory = directory
+/// Interprets a path given on the command line (relative to the current directory) as a file in
+/// the shared directory. The file doesn't need to exist anymore.
+fn relative_path_argument(directory: &Path, path: &Path) -> Result<RelativePath> {
+    let absolute_path = std::env::current_dir()?.join(path);
+    let absolute_path = absolute_path.canonicalize().unwrap_or_else(|_| {
+        match (absolute_path.parent(), absolute_path.file_name()) {
+            (Some(parent), Some(file_name)) => parent
+                .canonicalize()
+                .map_or_else(|_| absolute_path.clone(), |parent| parent.join(file_name)),
+            _ => absolute_path.clone(),
+        }
+    });
+    RelativePath::try_from_path(directory, &absolute_path)
+        .with_context(|| format!("'{}' is not in the shared directory", path.display()))
+}
+
+fn canonical_directory(directory: Option<PathBuf>) -> PathBuf {
+    directory
         .unwrap_or_else(|| std::env::current_dir().expect("Could not access current directory"))
         .canonicalize()
-        .expect("Could not access given directory");
+        .expect("Could not access given directory")
+}
+
+fn get_directory(directory: Option<PathBuf>) -> Result<PathBuf> {
+    let directory = canonical_directory(directory);
     if has_ethersync_directory(&directory) {
         let old_directory = directory.join(config::LEGACY_CONFIG_DIR);
 
@@ -186,7 +595,50 @@ fn get_directory(directory: Option<PathBuf>) -> Result<PathBuf> {
     Ok(directory)
 }
 
-async fn wait_for_shutdown() {
+/// Asks in the terminal about each peer which waits for approval. Editors (or `teamtype approve`)
+/// can decide in the meantime.
+fn spawn_approval_prompt(document_handle: DocumentActorHandle) {
+    let mut approval_requests = document_handle.subscribe_approval_requests();
+    let runtime = tokio::runtime::Handle::current();
+    // Reading from the terminal blocks, so we do it in a thread of our own.
+    std::thread::spawn(move || loop {
+        let (node_id, access) = match approval_requests.blocking_recv() {
+            Ok(request) => request,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let decision = match ask_about_peer(&node_id, access) {
+            Ok(decision) => decision,
+            Err(err) => {
+                warn!("Failed to ask about peer {node_id}: {err:#}");
+                break;
+            }
+        };
+        if let Err(err) = runtime.block_on(document_handle.decide_on_peer(node_id, decision)) {
+            // Maybe someone else decided while we were asking.
+            warn!("{err:#}");
+        }
+    });
+}
+
+fn ask_about_peer(node_id: &str, access: Access) -> Result<PeerDecision> {
+    let access_description = match access {
+        Access::Full => "",
+        Access::ReadOnly => " (read-only)",
+    };
+    print!("Let peer {node_id}{access_description} connect? [a]ccept once, always [t]rust it, or [r]eject (a/t/R): ");
+    std::io::stdout().flush()?;
+    let Some(line) = std::io::stdin().lines().next() else {
+        bail!("The terminal was closed");
+    };
+    Ok(match line?.trim().to_lowercase().as_str() {
+        "a" | "accept" => PeerDecision::Accept,
+        "t" | "trust" => PeerDecision::AlwaysTrust,
+        _ => PeerDecision::Reject,
+    })
+}
+
+async fn wait_for_shutdown(mut shutdown_requests: broadcast::Receiver<()>) {
     let mut signal_terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
         .expect("Should have been able to create terminate signal stream");
     tokio::select! {
@@ -196,5 +648,8 @@ async fn wait_for_shutdown() {
         _ = signal_terminate.recv() => {
             debug!("Got SIGTERM, shutting down");
         }
+        _ = shutdown_requests.recv() => {
+            debug!("Got a shutdown request, shutting down");
+        }
     }
 }
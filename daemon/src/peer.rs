@@ -5,27 +5,50 @@
 
 //! This module provides a [`ConnectionManager`], which can be used to connect to other daemons.
 
-use self::sync::{Connection, PeerMessage, SyncActor};
-use crate::daemon::DocumentActorHandle;
+use self::backoff::Backoff;
+use self::sync::{Connection, PeerMessage, SyncActor, UnrelatedHistory};
+use self::tcp::{IncomingTcpConnection, TcpAddress};
+use crate::config::{self, AppConfig, Discovery, PeerApproval, Reconnect, Relays};
+use crate::daemon::{DocMessage, DocumentActorHandle, PeerCommand, PeerCommandReceiver};
+use crate::encryption::{self, Purpose};
+use crate::invite::{self, Invite, Redemption};
+use crate::sandbox;
+use crate::timestamp;
+use crate::trust::{self, TrustState};
+use crate::types::{Access, ConnectionState, PeerDecision, PeerDirection};
 use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
+use hmac::{Hmac, Mac};
 use iroh::endpoint::{RecvStream, SendStream};
-use iroh::{NodeAddr, SecretKey};
+use iroh::{NodeAddr, NodeId, RelayMap, RelayMode, RelayUrl, SecretKey};
 use postcard::{from_bytes, to_allocvec};
-use std::fs::{self, File, OpenOptions};
-use std::io::{Read, Write};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
-use tokio::sync::{mpsc, oneshot};
-use tokio::time::sleep;
-use tracing::{debug, info, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::time::{sleep, timeout};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
+mod backoff;
 mod sync;
+mod tcp;
 
 const ALPN: &[u8] = b"/teamtype/0";
+/// How long we wait for our own addresses, when we need to put them into the secret address.
+const OWN_ADDRESS_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// The secret address of another daemon: `<node ID>#<passphrase>`. Optionally, it's followed by
+/// `#` and a comma-separated list of direct addresses (`<IP>:<port>`) and a relay URL, under
+/// which the daemon can be reached without a discovery service.
 struct SecretAddress {
     node_addr: NodeAddr,
     passphrase: SecretKey,
@@ -35,12 +58,15 @@ impl FromStr for SecretAddress {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self> {
         let parts: Vec<&str> = s.split('#').collect();
-        if parts.len() != 2 {
-            bail!("Peer string must have format <node_id>#<passphrase>");
+        if parts.len() != 2 && parts.len() != 3 {
+            bail!("Peer string must have format <node_id>#<passphrase>[#<addresses>]");
         }
 
-        let node_addr = iroh::PublicKey::from_str(parts[0])?.into();
+        let mut node_addr = NodeAddr::new(iroh::PublicKey::from_str(parts[0])?);
         let passphrase = SecretKey::from_str(parts[1])?;
+        if let Some(addresses) = parts.get(2) {
+            parse_addresses(&mut node_addr, addresses)?;
+        }
 
         Ok(Self {
             node_addr,
@@ -49,43 +75,397 @@ impl FromStr for SecretAddress {
     }
 }
 
+/// Adds the direct addresses and the relay URL in the comma-separated list `addresses` to
+/// `node_addr`.
+fn parse_addresses(node_addr: &mut NodeAddr, addresses: &str) -> Result<()> {
+    for address in addresses.split(',').filter(|address| !address.is_empty()) {
+        if address.contains("://") {
+            node_addr.relay_url = Some(
+                RelayUrl::from_str(address)
+                    .with_context(|| format!("Invalid relay URL '{address}'"))?,
+            );
+        } else {
+            node_addr.direct_addresses.insert(
+                SocketAddr::from_str(address)
+                    .with_context(|| format!("Invalid direct address '{address}'"))?,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Formats the direct addresses and the relay URL of `node_addr` as a comma-separated list.
+fn format_addresses(node_addr: &NodeAddr) -> String {
+    let addresses: Vec<String> = node_addr
+        .direct_addresses
+        .iter()
+        .map(ToString::to_string)
+        .chain(node_addr.relay_url.iter().map(ToString::to_string))
+        .collect();
+    addresses.join(",")
+}
+
+/// Formats the node ID of `node_addr` and `passphrase` as a secret address, including the
+/// direct addresses and the relay URL of `node_addr`, if it has any.
+fn format_secret_address(node_addr: &NodeAddr, passphrase: &SecretKey) -> String {
+    let addresses = format_addresses(node_addr);
+    if addresses.is_empty() {
+        format!("{}#{passphrase}", node_addr.node_id)
+    } else {
+        format!("{}#{passphrase}#{addresses}", node_addr.node_id)
+    }
+}
+
+/// Formats the address under which other session members can reach us: the node ID of
+/// `node_addr`, followed by `#` and its addresses, if it has any. It contains no passphrase. They
+/// can connect anyway, because a peer with full access vouched for them.
+fn format_member_address(node_addr: &NodeAddr) -> String {
+    let addresses = format_addresses(node_addr);
+    if addresses.is_empty() {
+        node_addr.node_id.to_string()
+    } else {
+        format!("{}#{addresses}", node_addr.node_id)
+    }
+}
+
+/// The node ID at the start of an address formatted by `format_member_address`.
+pub fn member_node_id(member_address: &str) -> &str {
+    member_address
+        .split_once('#')
+        .map_or(member_address, |(node_id, _)| node_id)
+}
+
+/// Parses an address formatted by `format_member_address`.
+fn parse_member_address(s: &str) -> Result<NodeAddr> {
+    let (node_id, addresses) = s.split_once('#').unwrap_or((s, ""));
+    let mut node_addr = NodeAddr::new(iroh::PublicKey::from_str(node_id)?);
+    parse_addresses(&mut node_addr, addresses)?;
+    Ok(node_addr)
+}
+
+fn address_file(base_dir: &Path) -> PathBuf {
+    base_dir.join(config::CONFIG_DIR).join(config::ADDRESS_FILE)
+}
+
+/// Remembers the addresses under which we can be reached, so that secret addresses created
+/// while the daemon isn't running contain them, too.
+fn remember_own_addr(base_dir: &Path, own_addr: &NodeAddr) -> Result<()> {
+    sandbox::write_file_atomically(
+        base_dir,
+        &address_file(base_dir),
+        format_addresses(own_addr).as_bytes(),
+    )
+}
+
+/// Returns the addresses under which the daemon with `node_id` could be reached when it last
+/// started. Fails if we need them, because discovery is off, but it never started yet.
+fn last_own_addr(base_dir: &Path, node_id: NodeId) -> Result<NodeAddr> {
+    let mut node_addr = NodeAddr::new(node_id);
+    let file = address_file(base_dir);
+    if sandbox::exists(base_dir, &file)? {
+        let addresses = String::from_utf8(sandbox::read_file(base_dir, &file)?)?;
+        parse_addresses(&mut node_addr, addresses.trim())
+            .with_context(|| format!("Invalid addresses in '{}'", file.display()))?;
+    } else {
+        let config_file = base_dir.join(config::CONFIG_DIR).join(config::CONFIG_FILE);
+        let discovery = AppConfig::from_config_file(&config_file)
+            .map_or_else(Discovery::default, |app_config| app_config.discovery);
+        if discovery == Discovery::Disabled {
+            bail!("Discovery is turned off, so secret addresses have to contain the addresses under which the daemon can be reached. Start it once with `teamtype share` to find them out.");
+        }
+    }
+    Ok(node_addr)
+}
+
+/// Where to reach another daemon: via iroh, or via plain TCP if the secret address starts with
+/// `tcp://<host>:<port>#`. Other session members are reached via iroh, without a passphrase.
+enum PeerAddress {
+    Iroh(SecretAddress),
+    Tcp(TcpAddress),
+    Member(NodeAddr),
+}
+
+impl PeerAddress {
+    const fn node_id(&self) -> NodeId {
+        match self {
+            Self::Iroh(secret_address) => secret_address.node_addr.node_id,
+            Self::Tcp(tcp_address) => tcp_address.secret_address.node_addr.node_id,
+            Self::Member(node_addr) => node_addr.node_id,
+        }
+    }
+}
+
+impl FromStr for PeerAddress {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with(tcp::SCHEME) {
+            Ok(Self::Tcp(TcpAddress::from_str(s)?))
+        } else {
+            Ok(Self::Iroh(SecretAddress::from_str(s)?))
+        }
+    }
+}
+
+/// The secret addresses under which others can connect to us. Both change when the passphrase is
+/// rotated.
+#[derive(Debug, Clone)]
+pub struct SecretAddresses {
+    full: String,
+    read_only: String,
+}
+
+impl SecretAddresses {
+    /// The secret addresses contain the direct addresses and the relay URL of `node_addr`, if it
+    /// has any.
+    fn new(node_addr: &NodeAddr, passphrase: &SecretKey) -> Self {
+        Self {
+            full: format_secret_address(node_addr, passphrase),
+            read_only: format_secret_address(node_addr, &read_only_passphrase(passphrase)),
+        }
+    }
+
+    /// The secret address which grants `access`.
+    #[must_use]
+    pub fn get(&self, access: Access) -> &str {
+        match access {
+            Access::Full => &self.full,
+            Access::ReadOnly => &self.read_only,
+        }
+    }
+}
+
+/// Derives another passphrase from ours, for the purpose given by `label`. Derived passphrases
+/// don't need to be stored, change when ours is rotated, and don't reveal ours.
+pub(crate) fn derive_passphrase(passphrase: &SecretKey, label: &str) -> SecretKey {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&passphrase.to_bytes())
+        .expect("HMAC takes keys of any size");
+    mac.update(label.as_bytes());
+    SecretKey::from_bytes(&mac.finalize().into_bytes().into())
+}
+
+/// Peers which know this passphrase instead of the real one only get read-only access.
+fn read_only_passphrase(passphrase: &SecretKey) -> SecretKey {
+    derive_passphrase(passphrase, "teamtype read-only passphrase")
+}
+
+/// Returns which access `received` grants, if it's one of our passphrases.
+fn access_for_passphrase(received: &[u8; 32], passphrase: &SecretKey) -> Option<Access> {
+    // Guard against timing attacks.
+    if constant_time_eq::constant_time_eq(received, &passphrase.to_bytes()) {
+        Some(Access::Full)
+    } else if constant_time_eq::constant_time_eq(
+        received,
+        &read_only_passphrase(passphrase).to_bytes(),
+    ) {
+        Some(Access::ReadOnly)
+    } else {
+        None
+    }
+}
+
+/// What a peer may do, and why.
+struct Authentication {
+    access: Access,
+    /// Only matters for peers we don't trust yet.
+    origin: trust::Origin,
+    /// The ID of the invite the peer joined with, if any. It's only used up once the peer is let
+    /// in.
+    invite: Option<String>,
+    /// Whether the peer is a session member which another peer vouched for. Then, it's never
+    /// trusted beyond that.
+    vouched: bool,
+}
+
+impl Authentication {
+    /// For peers we trust already, and peers we connected to.
+    const fn trusted(access: Access) -> Self {
+        Self {
+            access,
+            origin: trust::Origin::Explicit,
+            invite: None,
+            vouched: false,
+        }
+    }
+
+    /// For session members which another peer vouched for.
+    const fn vouched() -> Self {
+        Self {
+            access: Access::Full,
+            origin: trust::Origin::Explicit,
+            invite: None,
+            vouched: true,
+        }
+    }
+}
+
+/// Decides what a peer which connected to us may do, based on the passphrase it sent. It can also
+/// be the passphrase of an invite.
+fn authenticate(
+    received: &[u8; 32],
+    passphrase: &SecretKey,
+    base_dir: &Path,
+    node_id: &NodeId,
+) -> Result<Authentication> {
+    if let Some(access) = access_for_passphrase(received, passphrase) {
+        return Ok(Authentication {
+            access,
+            origin: trust::Origin::Passphrase,
+            invite: None,
+            vouched: false,
+        });
+    }
+    match invite::check(base_dir, received, passphrase)? {
+        Some(Redemption::Accepted(invite)) => Ok(Authentication {
+            access: invite.access,
+            origin: trust::Origin::Invite,
+            invite: Some(invite.id),
+            vouched: false,
+        }),
+        Some(redemption) => {
+            reject_invite(&node_id.to_string(), &redemption);
+            bail!("Peer used an invalid invite.");
+        }
+        None => bail!("Peer provided incorrect passphrase."),
+    }
+}
+
+/// Logs why the invite a peer tried to join with didn't work.
+fn reject_invite(node_id: &str, redemption: &Redemption) {
+    match redemption {
+        Redemption::Accepted(_) => {}
+        Redemption::Expired(invite) => warn!(
+            "Rejected peer {node_id}, because its invite {} expired at {}.",
+            invite.id,
+            invite.expires.map_or_else(String::new, timestamp::format)
+        ),
+        Redemption::UsedUp(invite) => warn!(
+            "Rejected peer {node_id}, because its invite {} is used up.",
+            invite.id
+        ),
+    }
+}
+
 enum PeerAuth {
-    MyPassphrase(SecretKey),
+    /// The peer connected to us. It needs to know one of our passphrases (or the one of an invite
+    /// in `base_dir`), unless we trust it already, and then gets the access we trust it with. If
+    /// we don't trust it yet, it might also need to be approved. Session members which a peer we
+    /// trust vouches for are `vouched`, and get full access.
+    MyPassphrase {
+        passphrase: SecretKey,
+        trusted: Option<Access>,
+        vouched: bool,
+        base_dir: PathBuf,
+        approval: PeerApproval,
+    },
     YourPassphrase(SecretKey),
+    /// We connect to another session member, which lets us in because a peer with full access
+    /// vouched for us. So we don't need its passphrase.
+    Vouched,
+}
+
+/// The session members which peers vouched for. For each, the node IDs of the peers which vouched
+/// for it, and whether they're still connected. Only kept in memory: a member can only make new
+/// connections while a peer which vouched for it is connected and trusted, and its connections are
+/// closed once none of them is trusted anymore.
+type Vouches = HashMap<NodeId, HashMap<String, bool>>;
+
+/// Whether a connected peer we trust vouches for the session member with `node_id`.
+fn is_vouched(vouches: &Vouches, node_id: &NodeId, base_dir: &Path) -> bool {
+    vouches.get(node_id).is_some_and(|vouchers| {
+        vouchers.iter().any(|(voucher, connected)| {
+            *connected && EndpointActor::trust_state(base_dir, voucher) == Some(TrustState::Trusted)
+        })
+    })
 }
 
 pub struct ConnectionManager {
     message_tx: mpsc::Sender<EndpointMessage>,
-    secret_address: String,
+    /// Changes when the passphrase is rotated.
+    secret_addresses: watch::Receiver<SecretAddresses>,
+    /// Where we listen for TCP connections, if we do.
+    tcp_listen_address: Option<SocketAddr>,
 }
 
 impl ConnectionManager {
-    pub async fn new(document_handle: DocumentActorHandle, base_dir: &Path) -> Result<Self> {
+    pub async fn new(document_handle: DocumentActorHandle, app_config: &AppConfig) -> Result<Self> {
         let (message_tx, message_rx) = mpsc::channel(1);
 
-        let (endpoint, my_passphrase) = Self::build_endpoint(base_dir).await?;
+        let (endpoint, my_passphrase) = Self::build_endpoint(app_config).await?;
+        let own_addr = match app_config.discovery {
+            Discovery::N0 => NodeAddr::new(endpoint.node_id()),
+            // Without discovery, peers can only find us via the addresses in our secret address.
+            Discovery::Disabled => {
+                Self::own_addr(&endpoint, app_config.relays != Relays::Disabled).await
+            }
+        };
+
+        if let Err(err) = remember_own_addr(&app_config.base_dir, &own_addr) {
+            warn!("Failed to remember our own addresses: {err:#}");
+        }
+
+        let mut tcp_listener = None;
+        if let Some(address) = &app_config.listen_tcp {
+            let listener = TcpListener::bind(address)
+                .await
+                .with_context(|| format!("Failed to listen for TCP connections on {address}"))?;
+            tcp_listener = Some(listener);
+        }
+        let tcp_listen_address = tcp_listener
+            .as_ref()
+            .map(TcpListener::local_addr)
+            .transpose()?;
 
-        let secret_address = format!("{}#{}", endpoint.node_id(), my_passphrase);
+        let (secret_addresses_tx, secret_addresses) =
+            watch::channel(SecretAddresses::new(&own_addr, &my_passphrase));
 
-        let mut actor = EndpointActor::new(
+        let mut actor = EndpointActor {
             endpoint,
+            tcp_listener,
             message_rx,
-            message_tx.clone(),
+            message_tx: message_tx.clone(),
+            peer_command_rx: document_handle.subscribe_peer_commands(),
             document_handle,
             my_passphrase,
-        );
+            secret_addresses_tx,
+            own_addr,
+            base_dir: app_config.base_dir.clone(),
+            incoming_connections: vec![],
+            outgoing_peers: HashSet::new(),
+            vouches_tx: watch::Sender::new(Vouches::new()),
+            peer_approval: app_config.peer_approval,
+            reconnect: app_config.reconnect,
+        };
+
+        // Peers with full access advertise it to the other session members.
+        actor
+            .document_handle
+            .send_message(DocMessage::OwnAddress(format_member_address(
+                &actor.own_addr,
+            )))
+            .await;
 
         tokio::spawn(async move { actor.run().await });
 
         Ok(Self {
             message_tx,
-            secret_address,
+            secret_addresses,
+            tcp_listen_address,
         })
     }
 
+    /// Returns the current secret address which grants `access`.
+    #[must_use]
+    pub fn secret_address(&self, access: Access) -> String {
+        self.secret_addresses.borrow().get(access).to_string()
+    }
+
+    /// Returns the current secret address which grants `access` via TCP, if we listen for TCP
+    /// connections. If we listen on all interfaces, peers have to replace the host with one of
+    /// ours.
     #[must_use]
-    pub fn secret_address(&self) -> &str {
-        &self.secret_address
+    pub fn tcp_secret_address(&self, access: Access) -> Option<String> {
+        self.tcp_listen_address
+            .map(|address| format!("{}{address}#{}", tcp::SCHEME, self.secret_address(access)))
     }
 
     pub async fn connect(&self, secret_address: String) -> Result<()> {
@@ -93,9 +473,8 @@ impl ConnectionManager {
 
         self.message_tx
             .send(EndpointMessage::Connect {
-                secret_address: SecretAddress::from_str(&secret_address)?,
+                address: PeerAddress::from_str(&secret_address)?,
                 response_tx: Some(response_tx),
-                previous_attempts: 0,
             })
             .await
             .expect("EndpointActor task has been killed");
@@ -105,21 +484,78 @@ impl ConnectionManager {
         Ok(())
     }
 
-    async fn build_endpoint(base_dir: &Path) -> Result<(iroh::Endpoint, SecretKey)> {
-        let (secret_key, my_passphrase) = Self::get_keypair(base_dir);
+    /// Closes the connections to all peers, and stops accepting new ones.
+    pub async fn close(&self) {
+        let (response_tx, response_rx) = oneshot::channel();
+        if self
+            .message_tx
+            .send(EndpointMessage::Close { response_tx })
+            .await
+            .is_ok()
+        {
+            let _ = response_rx.await;
+        }
+    }
+
+    async fn build_endpoint(app_config: &AppConfig) -> Result<(iroh::Endpoint, SecretKey)> {
+        let (secret_key, my_passphrase) = Self::get_keypair(&app_config.base_dir)?;
+
+        let relay_mode = match &app_config.relays {
+            Relays::Default => RelayMode::Default,
+            Relays::Disabled => RelayMode::Disabled,
+            Relays::Custom(urls) => RelayMode::Custom(
+                urls.iter()
+                    .map(|url| {
+                        RelayUrl::from_str(url)
+                            .with_context(|| format!("Invalid relay URL '{url}'"))
+                    })
+                    .collect::<Result<RelayMap>>()?,
+            ),
+        };
 
-        let endpoint = iroh::Endpoint::builder()
+        let mut builder = iroh::Endpoint::builder()
             .secret_key(secret_key)
             .alpns(vec![ALPN.to_vec()])
-            .discovery_n0()
-            .bind()
-            .await?;
+            .relay_mode(relay_mode);
+        if app_config.discovery == Discovery::N0 {
+            builder = builder.discovery_n0();
+        }
+        if let Some(port) = app_config.bind_port {
+            builder = builder.bind_addr_v4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port));
+        }
+        let endpoint = builder.bind().await?;
 
         Ok((endpoint, my_passphrase))
     }
 
-    fn get_keypair(base_dir: &Path) -> (SecretKey, SecretKey) {
-        let keyfile = base_dir.join(".teamtype").join("key");
+    /// Returns our direct addresses, and our home relay if `use_relay` is set, as far as the
+    /// endpoint finds them out in a few seconds.
+    async fn own_addr(endpoint: &iroh::Endpoint, use_relay: bool) -> NodeAddr {
+        let mut node_addr = NodeAddr::new(endpoint.node_id());
+        let mut direct_addresses = endpoint.direct_addresses();
+        if let Ok(Ok(addresses)) =
+            timeout(OWN_ADDRESS_TIMEOUT, direct_addresses.initialized()).await
+        {
+            node_addr.direct_addresses =
+                addresses.into_iter().map(|address| address.addr).collect();
+        } else {
+            warn!("Failed to find out our own addresses for the secret address.");
+        }
+        if use_relay {
+            let mut home_relay = endpoint.home_relay();
+            if let Ok(Ok(relay_url)) = timeout(OWN_ADDRESS_TIMEOUT, home_relay.initialized()).await
+            {
+                node_addr.relay_url = Some(relay_url);
+            } else {
+                warn!("Failed to connect to a relay for the secret address.");
+            }
+        }
+        node_addr
+    }
+
+    fn get_keypair(base_dir: &Path) -> Result<(SecretKey, SecretKey)> {
+        let keyfile = base_dir.join(config::CONFIG_DIR).join(config::KEY_FILE);
+        let cipher = encryption::cipher(base_dir)?;
         if keyfile.exists() {
             let metadata =
                 fs::metadata(&keyfile).expect("Expected to have access to metadata of the keyfile");
@@ -128,23 +564,21 @@ impl ConnectionManager {
             let allowed_permissions = 0o100_600;
             assert!(current_permissions == allowed_permissions, "For security reasons, please make sure to set the key file to user-readable only (set the permissions to 600).");
 
-            assert!(metadata.len() == 64, "Your keyfile is not 64 bytes long. This is a sign that it was created by a Teamtype version older than 0.7.0, which is not compatible. Please remove .teamtype/key, and try again.");
-
             debug!("Re-using existing keypair.");
-            let mut file = File::open(keyfile).expect("Failed to open key file");
-
-            let mut secret_key = [0; 32];
-            file.read_exact(&mut secret_key)
-                .expect("Failed to read from key file");
+            let mut bytes = fs::read(&keyfile).expect("Failed to read from key file");
+            if let Some(cipher) = &cipher {
+                bytes = cipher
+                    .open(Purpose::KeyFile, &bytes)
+                    .context("Failed to decrypt the key file")?;
+            }
 
-            let mut passphrase = [0; 32];
-            file.read_exact(&mut passphrase)
-                .expect("Failed to read from key file");
+            assert!(bytes.len() == 64, "Your keyfile is not 64 bytes long. This is a sign that it was created by a Teamtype version older than 0.7.0, which is not compatible. Please remove .teamtype/key, and try again.");
 
-            (
-                SecretKey::from_bytes(&secret_key),
-                SecretKey::from_bytes(&passphrase),
-            )
+            let (secret_key, passphrase) = bytes.split_at(32);
+            Ok((
+                SecretKey::from_bytes(secret_key.try_into().expect("Slice should have 32 bytes")),
+                SecretKey::from_bytes(passphrase.try_into().expect("Slice should have 32 bytes")),
+            ))
         } else {
             debug!("Generating new keypair.");
             let secret_key = SecretKey::generate(rand::rngs::OsRng);
@@ -157,139 +591,371 @@ impl ConnectionManager {
                 .open(keyfile)
                 .expect("Should have been able to create key file that did not exist before");
 
-            file.write_all(&secret_key.to_bytes())
-                .expect("Failed to write to key file");
-            file.write_all(&passphrase.to_bytes())
+            file.write_all(&key_file_content(base_dir, &secret_key, &passphrase)?)
                 .expect("Failed to write to key file");
 
-            (secret_key, passphrase)
+            Ok((secret_key, passphrase))
         }
     }
 }
 
+fn key_file_content(
+    base_dir: &Path,
+    secret_key: &SecretKey,
+    passphrase: &SecretKey,
+) -> Result<Vec<u8>> {
+    let bytes = [secret_key.to_bytes(), passphrase.to_bytes()].concat();
+    Ok(match encryption::cipher(base_dir)? {
+        Some(cipher) => cipher.seal(Purpose::KeyFile, &bytes),
+        None => bytes,
+    })
+}
+
+/// Replaces the passphrase in `.teamtype/key` with a new one, but keeps the secret key, and with
+/// it the node ID. Returns the new secret address, and the new passphrase.
+pub fn rotate_passphrase(base_dir: &Path) -> Result<(String, SecretKey)> {
+    let keyfile = base_dir.join(config::CONFIG_DIR).join(config::KEY_FILE);
+    if !keyfile.exists() {
+        bail!("There is no key file yet. It is created when Teamtype runs for the first time.");
+    }
+    let (secret_key, _) = ConnectionManager::get_keypair(base_dir)?;
+    let passphrase = SecretKey::generate(rand::rngs::OsRng);
+    sandbox::write_private_file_atomically(
+        base_dir,
+        &keyfile,
+        &key_file_content(base_dir, &secret_key, &passphrase)?,
+    )?;
+    // Peers which only got in because they knew the old passphrase have to know the new one.
+    let revoked = trust::revoke_passphrase_peers(base_dir)?;
+    if !revoked.is_empty() {
+        info!(
+            "Revoked the trust in {} peer(s) which had joined with the old passphrase.",
+            revoked.len()
+        );
+    }
+    let own_addr = last_own_addr(base_dir, secret_key.public())?;
+    Ok((format_secret_address(&own_addr, &passphrase), passphrase))
+}
+
+/// Creates an invite, see `invite::create`. Also returns the secret address under which peers
+/// can join with it.
+pub fn create_invite(
+    base_dir: &Path,
+    access: Access,
+    expires: Option<i64>,
+    uses: Option<u32>,
+) -> Result<(Invite, String)> {
+    let (secret_key, passphrase) = ConnectionManager::get_keypair(base_dir)?;
+    // Before creating an invite nobody could use.
+    let own_addr = last_own_addr(base_dir, secret_key.public())?;
+    let invite = invite::create(base_dir, access, expires, uses)?;
+    let secret_address = format_secret_address(&own_addr, &invite.passphrase(&passphrase));
+    Ok((invite, secret_address))
+}
+
+/// Closes a connection which a peer made to us.
+enum Closer {
+    Iroh(iroh::endpoint::Connection),
+    Tcp(CancellationToken),
+}
+
+impl Closer {
+    fn close(&self, reason: &'static [u8]) {
+        match self {
+            Self::Iroh(connection) => connection.close(0_u32.into(), reason),
+            Self::Tcp(token) => token.cancel(),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        match self {
+            Self::Iroh(connection) => connection.close_reason().is_some(),
+            Self::Tcp(token) => token.is_cancelled(),
+        }
+    }
+}
+
+/// A peer which connected to us.
+struct IncomingConnection {
+    node_id: String,
+    closer: Closer,
+    /// Whether the peer had to authenticate with our passphrase, because we didn't trust it yet.
+    authenticated_with_passphrase: bool,
+    /// Whether the peer is a session member which got in because another peer vouched for it.
+    vouched: bool,
+}
+
+#[expect(clippy::large_enum_variant)] // these messages are rare
 enum EndpointMessage {
     // Instruct the endpoint to connect to a new peer.
     Connect {
         // All information we need to connect to another peer.
-        secret_address: SecretAddress,
+        address: PeerAddress,
         // On connection success, this channel will be pinged.
         // Used for the initial connection, where we want to fail if connecting fails.
         response_tx: Option<oneshot::Sender<Result<()>>>,
-        // How many times have we already attempted to connect?
-        previous_attempts: usize,
+    },
+    // We stopped trying to reach this peer.
+    GaveUp(NodeId),
+    // A peer which connected to us via TCP was let in. Remember it, so that we can close its
+    // connection later.
+    AddIncomingConnection(IncomingConnection),
+    // Instruct the endpoint to close all connections.
+    Close {
+        // Will be pinged once the endpoint is closed.
+        response_tx: oneshot::Sender<()>,
     },
 }
 
-// Owns the Iroh endpoint, accepts incoming connections, and can be instructed to connect to
-// another daemon.
+/// A connection we made to another daemon, before authenticating.
+enum OutgoingConnection {
+    Iroh(iroh::endpoint::Connection),
+    Tcp(TcpStream),
+}
+
+// Owns the Iroh endpoint (and the TCP listener, if any), accepts incoming connections, and can be
+// instructed to connect to another daemon.
 struct EndpointActor {
     endpoint: iroh::Endpoint,
+    tcp_listener: Option<TcpListener>,
     message_rx: mpsc::Receiver<EndpointMessage>,
     message_tx: mpsc::Sender<EndpointMessage>,
     document_handle: DocumentActorHandle,
+    peer_command_rx: PeerCommandReceiver,
     my_passphrase: SecretKey,
+    secret_addresses_tx: watch::Sender<SecretAddresses>,
+    /// What we put into our secret addresses, besides the passphrase.
+    own_addr: NodeAddr,
+    base_dir: PathBuf,
+    incoming_connections: Vec<IncomingConnection>,
+    /// The peers we connect to (or keep trying to).
+    outgoing_peers: HashSet<NodeId>,
+    vouches_tx: watch::Sender<Vouches>,
+    peer_approval: PeerApproval,
+    reconnect: Reconnect,
 }
 
 impl EndpointActor {
-    fn new(
-        endpoint: iroh::Endpoint,
-        message_rx: mpsc::Receiver<EndpointMessage>,
-        message_tx: mpsc::Sender<EndpointMessage>,
-        document_handle: DocumentActorHandle,
-        my_passphrase: SecretKey,
-    ) -> Self {
-        Self {
-            endpoint,
-            message_rx,
-            message_tx,
-            document_handle,
-            my_passphrase,
-        }
-    }
-
-    async fn handle_message(&self, message: EndpointMessage) -> Result<()> {
+    async fn handle_message(&mut self, message: EndpointMessage) -> Result<()> {
         match message {
             EndpointMessage::Connect {
-                secret_address,
+                address,
                 response_tx,
-                previous_attempts,
             } => {
-                let node_addr = secret_address.node_addr.clone();
-                let connect_result = self.endpoint.connect(node_addr, ALPN).await;
-                let conn = match connect_result {
-                    Ok(conn) => conn,
-                    Err(err) => {
-                        if let Some(response_tx) = response_tx {
-                            response_tx
-                                .send(Err(err))
-                                .expect("Connect receiver dropped");
-                        }
-                        Self::reconnect(self.message_tx.clone(), secret_address, previous_attempts)
-                            .await
-                            .expect("Failed to initiate reconnection");
-                        // Not really Ok, but Ok enough.
-                        return Ok(());
+                let node_id = address.node_id();
+                if !self.outgoing_peers.insert(node_id) {
+                    // There's a task for this peer already, which keeps trying to reach it.
+                    if let Some(response_tx) = response_tx {
+                        let _ = response_tx.send(Ok(()));
                     }
-                };
-
-                info!(
-                    "Connected to peer: {}",
-                    conn.remote_node_id()
-                        .expect("Connection should have a node ID")
-                );
-
-                if let Some(response_tx) = response_tx {
-                    response_tx.send(Ok(())).expect("Connect receiver dropped");
+                    return Ok(());
                 }
-
-                let document_handle_clone = self.document_handle.clone();
-                let message_tx_clone = self.message_tx.clone();
-                tokio::spawn(async move {
-                    if let Err(err) = Self::handle_peer(
-                        document_handle_clone,
-                        conn,
-                        PeerAuth::YourPassphrase(secret_address.passphrase.clone()),
-                    )
-                    .await
-                    {
-                        debug!("Error while handling a peer: {:?}", err);
+                // Connect in the background, so that we can connect to several peers at once.
+                tokio::spawn(Self::connect_to_peer(
+                    self.endpoint.clone(),
+                    self.document_handle.clone(),
+                    self.message_tx.clone(),
+                    self.base_dir.clone(),
+                    address,
+                    response_tx,
+                    self.reconnect,
+                    self.vouches_tx.subscribe(),
+                ));
+            }
+            EndpointMessage::GaveUp(node_id) => {
+                self.outgoing_peers.remove(&node_id);
+            }
+            EndpointMessage::AddIncomingConnection(incoming) => {
+                self.incoming_connections.push(incoming);
+            }
+            EndpointMessage::Close { response_tx } => {
+                debug!("Closing endpoint");
+                self.tcp_listener = None;
+                for incoming in &self.incoming_connections {
+                    if let Closer::Tcp(token) = &incoming.closer {
+                        token.cancel();
                     }
-                    Self::reconnect(message_tx_clone, secret_address, 0)
-                        .await
-                        .expect("Failed to initiate reconnection");
-                });
+                }
+                self.endpoint.close().await;
+                let _ = response_tx.send(());
             }
         }
         Ok(())
     }
 
-    async fn reconnect(
+    /// Connects to a peer, and syncs with it until the connection is lost. Then, starts over,
+    /// backing off after every failed attempt, until it's time to give up. Session members are
+    /// only connected to while a peer we trust vouches for them.
+    #[expect(clippy::too_many_arguments)]
+    async fn connect_to_peer(
+        endpoint: iroh::Endpoint,
+        document_handle: DocumentActorHandle,
         message_tx: mpsc::Sender<EndpointMessage>,
-        secret_address: SecretAddress,
-        previous_attempts: usize,
-    ) -> Result<()> {
-        // Only log at "info" level if this is the first reconnection attempt.
-        if previous_attempts == 0 {
-            info!(
-                "Connection to peer {} lost, will keep trying to reconnect...",
-                secret_address.node_addr.node_id
-            );
-        } else {
-            sleep(Duration::from_secs(10)).await;
-            debug!(
-                "Making another attempt to connect to peer {}...",
-                secret_address.node_addr.node_id
-            );
-        }
-        // We don't need to be notified, so we don't need to use the response channel.
-        message_tx
-            .send(EndpointMessage::Connect {
-                secret_address,
-                response_tx: None,
-                previous_attempts: previous_attempts + 1,
+        base_dir: PathBuf,
+        address: PeerAddress,
+        mut response_tx: Option<oneshot::Sender<Result<()>>>,
+        reconnect: Reconnect,
+        mut vouches: watch::Receiver<Vouches>,
+    ) {
+        let node_id = address.node_id();
+        let report = |state| {
+            document_handle.send_message(DocMessage::PeerConnectionState {
+                node_id: node_id.to_string(),
+                state,
             })
-            .await?;
-        Ok(())
+        };
+        let is_member = matches!(address, PeerAddress::Member(_));
+        let mut backoff = Backoff::new(reconnect);
+        loop {
+            if is_member && !is_vouched(&vouches.borrow(), &node_id, &base_dir) {
+                info!("Nobody we trust vouches for session member {node_id} anymore, giving up.");
+                report(ConnectionState::GaveUp).await;
+                let _ = message_tx.send(EndpointMessage::GaveUp(node_id)).await;
+                return;
+            }
+            report(ConnectionState::Connecting).await;
+            let connect_result = match &address {
+                PeerAddress::Iroh(SecretAddress { node_addr, .. })
+                | PeerAddress::Member(node_addr) => endpoint
+                    .connect(node_addr.clone(), ALPN)
+                    .await
+                    .map(OutgoingConnection::Iroh),
+                PeerAddress::Tcp(tcp_address) => TcpStream::connect(&tcp_address.address)
+                    .await
+                    .map(OutgoingConnection::Tcp)
+                    .with_context(|| format!("Failed to connect to {}", tcp_address.address)),
+            };
+            match connect_result {
+                Ok(conn) => {
+                    info!("Connected to peer: {node_id}");
+                    if let Some(response_tx) = response_tx.take() {
+                        // The receiver is gone if another peer was reached first.
+                        let _ = response_tx.send(Ok(()));
+                    }
+
+                    let iroh_conn = match &conn {
+                        OutgoingConnection::Iroh(conn) => Some(conn.clone()),
+                        OutgoingConnection::Tcp(_) => None,
+                    };
+                    // Only start over once the peer let us in, and our histories match.
+                    // Otherwise, a peer which rejects us would be tried again and again.
+                    let (greeted_tx, greeted_rx) = oneshot::channel();
+                    let handling = async {
+                        match (conn, &address) {
+                            (OutgoingConnection::Iroh(conn), PeerAddress::Iroh(secret_address)) => {
+                                Self::handle_peer(
+                                    document_handle.clone(),
+                                    conn,
+                                    PeerAuth::YourPassphrase(secret_address.passphrase.clone()),
+                                    &base_dir,
+                                    Some(greeted_tx),
+                                )
+                                .await
+                            }
+                            (OutgoingConnection::Iroh(conn), _) => {
+                                Self::handle_peer(
+                                    document_handle.clone(),
+                                    conn,
+                                    PeerAuth::Vouched,
+                                    &base_dir,
+                                    Some(greeted_tx),
+                                )
+                                .await
+                            }
+                            (OutgoingConnection::Tcp(stream), PeerAddress::Tcp(tcp_address)) => {
+                                Self::handle_outgoing_tcp_peer(
+                                    document_handle.clone(),
+                                    stream,
+                                    &tcp_address.secret_address,
+                                    endpoint.secret_key(),
+                                    &base_dir,
+                                    greeted_tx,
+                                )
+                                .await
+                            }
+                            (OutgoingConnection::Tcp(_), _) => {
+                                unreachable!("Only TCP addresses are connected to via TCP")
+                            }
+                        }
+                    };
+                    // Keep syncing while we report: the report can be queued behind a message
+                    // the sync sends to the document actor.
+                    let greeted = async {
+                        if greeted_rx.await.is_ok() {
+                            report(ConnectionState::Connected).await;
+                            backoff.reset();
+                        }
+                    };
+                    // Session members are dropped once nobody we trust vouches for them.
+                    let dropped = async {
+                        if is_member {
+                            let _ = vouches
+                                .wait_for(|vouches| !vouches.contains_key(&node_id))
+                                .await;
+                        } else {
+                            std::future::pending::<()>().await;
+                        }
+                    };
+                    let result = tokio::select! {
+                        (result, ()) = async { tokio::join!(handling, greeted) } => result,
+                        () = dropped => {
+                            if let Some(conn) = iroh_conn {
+                                conn.close(0_u32.into(), b"voucher revoked");
+                            }
+                            info!("Disconnected session member {node_id}, because nobody we trust vouches for it anymore.");
+                            report(ConnectionState::GaveUp).await;
+                            let _ = message_tx.send(EndpointMessage::GaveUp(node_id)).await;
+                            return;
+                        }
+                    };
+                    if let Err(err) = result {
+                        if err.is::<UnrelatedHistory>() {
+                            // Trying again won't help.
+                            report(ConnectionState::GaveUp).await;
+                            let _ = message_tx.send(EndpointMessage::GaveUp(node_id)).await;
+                            return;
+                        }
+                        debug!("Error while handling a peer: {:?}", err);
+                    }
+                    info!("Connection to peer {node_id} lost, will keep trying to reconnect...");
+                }
+                Err(err) => {
+                    debug!("Failed to connect to peer {node_id}: {err:#}");
+                    if let Some(response_tx) = response_tx.take() {
+                        let _ = response_tx.send(Err(err));
+                    }
+                }
+            }
+
+            if endpoint.is_closed() {
+                return;
+            }
+            let Some(delay) = backoff.next_delay() else {
+                warn!("Failed to reach peer {node_id} for too long, giving up.");
+                report(ConnectionState::GaveUp).await;
+                let _ = message_tx.send(EndpointMessage::GaveUp(node_id)).await;
+                return;
+            };
+            report(ConnectionState::BackingOff {
+                attempts: backoff.attempts(),
+                next_attempt: timestamp::now() + delay.as_secs() as i64,
+            })
+            .await;
+            // When our own addresses change, we're probably in a different network, where the
+            // peer might be reachable again.
+            let mut direct_addresses = endpoint.direct_addresses();
+            tokio::select! {
+                () = sleep(delay) => {}
+                result = direct_addresses.updated() => {
+                    if result.is_err() {
+                        // The endpoint is gone.
+                        return;
+                    }
+                    info!("The network changed, trying to reach peer {node_id} right away.");
+                }
+            }
+            debug!("Making another attempt to connect to peer {node_id}...");
+        }
     }
 
     async fn run(&mut self) {
@@ -313,6 +979,12 @@ impl EndpointActor {
                         }
                     }
                 }
+                accepted = Self::accept_tcp(self.tcp_listener.as_ref()) => {
+                    match accepted {
+                        Ok((stream, _)) => self.handle_incoming_tcp_connection(stream),
+                        Err(err) => debug!("Error while accepting TCP connection: {err}"),
+                    }
+                }
                 maybe_message = self.message_rx.recv() => {
                     match maybe_message {
                         Some(message) => {
@@ -324,26 +996,185 @@ impl EndpointActor {
                         }
                     }
                 }
+                maybe_command = self.peer_command_rx.recv() => {
+                    match maybe_command {
+                        Ok(command) => self.handle_peer_command(command).await,
+                        Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(broadcast::error::RecvError::Closed) => {
+                            // The document actor is gone. Let's shut down.
+                            break
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Accepts the next TCP connection. Never completes if we don't listen for TCP connections.
+    async fn accept_tcp(
+        listener: Option<&TcpListener>,
+    ) -> std::io::Result<(TcpStream, SocketAddr)> {
+        match listener {
+            Some(listener) => listener.accept().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn handle_peer_command(&mut self, command: PeerCommand) {
+        // Forget connections which are closed already.
+        self.incoming_connections
+            .retain(|incoming| !incoming.closer.is_closed());
+
+        match command {
+            PeerCommand::Disconnect(node_id) => {
+                for incoming in &self.incoming_connections {
+                    if incoming.node_id == node_id {
+                        incoming.closer.close(b"disconnected");
+                    }
+                }
+                info!("Disconnected peer {node_id}.");
+                // Its trust might have been revoked.
+                self.drop_untrusted_vouches();
+            }
+            PeerCommand::Left(node_id) => {
+                self.vouches_tx.send_modify(|vouches| {
+                    for vouchers in vouches.values_mut() {
+                        if let Some(connected) = vouchers.get_mut(&node_id) {
+                            *connected = false;
+                        }
+                    }
+                });
+            }
+            PeerCommand::UsePassphrase(passphrase) => {
+                let secret_addresses = SecretAddresses::new(&self.own_addr, &passphrase);
+                self.secret_addresses_tx.send_replace(secret_addresses);
+                self.my_passphrase = passphrase;
+
+                let mut count = 0;
+                for incoming in &self.incoming_connections {
+                    if incoming.authenticated_with_passphrase
+                        || Self::trust_state(&self.base_dir, &incoming.node_id)
+                            == Some(TrustState::Revoked)
+                    {
+                        incoming.closer.close(b"passphrase rotated");
+                        count += 1;
+                    }
+                }
+                info!("Rotated the passphrase, and disconnected {count} peer(s) which had used the old one.");
+                self.drop_untrusted_vouches();
+            }
+            PeerCommand::Connect { voucher, address } => {
+                let node_addr = match parse_member_address(&address) {
+                    Ok(node_addr) => node_addr,
+                    Err(err) => {
+                        debug!("Ignoring the address of a session member: {err:#}");
+                        return;
+                    }
+                };
+                let node_id = node_addr.node_id;
+                if node_id == self.endpoint.node_id() {
+                    return;
+                }
+                // Peers which were only accepted for one connection can't let anyone else in.
+                if Self::trust_state(&self.base_dir, &voucher) != Some(TrustState::Trusted) {
+                    debug!("Ignoring session member {node_id}, because we don't trust peer {voucher}, which vouched for it.");
+                    return;
+                }
+                // While the voucher is connected, the member can connect to us, too.
+                self.vouches_tx.send_modify(|vouches| {
+                    vouches.entry(node_id).or_default().insert(voucher, true);
+                });
+                let connected = self.outgoing_peers.contains(&node_id)
+                    || self
+                        .incoming_connections
+                        .iter()
+                        .any(|incoming| incoming.node_id == node_id.to_string());
+                // Of two session members, only the one with the smaller node ID connects to the
+                // other, so that they don't connect twice.
+                if connected || node_id.as_bytes() <= self.endpoint.node_id().as_bytes() {
+                    return;
+                }
+                info!("Connecting to session member {node_id}.");
+                self.handle_message(EndpointMessage::Connect {
+                    address: PeerAddress::Member(node_addr),
+                    response_tx: None,
+                })
+                .await
+                .expect("Failed to handle endpoint message");
+            }
+        }
+    }
+
+    /// Forgets the vouches of peers we don't trust anymore, and closes the connections of the
+    /// session members which none of the remaining ones vouched for.
+    fn drop_untrusted_vouches(&self) {
+        let mut dropped = HashSet::new();
+        self.vouches_tx.send_if_modified(|vouches| {
+            vouches.retain(|member, vouchers| {
+                vouchers.retain(|voucher, _| {
+                    Self::trust_state(&self.base_dir, voucher) == Some(TrustState::Trusted)
+                });
+                if vouchers.is_empty() {
+                    dropped.insert(member.to_string());
+                }
+                !vouchers.is_empty()
+            });
+            !dropped.is_empty()
+        });
+        for incoming in &self.incoming_connections {
+            if incoming.vouched && dropped.contains(&incoming.node_id) {
+                incoming.closer.close(b"voucher revoked");
             }
         }
+        if !dropped.is_empty() {
+            info!(
+                "Dropped {} session member(s), because nobody we trust vouches for them anymore.",
+                dropped.len()
+            );
+        }
     }
 
-    fn handle_incoming_connection(&self, conn: iroh::endpoint::Connection) {
+    fn handle_incoming_connection(&mut self, conn: iroh::endpoint::Connection) {
         let node_id = conn
             .remote_node_id()
             .expect("Connection should have a node ID");
 
+        let trust_state = Self::trust_state(&self.base_dir, &node_id.to_string());
+        if trust_state == Some(TrustState::Revoked) {
+            warn!("Rejected peer {node_id}, because its trust was revoked.");
+            conn.close(0_u32.into(), b"trust revoked");
+            return;
+        }
+
         info!("Peer connected: {}", &node_id);
 
-        let my_passphrase_clone = self.my_passphrase.clone();
+        // Session members don't need our passphrase while a peer we trust vouches for them.
+        let vouched = trust_state.is_none()
+            && is_vouched(&self.vouches_tx.borrow(), &node_id, &self.base_dir);
+        let trusted = if vouched {
+            Some(Access::Full)
+        } else {
+            trust_state.and_then(TrustState::access)
+        };
+        self.incoming_connections.push(IncomingConnection {
+            node_id: node_id.to_string(),
+            closer: Closer::Iroh(conn.clone()),
+            authenticated_with_passphrase: trusted.is_none(),
+            vouched,
+        });
+
+        let auth = PeerAuth::MyPassphrase {
+            passphrase: self.my_passphrase.clone(),
+            trusted,
+            vouched,
+            base_dir: self.base_dir.clone(),
+            approval: self.peer_approval,
+        };
         let document_handle_clone = self.document_handle.clone();
+        let base_dir = self.base_dir.clone();
         tokio::spawn(async move {
-            if let Err(err) = Self::handle_peer(
-                document_handle_clone,
-                conn,
-                PeerAuth::MyPassphrase(my_passphrase_clone),
-            )
-            .await
+            if let Err(err) =
+                Self::handle_peer(document_handle_clone, conn, auth, &base_dir, None).await
             {
                 warn!("Incoming connection failed: {err}");
             }
@@ -356,10 +1187,320 @@ impl EndpointActor {
         document_handle: DocumentActorHandle,
         conn: iroh::endpoint::Connection,
         auth: PeerAuth,
+        base_dir: &Path,
+        greeted_tx: Option<oneshot::Sender<()>>,
     ) -> Result<()> {
-        let connection = IrohConnection::new(conn, auth).await?;
-        let syncer = SyncActor::new(document_handle, Box::new(connection));
-        syncer.run().await
+        let node_id = conn.remote_node_id()?.to_string();
+        let (direction, needs_approval) = match &auth {
+            PeerAuth::MyPassphrase {
+                trusted, approval, ..
+            } => (
+                PeerDirection::Incoming,
+                trusted.is_none() && *approval == PeerApproval::Required,
+            ),
+            PeerAuth::YourPassphrase(_) | PeerAuth::Vouched => (PeerDirection::Outgoing, false),
+        };
+
+        let (connection, auth) = IrohConnection::new(conn.clone(), auth).await?;
+
+        let closed = async {
+            conn.closed().await;
+        };
+        if let Err(err) = Self::admit(
+            &document_handle,
+            &node_id,
+            &auth,
+            needs_approval,
+            base_dir,
+            closed,
+        )
+        .await
+        {
+            conn.close(0_u32.into(), b"rejected");
+            return Err(err);
+        }
+
+        Self::sync_with_peer(
+            document_handle,
+            Box::new(connection),
+            node_id,
+            direction,
+            auth.access,
+            greeted_tx,
+        )
+        .await
+    }
+
+    fn handle_incoming_tcp_connection(&self, stream: TcpStream) {
+        let document_handle = self.document_handle.clone();
+        let message_tx = self.message_tx.clone();
+        let secret_key = self.endpoint.secret_key().clone();
+        let passphrase = self.my_passphrase.clone();
+        let base_dir = self.base_dir.clone();
+        let peer_approval = self.peer_approval;
+        tokio::spawn(async move {
+            if let Err(err) = Self::handle_incoming_tcp_peer(
+                document_handle,
+                message_tx,
+                stream,
+                &secret_key,
+                &passphrase,
+                &base_dir,
+                peer_approval,
+            )
+            .await
+            {
+                warn!("Incoming TCP connection failed: {err}");
+            }
+        });
+    }
+
+    async fn handle_incoming_tcp_peer(
+        document_handle: DocumentActorHandle,
+        message_tx: mpsc::Sender<EndpointMessage>,
+        stream: TcpStream,
+        secret_key: &SecretKey,
+        passphrase: &SecretKey,
+        base_dir: &Path,
+        peer_approval: PeerApproval,
+    ) -> Result<()> {
+        let mut incoming = IncomingTcpConnection::accept(stream, secret_key).await?;
+        let node_id = incoming.node_id.to_string();
+
+        let trust_state = Self::trust_state(base_dir, &node_id);
+        if trust_state == Some(TrustState::Revoked) {
+            bail!("Rejected peer {node_id}, because its trust was revoked.");
+        }
+
+        info!("Peer connected via TCP: {node_id}");
+
+        let trusted = trust_state.and_then(TrustState::access);
+        let auth = match trusted {
+            Some(access) => Authentication::trusted(access),
+            None => authenticate(
+                &incoming.passphrase,
+                passphrase,
+                base_dir,
+                &incoming.node_id,
+            )?,
+        };
+        let needs_approval = trusted.is_none() && peer_approval == PeerApproval::Required;
+        Self::admit(
+            &document_handle,
+            &node_id,
+            &auth,
+            needs_approval,
+            base_dir,
+            incoming.closed(),
+        )
+        .await?;
+        let connection = incoming.finish().await?;
+
+        message_tx
+            .send(EndpointMessage::AddIncomingConnection(IncomingConnection {
+                node_id: node_id.clone(),
+                closer: Closer::Tcp(connection.closer()),
+                authenticated_with_passphrase: trusted.is_none(),
+                vouched: false,
+            }))
+            .await?;
+
+        let result = Self::sync_with_peer(
+            document_handle,
+            Box::new(connection),
+            node_id.clone(),
+            PeerDirection::Incoming,
+            auth.access,
+            None,
+        )
+        .await;
+
+        info!("Peer disconnected: {node_id}");
+
+        result
+    }
+
+    async fn handle_outgoing_tcp_peer(
+        document_handle: DocumentActorHandle,
+        stream: TcpStream,
+        secret_address: &SecretAddress,
+        secret_key: &SecretKey,
+        base_dir: &Path,
+        greeted_tx: oneshot::Sender<()>,
+    ) -> Result<()> {
+        let connection = tcp::connect(stream, secret_address, secret_key).await?;
+        let node_id = secret_address.node_addr.node_id.to_string();
+
+        Self::admit(
+            &document_handle,
+            &node_id,
+            &Authentication::trusted(Access::Full),
+            false,
+            base_dir,
+            std::future::pending(),
+        )
+        .await?;
+
+        Self::sync_with_peer(
+            document_handle,
+            Box::new(connection),
+            node_id,
+            PeerDirection::Outgoing,
+            Access::Full,
+            Some(greeted_tx),
+        )
+        .await
+    }
+
+    /// Returns what we decided about the peer with `node_id`, if anything.
+    fn trust_state(base_dir: &Path, node_id: &str) -> Option<TrustState> {
+        trust::state(base_dir, node_id).unwrap_or_else(|err| {
+            warn!("Failed to read the trusted peers: {err:#}");
+            None
+        })
+    }
+
+    /// Lets an authenticated peer in. If it needs approval, waits until someone decides about
+    /// it, or until `closed` completes. If it joined with an invite, uses it up. Unless it was
+    /// only accepted once, or is a session member which another peer vouched for, it's trusted
+    /// from then on.
+    async fn admit(
+        document_handle: &DocumentActorHandle,
+        node_id: &str,
+        auth: &Authentication,
+        needs_approval: bool,
+        base_dir: &Path,
+        closed: impl Future<Output = ()>,
+    ) -> Result<()> {
+        let access = auth.access;
+        let newly_trusted = if needs_approval {
+            let decision = tokio::select! {
+                decision = document_handle.wait_for_approval(node_id.to_string(), access) => decision,
+                () = closed => bail!("Peer {node_id} disconnected while waiting for approval."),
+            };
+            match decision {
+                PeerDecision::Reject => bail!("Rejected peer {node_id}."),
+                PeerDecision::Accept => {
+                    Self::use_invite(base_dir, node_id, auth)?;
+                    info!("Accepted peer {node_id}, for this connection only.");
+                    Ok(false)
+                }
+                PeerDecision::AlwaysTrust => {
+                    Self::use_invite(base_dir, node_id, auth)?;
+                    trust::trust(base_dir, node_id, access).map(|()| true)
+                }
+            }
+        } else if auth.vouched {
+            info!(
+                "Syncing with session member {node_id}, without trusting it beyond this session."
+            );
+            Ok(false)
+        } else {
+            Self::use_invite(base_dir, node_id, auth)?;
+            // From now on, this peer can connect without the passphrase.
+            trust::trust_on_first_use(base_dir, node_id, access, auth.origin)
+        };
+        match newly_trusted {
+            Ok(true) if access == Access::ReadOnly => {
+                info!("Trusting peer {node_id} with read-only access from now on.");
+            }
+            Ok(true) => info!("Trusting peer {node_id} from now on."),
+            Ok(false) => {}
+            Err(err) => warn!("Failed to trust peer {node_id}: {err:#}"),
+        }
+        Ok(())
+    }
+
+    /// Uses up the invite the peer joined with, if any. Fails if it's not valid anymore, for
+    /// example because another peer used it up in the meantime.
+    fn use_invite(base_dir: &Path, node_id: &str, auth: &Authentication) -> Result<()> {
+        let Some(id) = &auth.invite else {
+            return Ok(());
+        };
+        match invite::redeem(base_dir, id)? {
+            Redemption::Accepted(invite) => {
+                info!("Peer {node_id} joined with invite {}.", invite.id);
+                Ok(())
+            }
+            redemption => {
+                reject_invite(node_id, &redemption);
+                bail!("Peer used an invalid invite.");
+            }
+        }
+    }
+
+    /// Syncs the document with a peer which was let in, until the connection ends. Sends to
+    /// `greeted_tx` once we know that we can sync with the peer.
+    async fn sync_with_peer(
+        document_handle: DocumentActorHandle,
+        mut connection: Box<dyn Connection<PeerMessage>>,
+        node_id: String,
+        direction: PeerDirection,
+        access: Access,
+        greeted_tx: Option<oneshot::Sender<()>>,
+    ) -> Result<()> {
+        let (response_tx, response_rx) = oneshot::channel();
+        document_handle
+            .send_message(DocMessage::GetIdentity { response_tx })
+            .await;
+        let document_id = response_rx
+            .await
+            .context("Could not read response from Document channel")?;
+        let features = async {
+            let (features, their_document_id) =
+                sync::greet(connection.as_mut(), &node_id, document_id).await?;
+            let (response_tx, response_rx) = oneshot::channel();
+            document_handle
+                .send_message(DocMessage::CheckIdentity {
+                    identity: their_document_id,
+                    response_tx,
+                })
+                .await;
+            let related = response_rx
+                .await
+                .context("Could not read response from Document channel")?;
+            if !related {
+                return Err(UnrelatedHistory {
+                    node_id: node_id.clone(),
+                }
+                .into());
+            }
+            anyhow::Ok(features)
+        }
+        .await
+        .inspect_err(|err| {
+                if err.is::<UnrelatedHistory>() && direction == PeerDirection::Outgoing {
+                    error!("{err:#}. To discard our history, and sync with the peer, run `teamtype join --discard-history`. Files which differ will be overwritten with the peer's versions.");
+                } else {
+                    warn!("{err:#}");
+                }
+            })?;
+        if let Some(greeted_tx) = greeted_tx {
+            let _ = greeted_tx.send(());
+        }
+
+        document_handle
+            .send_message(DocMessage::PeerConnected {
+                node_id: node_id.clone(),
+                direction,
+                access,
+            })
+            .await;
+
+        let syncer = SyncActor::new(
+            document_handle.clone(),
+            connection,
+            node_id.clone(),
+            access,
+            features,
+        );
+        let result = syncer.run().await;
+
+        document_handle
+            .send_message(DocMessage::PeerDisconnected(node_id))
+            .await;
+
+        result
     }
 }
 
@@ -370,28 +1511,52 @@ struct IrohConnection {
 }
 
 impl IrohConnection {
-    async fn new(conn: iroh::endpoint::Connection, auth: PeerAuth) -> Result<Self> {
-        let (send, receive) = match auth {
+    /// Also returns what the peer may do. Peers we connected to always have full access, but they
+    /// might only give us read-only access.
+    async fn new(
+        conn: iroh::endpoint::Connection,
+        auth: PeerAuth,
+    ) -> Result<(Self, Authentication)> {
+        let (send, receive, authentication) = match auth {
             PeerAuth::YourPassphrase(passphrase) => {
                 let (mut send, recv) = conn.open_bi().await?;
 
                 send.write_all(&passphrase.to_bytes()).await?;
 
-                (send, recv)
+                (send, recv, Authentication::trusted(Access::Full))
+            }
+            PeerAuth::Vouched => {
+                let (mut send, recv) = conn.open_bi().await?;
+
+                // The peer lets us in already, and doesn't look at the passphrase.
+                send.write_all(&[0; 32]).await?;
+
+                (send, recv, Authentication::vouched())
             }
-            PeerAuth::MyPassphrase(passphrase) => {
+            PeerAuth::MyPassphrase {
+                passphrase,
+                trusted,
+                vouched,
+                base_dir,
+                ..
+            } => {
                 let (send, mut recv) = conn.accept_bi().await?;
 
                 let mut received_passphrase = [0; 32];
                 recv.read_exact(&mut received_passphrase).await?;
 
-                // Guard against timing attacks.
-                if !constant_time_eq::constant_time_eq(&received_passphrase, &passphrase.to_bytes())
-                {
-                    bail!("Peer provided incorrect passphrase.");
-                }
+                let authentication = match trusted {
+                    Some(_) if vouched => Authentication::vouched(),
+                    Some(access) => Authentication::trusted(access),
+                    None => authenticate(
+                        &received_passphrase,
+                        &passphrase,
+                        &base_dir,
+                        &conn.remote_node_id()?,
+                    )?,
+                };
 
-                (send, recv)
+                (send, recv, authentication)
             }
         };
 
@@ -401,7 +1566,7 @@ impl IrohConnection {
             let _ = Self::read_loop(receive, message_tx).await;
         });
 
-        Ok(Self { send, message_rx })
+        Ok((Self { send, message_rx }, authentication))
     }
 
     async fn read_loop(
@@ -422,7 +1587,8 @@ impl IrohConnection {
 
         let mut bytes = vec![0; byte_count as usize];
         receive.read_exact(&mut bytes).await?;
-        from_bytes(&bytes).context("Failed to convert bytes to PeerMessage")
+        from_bytes(&bytes)
+            .context("Failed to convert bytes to PeerMessage (does the peer run a newer Teamtype?)")
     }
 }
 
@@ -447,3 +1613,196 @@ impl Connection<PeerMessage> for IrohConnection {
             .context("Failed to await next peer message")?
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn rotating_the_passphrase_keeps_the_node_id() {
+        let dir = TempDir::new().unwrap();
+        sandbox::create_dir(dir.path(), &dir.child(config::CONFIG_DIR)).unwrap();
+        assert!(rotate_passphrase(dir.path()).is_err());
+
+        let (secret_key, old_passphrase) = ConnectionManager::get_keypair(dir.path()).unwrap();
+        let (secret_address, new_passphrase) = rotate_passphrase(dir.path()).unwrap();
+        assert_ne!(new_passphrase.to_bytes(), old_passphrase.to_bytes());
+        assert_eq!(
+            secret_address,
+            format!("{}#{new_passphrase}", secret_key.public())
+        );
+
+        let (reloaded_secret_key, reloaded_passphrase) =
+            ConnectionManager::get_keypair(dir.path()).unwrap();
+        assert_eq!(reloaded_secret_key.to_bytes(), secret_key.to_bytes());
+        assert_eq!(reloaded_passphrase.to_bytes(), new_passphrase.to_bytes());
+    }
+
+    #[test]
+    fn invites_contain_the_last_known_addresses() {
+        let dir = TempDir::new().unwrap();
+        sandbox::create_dir(dir.path(), &dir.child(config::CONFIG_DIR)).unwrap();
+        let config_file = dir.child(config::CONFIG_DIR).join(config::CONFIG_FILE);
+        sandbox::write_file(dir.path(), &config_file, b"discovery = none\n").unwrap();
+        let (secret_key, passphrase) = ConnectionManager::get_keypair(dir.path()).unwrap();
+
+        // Without the addresses, peers couldn't reach us.
+        assert!(create_invite(dir.path(), Access::Full, None, None).is_err());
+        assert!(invite::invites(dir.path()).unwrap().is_empty());
+
+        let mut own_addr = NodeAddr::new(secret_key.public());
+        own_addr
+            .direct_addresses
+            .insert(SocketAddr::from_str("192.0.2.1:4001").unwrap());
+        remember_own_addr(dir.path(), &own_addr).unwrap();
+        let (invite, secret_address) = create_invite(dir.path(), Access::Full, None, None).unwrap();
+        let secret_address = SecretAddress::from_str(&secret_address).unwrap();
+        assert_eq!(secret_address.node_addr, own_addr);
+        assert_eq!(
+            secret_address.passphrase.to_bytes(),
+            invite.passphrase(&passphrase).to_bytes()
+        );
+    }
+
+    #[test]
+    fn member_addresses_contain_no_passphrase() {
+        let secret_key = SecretKey::generate(rand::rngs::OsRng);
+        let mut node_addr = NodeAddr::new(secret_key.public());
+        let member_address = format_member_address(&node_addr);
+        assert_eq!(member_address, secret_key.public().to_string());
+        assert_eq!(parse_member_address(&member_address).unwrap(), node_addr);
+
+        node_addr
+            .direct_addresses
+            .insert(SocketAddr::from_str("192.0.2.1:4001").unwrap());
+        let member_address = format_member_address(&node_addr);
+        assert_eq!(
+            member_address,
+            format!("{}#192.0.2.1:4001", secret_key.public())
+        );
+        assert_eq!(
+            member_node_id(&member_address),
+            secret_key.public().to_string()
+        );
+        assert_eq!(parse_member_address(&member_address).unwrap(), node_addr);
+    }
+
+    #[test]
+    fn members_are_only_vouched_for_by_connected_trusted_peers() {
+        let dir = TempDir::new().unwrap();
+        sandbox::create_dir(dir.path(), &dir.child(config::CONFIG_DIR)).unwrap();
+        let node_id = |()| SecretKey::generate(rand::rngs::OsRng).public();
+        let [member, trusted, read_only, stranger] = [(); 4].map(node_id);
+        trust::trust(dir.path(), &trusted.to_string(), Access::Full).unwrap();
+        trust::trust(dir.path(), &read_only.to_string(), Access::ReadOnly).unwrap();
+
+        let vouched_by = |voucher: NodeId, connected| {
+            Vouches::from([(member, HashMap::from([(voucher.to_string(), connected)]))])
+        };
+        assert!(is_vouched(&vouched_by(trusted, true), &member, dir.path()));
+        assert!(!is_vouched(
+            &vouched_by(trusted, false),
+            &member,
+            dir.path()
+        ));
+        assert!(!is_vouched(
+            &vouched_by(read_only, true),
+            &member,
+            dir.path()
+        ));
+        assert!(!is_vouched(
+            &vouched_by(stranger, true),
+            &member,
+            dir.path()
+        ));
+        assert!(!is_vouched(
+            &vouched_by(trusted, true),
+            &stranger,
+            dir.path()
+        ));
+
+        trust::revoke(dir.path(), &trusted.to_string()).unwrap();
+        assert!(!is_vouched(&vouched_by(trusted, true), &member, dir.path()));
+        // Members are never trusted themselves.
+        assert_eq!(trust::state(dir.path(), &member.to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn read_only_passphrase_grants_read_only_access() {
+        let passphrase = SecretKey::generate(rand::rngs::OsRng);
+        let read_only = read_only_passphrase(&passphrase);
+        assert_ne!(read_only.to_bytes(), passphrase.to_bytes());
+
+        assert_eq!(
+            access_for_passphrase(&passphrase.to_bytes(), &passphrase),
+            Some(Access::Full)
+        );
+        assert_eq!(
+            access_for_passphrase(&read_only.to_bytes(), &passphrase),
+            Some(Access::ReadOnly)
+        );
+        // Deriving again from the read-only passphrase gives nothing useful.
+        assert_eq!(
+            access_for_passphrase(&read_only_passphrase(&read_only).to_bytes(), &passphrase),
+            None
+        );
+
+        let secret_addresses = SecretAddresses::new(&passphrase.public().into(), &passphrase);
+        let secret_address =
+            SecretAddress::from_str(secret_addresses.get(Access::ReadOnly)).unwrap();
+        assert_eq!(secret_address.passphrase.to_bytes(), read_only.to_bytes());
+    }
+
+    #[test]
+    fn secret_addresses_can_contain_addresses() {
+        let passphrase = SecretKey::generate(rand::rngs::OsRng);
+        let node_addr = NodeAddr::from_parts(
+            passphrase.public(),
+            Some(RelayUrl::from_str("https://relay.example.com").unwrap()),
+            [SocketAddr::from_str("192.168.1.23:4001").unwrap()],
+        );
+
+        let secret_address =
+            SecretAddress::from_str(&format_secret_address(&node_addr, &passphrase)).unwrap();
+        assert_eq!(secret_address.node_addr, node_addr);
+
+        let without_addresses = format_secret_address(&passphrase.public().into(), &passphrase);
+        assert_eq!(without_addresses.matches('#').count(), 1);
+        assert!(SecretAddress::from_str(&format!("{without_addresses}#not-an-address")).is_err());
+    }
+
+    #[tokio::test]
+    async fn connects_without_relays_and_discovery() {
+        async fn endpoint(dir: &TempDir) -> (iroh::Endpoint, SecretKey) {
+            sandbox::create_dir(dir.path(), &dir.child(config::CONFIG_DIR)).unwrap();
+            let app_config = AppConfig {
+                base_dir: dir.path().to_path_buf(),
+                relays: Relays::Disabled,
+                discovery: Discovery::Disabled,
+                ..Default::default()
+            };
+            ConnectionManager::build_endpoint(&app_config)
+                .await
+                .unwrap()
+        }
+        let host_dir = TempDir::new().unwrap();
+        let guest_dir = TempDir::new().unwrap();
+        let (host, passphrase) = endpoint(&host_dir).await;
+        let (guest, _) = endpoint(&guest_dir).await;
+
+        let own_addr = ConnectionManager::own_addr(&host, false).await;
+        let secret_address =
+            SecretAddress::from_str(&format_secret_address(&own_addr, &passphrase)).unwrap();
+        assert!(!secret_address.node_addr.direct_addresses.is_empty());
+
+        let accepting_host = host.clone();
+        let accepted =
+            tokio::spawn(async move { accepting_host.accept().await.unwrap().await.unwrap() });
+        guest.connect(secret_address.node_addr, ALPN).await.unwrap();
+        assert_eq!(
+            accepted.await.unwrap().remote_node_id().unwrap(),
+            guest.node_id()
+        );
+    }
+}
@@ -0,0 +1,94 @@
+// SPDX-FileCopyrightText: 2025 blinry <mail@blinry.org>
+// SPDX-FileCopyrightText: 2025 zormit <nt4u@kpvn.de>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Decides how long to wait before trying to reach a peer again.
+//!
+//! The delay doubles with every failed attempt, up to a maximum. A random part of it is left out,
+//! so that peers which lost their connection at the same time don't all retry at the same time.
+use crate::config::Reconnect;
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+pub struct Backoff {
+    reconnect: Reconnect,
+    attempts: u32,
+    /// When the first attempt after the last successful connection failed.
+    failing_since: Option<Instant>,
+}
+
+impl Backoff {
+    pub const fn new(reconnect: Reconnect) -> Self {
+        Self {
+            reconnect,
+            attempts: 0,
+            failing_since: None,
+        }
+    }
+
+    /// Starts over, because we're connected again.
+    pub const fn reset(&mut self) {
+        self.attempts = 0;
+        self.failing_since = None;
+    }
+
+    /// How many times we've waited since the last successful connection.
+    pub const fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Returns how long to wait before the next attempt, or `None` if it's time to give up.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        let failing_since = *self.failing_since.get_or_insert_with(Instant::now);
+
+        let delay = self
+            .reconnect
+            .initial_delay
+            .saturating_mul(2_u32.saturating_pow(self.attempts))
+            .min(self.reconnect.max_delay);
+        let delay = rand::thread_rng().gen_range(delay / 2..=delay);
+
+        if let Some(give_up_after) = self.reconnect.give_up_after {
+            if failing_since.elapsed() + delay > give_up_after {
+                return None;
+            }
+        }
+        self.attempts += 1;
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_up_to_the_maximum() {
+        let mut backoff = Backoff::new(Reconnect {
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(10),
+            give_up_after: None,
+        });
+        for max in [2, 4, 8, 10, 10] {
+            let delay = backoff.next_delay().unwrap();
+            assert!(delay >= Duration::from_secs(max) / 2);
+            assert!(delay <= Duration::from_secs(max));
+        }
+        assert_eq!(backoff.attempts(), 5);
+
+        backoff.reset();
+        assert!(backoff.next_delay().unwrap() <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn gives_up_eventually() {
+        let mut backoff = Backoff::new(Reconnect {
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(10),
+            give_up_after: Some(Duration::from_secs(1)),
+        });
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.attempts(), 0);
+    }
+}
@@ -4,23 +4,138 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use crate::daemon::{DocMessage, DocumentActorHandle};
-use crate::types::EphemeralMessage;
-use anyhow::{Context, Result};
+use crate::types::{Access, EphemeralMessage};
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
 use automerge::sync::{Message as AutomergeSyncMessage, State as SyncState};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::mem;
 use tokio::sync::{broadcast, oneshot};
-use tracing::debug;
+use tracing::{debug, error};
+
+/// Bumped when daemons with different versions can't sync with each other anymore. Additions
+/// which older daemons can do without are negotiated as features instead.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The peer sends [`PeerMessage::Members`].
+const FEATURE_MEMBERS: &str = "members";
+/// The optional features we support. A feature is only used if both peers support it.
+const FEATURES: &[&str] = &[FEATURE_MEMBERS];
+
+/// The first message on every connection, in both directions, after the passphrase.
+///
+/// Fields may only be appended, so that older daemons can still read the ones they know.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    /// Like "0.9.0". Only used to tell users what to update.
+    pub daemon_version: String,
+    pub features: Vec<String>,
+    /// The identity of the peer's history. `None` if it just joined, and hasn't synced yet.
+    pub document_id: Option<String>,
+}
+
+impl Hello {
+    fn ours(document_id: Option<String>) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            daemon_version: env!("CARGO_PKG_VERSION").to_string(),
+            features: FEATURES.iter().map(ToString::to_string).collect(),
+            document_id,
+        }
+    }
+
+    /// Checks whether we can sync with the peer which sent this hello. Returns the features both
+    /// of us support.
+    fn negotiate(&self, node_id: &str) -> Result<Vec<String>> {
+        let ours = Self::ours(None);
+        if self.protocol_version != ours.protocol_version {
+            let who_should_update = if self.protocol_version > ours.protocol_version {
+                "Please update Teamtype"
+            } else {
+                "The peer should update Teamtype"
+            };
+            bail!(
+                "Peer {node_id} runs Teamtype {} (protocol version {}), which can't sync with Teamtype {} (protocol version {}). {who_should_update}.",
+                self.daemon_version,
+                self.protocol_version,
+                ours.daemon_version,
+                ours.protocol_version
+            );
+        }
+        Ok(ours
+            .features
+            .into_iter()
+            .filter(|feature| self.features.contains(feature))
+            .collect())
+    }
+}
+
+/// The peer's history has a different identity than ours, so they have nothing to do with each
+/// other, and syncing them would mix two shared directories.
+#[derive(Debug)]
+pub struct UnrelatedHistory {
+    pub node_id: String,
+}
+
+impl fmt::Display for UnrelatedHistory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The history of peer {} is unrelated to ours, so we don't sync with it",
+            self.node_id
+        )
+    }
+}
+
+impl std::error::Error for UnrelatedHistory {}
+
+/// Sends our [`Hello`] to the peer, and checks its own. Returns the features both of us support,
+/// and the identity of the peer's history.
+pub async fn greet(
+    connection: &mut dyn Connection<PeerMessage>,
+    node_id: &str,
+    document_id: Option<String>,
+) -> Result<(Vec<String>, Option<String>)> {
+    connection
+        .send(PeerMessage::Hello(Hello::ours(document_id)))
+        .await?;
+    match connection.next().await? {
+        PeerMessage::Hello(hello) => {
+            let features = hello.negotiate(node_id)?;
+            debug!(
+                "Peer {node_id} runs Teamtype {}, using features {features:?}.",
+                hello.daemon_version
+            );
+            Ok((features, hello.document_id))
+        }
+        _ => bail!("Peer {node_id} runs an older version of Teamtype, which can't sync with ours. The peer should update Teamtype."),
+    }
+}
 
 #[derive(Deserialize, Serialize)]
 /// The `PeerMessage` is used for peer to peer data exchange.
+///
+/// New variants have to be appended, and may only be sent to peers which support the feature
+/// they belong to, see [`Hello`].
 pub enum PeerMessage {
     /// The Sync message contains the changes to the CRDT
     Sync(Vec<u8>),
     /// The Ephemeral message currently is used for cursor messages, but can later be used for
     /// other things that should not be persisted.
     Ephemeral(EphemeralMessage),
+    /// Tells a peer which connected to us that it only has read-only access.
+    ReadOnly,
+    /// Tells a peer why we're about to close the connection, because it sent changes even though
+    /// it only has read-only access.
+    Rejected(String),
+    /// Tells a peer with full access the addresses of the other session members we're connected
+    /// to, and our own, so that it can trust them and connect to them directly. The addresses
+    /// contain node IDs and network addresses, but never passphrases.
+    Members(Vec<String>),
+    /// See [`Hello`].
+    Hello(Hello),
 }
 
 #[async_trait]
@@ -36,17 +151,28 @@ pub struct SyncActor {
     peer_state: SyncState,
     document_handle: DocumentActorHandle,
     connection: Box<dyn Connection<PeerMessage>>,
+    node_id: String,
+    /// What the peer may do. If it only has read-only access, we don't accept its changes.
+    access: Access,
+    /// The optional features both of us support.
+    features: Vec<String>,
 }
 
 impl SyncActor {
     pub fn new(
         document_handle: DocumentActorHandle,
         connection: Box<dyn Connection<PeerMessage>>,
+        node_id: String,
+        access: Access,
+        features: Vec<String>,
     ) -> Self {
         Self {
             peer_state: SyncState::new(),
             document_handle,
             connection,
+            node_id,
+            access,
+            features,
         }
     }
 
@@ -55,6 +181,20 @@ impl SyncActor {
         match message {
             PeerMessage::Sync(message_buf) => {
                 let message = AutomergeSyncMessage::decode(&message_buf)?;
+                // The rest of the sync message (our heads, which changes it needs, ...) is fine,
+                // that's how the peer gets our changes.
+                if self.access == Access::ReadOnly && !message.changes.is_empty() {
+                    let _ = self
+                        .connection
+                        .send(PeerMessage::Rejected(
+                            "You only have read-only access, so your changes were rejected. To watch again, remove .teamtype/doc, so that you start from a fresh copy.".to_string(),
+                        ))
+                        .await;
+                    bail!(
+                        "Peer {} only has read-only access, but sent changes",
+                        self.node_id
+                    );
+                }
                 self.document_handle
                     .send_message(DocMessage::ReceiveSyncMessage {
                         message,
@@ -71,6 +211,42 @@ impl SyncActor {
                     .send_message(DocMessage::ReceiveEphemeral(cursor))
                     .await;
             }
+            PeerMessage::ReadOnly => {
+                self.document_handle
+                    .send_message(DocMessage::ReadOnlyAccess(self.node_id.clone()))
+                    .await;
+            }
+            PeerMessage::Rejected(reason) => {
+                error!("Peer {} rejected our changes: {reason}", self.node_id);
+                bail!("Peer {} rejected our changes", self.node_id);
+            }
+            PeerMessage::Members(addresses) => {
+                self.document_handle
+                    .send_message(DocMessage::PeerMembers {
+                        node_id: self.node_id.clone(),
+                        addresses,
+                    })
+                    .await;
+            }
+            PeerMessage::Hello(_) => {
+                bail!("Peer {} said hello twice", self.node_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Advertises the session members to the peer, if it has full access, and knows what to do
+    /// with them. The peer itself is left out.
+    async fn send_members(&mut self, members: Vec<String>) -> Result<()> {
+        if self.access != Access::Full || !self.features.iter().any(|f| f == FEATURE_MEMBERS) {
+            return Ok(());
+        }
+        let members: Vec<String> = members
+            .into_iter()
+            .filter(|address| super::member_node_id(address) != self.node_id)
+            .collect();
+        if !members.is_empty() {
+            self.connection.send(PeerMessage::Members(members)).await?;
         }
         Ok(())
     }
@@ -100,10 +276,24 @@ impl SyncActor {
     pub async fn run(mut self) -> Result<()> {
         let mut doc_changed_ping_rx = self.document_handle.subscribe_document_changes();
         let mut ephemeral_messages_rx = self.document_handle.subscribe_ephemeral_messages();
+        let mut members_rx = self.document_handle.subscribe_members();
+
+        if self.access == Access::ReadOnly {
+            self.connection.send(PeerMessage::ReadOnly).await?;
+        }
 
         // Kick off initial synchronization with peer.
         self.generate_sync_message().await?;
 
+        let (response_tx, response_rx) = oneshot::channel();
+        self.document_handle
+            .send_message(DocMessage::GetMembers { response_tx })
+            .await;
+        let members = response_rx
+            .await
+            .context("Could not read response from Document channel")?;
+        self.send_members(members).await?;
+
         loop {
             tokio::select! {
                 // As doc_changed_ping_rx is a broadcast channel our understanding is,
@@ -137,6 +327,18 @@ impl SyncActor {
                         }
                     }
                 }
+                members = members_rx.recv() => {
+                    match members {
+                        Ok(members) => { self.send_members(members).await?; }
+                        Err(broadcast::error::RecvError::Closed) => {
+                            panic!("Members channel has been closed");
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => {
+                            // Only the most recent list matters, and we'll receive it next.
+                            debug!("Members channel lagged (this is fine).");
+                        }
+                    }
+                }
                 message = self.connection.next() => {
                     self.receive_peer_message(message?).await?;
                 }
@@ -144,3 +346,53 @@ impl SyncActor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_common_features() {
+        let mut hello = Hello::ours(None);
+        hello.features = vec!["teleport".to_string(), FEATURE_MEMBERS.to_string()];
+        assert_eq!(hello.negotiate("abc").unwrap(), vec![FEATURE_MEMBERS]);
+
+        hello.features = vec![];
+        assert!(hello.negotiate("abc").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_other_protocol_versions() {
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            daemon_version: "99.0.0".to_string(),
+            features: vec![],
+            document_id: None,
+        };
+        let err = hello.negotiate("abc").unwrap_err().to_string();
+        assert!(err.contains("Teamtype 99.0.0"));
+        assert!(err.contains("Please update Teamtype"));
+    }
+
+    #[test]
+    fn hellos_can_be_extended() {
+        #[derive(Serialize)]
+        struct FutureHello {
+            protocol_version: u32,
+            daemon_version: String,
+            features: Vec<String>,
+            document_id: Option<String>,
+            favorite_color: String,
+        }
+        let bytes = postcard::to_allocvec(&FutureHello {
+            protocol_version: PROTOCOL_VERSION,
+            daemon_version: "1.0.0".to_string(),
+            features: vec![],
+            document_id: None,
+            favorite_color: "teal".to_string(),
+        })
+        .unwrap();
+        let hello: Hello = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(hello.daemon_version, "1.0.0");
+    }
+}
@@ -0,0 +1,399 @@
+// SPDX-FileCopyrightText: 2025 blinry <mail@blinry.org>
+// SPDX-FileCopyrightText: 2025 zormit <nt4u@kpvn.de>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! A transport over plain TCP, for networks where iroh's relays and discovery service can't be
+//! reached.
+//!
+//! Connections are encrypted and authenticated with the Noise protocol (`Noise_IK`). The static
+//! keys are the daemons' iroh keys, converted to X25519, so that a daemon proves that it owns its
+//! node ID, like with iroh. The connecting daemon knows the other's node ID from the secret
+//! address, and sends its own node ID and the passphrase in the first handshake message. The other
+//! daemon only answers once it has let the peer in.
+//!
+//! Afterwards, each [`PeerMessage`] is sent as its length and its bytes, split into Noise
+//! messages. On the wire, each Noise message is prefixed with its own length.
+
+use super::sync::{Connection, PeerMessage};
+use super::SecretAddress;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use iroh::{NodeId, SecretKey};
+use postcard::{from_bytes, to_allocvec};
+use snow::{HandshakeState, StatelessTransportState};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+pub const SCHEME: &str = "tcp://";
+
+const NOISE_PARAMS: &str = "Noise_IK_25519_ChaChaPoly_SHA256";
+/// Both sides have to agree on this, so it also serves as a version of this transport.
+const PROLOGUE: &[u8] = b"teamtype tcp 0";
+const MAX_NOISE_MESSAGE_LENGTH: usize = 65535;
+const TAG_LENGTH: usize = 16;
+/// How long a peer which connected to us may take to send the first handshake message.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to reach a daemon over TCP: `tcp://<host>:<port>#<node ID>#<passphrase>`.
+pub struct TcpAddress {
+    /// The host and port, like `192.168.1.23:4000`.
+    pub address: String,
+    pub secret_address: SecretAddress,
+}
+
+impl FromStr for TcpAddress {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((address, secret_address)) =
+            s.strip_prefix(SCHEME).and_then(|rest| rest.split_once('#'))
+        else {
+            bail!("TCP peer string must have format tcp://<host>:<port>#<node_id>#<passphrase>");
+        };
+
+        Ok(Self {
+            address: address.to_string(),
+            secret_address: SecretAddress::from_str(secret_address)?,
+        })
+    }
+}
+
+/// Our iroh secret key, as an X25519 private key.
+fn private_key(secret_key: &SecretKey) -> [u8; 32] {
+    secret_key.secret().to_scalar_bytes()
+}
+
+/// The node ID, as an X25519 public key.
+fn public_key(node_id: &NodeId) -> [u8; 32] {
+    node_id.public().to_montgomery().to_bytes()
+}
+
+async fn write_frame(writer: &mut (impl AsyncWrite + Unpin), bytes: &[u8]) -> Result<()> {
+    let length = u16::try_from(bytes.len()).context("Noise message is too long")?;
+    writer.write_all(&length.to_be_bytes()).await?;
+    writer.write_all(bytes).await?;
+    Ok(())
+}
+
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>> {
+    let mut length = [0; 2];
+    reader.read_exact(&mut length).await?;
+    let mut bytes = vec![0; u16::from_be_bytes(length).into()];
+    reader.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+/// Authenticates to the daemon with `secret_address`, which we connected to via `stream`.
+/// Completes once the daemon lets us in.
+pub async fn connect(
+    mut stream: TcpStream,
+    secret_address: &SecretAddress,
+    secret_key: &SecretKey,
+) -> Result<TcpConnection> {
+    let private_key = private_key(secret_key);
+    let remote_public_key = public_key(&secret_address.node_addr.node_id);
+    let mut handshake = snow::Builder::new(NOISE_PARAMS.parse()?)
+        .prologue(PROLOGUE)
+        .local_private_key(&private_key)
+        .remote_public_key(&remote_public_key)
+        .build_initiator()?;
+
+    let payload = [
+        secret_key.public().as_bytes().as_slice(),
+        &secret_address.passphrase.to_bytes(),
+    ]
+    .concat();
+    let mut message = vec![0; MAX_NOISE_MESSAGE_LENGTH];
+    let length = handshake.write_message(&payload, &mut message)?;
+    write_frame(&mut stream, &message[..length]).await?;
+
+    let answer = read_frame(&mut stream)
+        .await
+        .context("Peer closed the connection during the handshake. Did it reject us?")?;
+    handshake.read_message(&answer, &mut message)?;
+
+    TcpConnection::new(stream, handshake)
+}
+
+/// A peer which connected to us, and sent the first handshake message. It doesn't get an answer
+/// until we [`finish`](Self::finish) the handshake.
+pub struct IncomingTcpConnection {
+    stream: TcpStream,
+    handshake: HandshakeState,
+    /// The node ID the peer proved to own.
+    pub node_id: NodeId,
+    /// The passphrase the peer sent.
+    pub passphrase: [u8; 32],
+}
+
+impl IncomingTcpConnection {
+    pub async fn accept(mut stream: TcpStream, secret_key: &SecretKey) -> Result<Self> {
+        let private_key = private_key(secret_key);
+        let mut handshake = snow::Builder::new(NOISE_PARAMS.parse()?)
+            .prologue(PROLOGUE)
+            .local_private_key(&private_key)
+            .build_responder()?;
+
+        let message = timeout(HANDSHAKE_TIMEOUT, read_frame(&mut stream))
+            .await
+            .context("Peer took too long to start the handshake")??;
+        let mut payload = vec![0; MAX_NOISE_MESSAGE_LENGTH];
+        let length = handshake.read_message(&message, &mut payload)?;
+        let Some((node_id, passphrase)) = payload[..length].split_first_chunk::<32>() else {
+            bail!("Peer sent an invalid handshake message");
+        };
+        let passphrase: [u8; 32] = passphrase
+            .try_into()
+            .context("Peer sent an invalid handshake message")?;
+
+        let node_id = NodeId::from_bytes(node_id)?;
+        let remote_static = handshake
+            .get_remote_static()
+            .context("Peer didn't send its static key")?;
+        if remote_static != public_key(&node_id) {
+            bail!("Peer sent a node ID which doesn't belong to its key");
+        }
+
+        Ok(Self {
+            stream,
+            handshake,
+            node_id,
+            passphrase,
+        })
+    }
+
+    /// Completes when the peer closes the connection. It doesn't send anything while it waits
+    /// for our answer.
+    pub async fn closed(&mut self) {
+        let _ = self.stream.read(&mut [0; 1]).await;
+    }
+
+    /// Lets the peer in.
+    pub async fn finish(mut self) -> Result<TcpConnection> {
+        let mut message = vec![0; MAX_NOISE_MESSAGE_LENGTH];
+        let length = self.handshake.write_message(&[], &mut message)?;
+        write_frame(&mut self.stream, &message[..length]).await?;
+
+        TcpConnection::new(self.stream, self.handshake)
+    }
+}
+
+// Sends/receives PeerMessages to/from a TCP connection, after the handshake.
+pub struct TcpConnection {
+    writer: OwnedWriteHalf,
+    transport: Arc<StatelessTransportState>,
+    /// The nonce of the next Noise message we send.
+    nonce: u64,
+    message_rx: mpsc::Receiver<Result<PeerMessage>>,
+    /// Cancelled when the connection is closed.
+    closed: CancellationToken,
+}
+
+impl TcpConnection {
+    fn new(stream: TcpStream, handshake: HandshakeState) -> Result<Self> {
+        let transport = Arc::new(handshake.into_stateless_transport_mode()?);
+        let (reader, writer) = stream.into_split();
+        let closed = CancellationToken::new();
+
+        let (message_tx, message_rx) = mpsc::channel(1);
+
+        let reader_transport = transport.clone();
+        let reader_closed = closed.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = Self::read_loop(reader, &reader_transport, message_tx) => {}
+                () = reader_closed.cancelled() => {}
+            }
+        });
+
+        Ok(Self {
+            writer,
+            transport,
+            nonce: 0,
+            message_rx,
+            closed,
+        })
+    }
+
+    /// A token which closes the connection when it's cancelled.
+    pub fn closer(&self) -> CancellationToken {
+        self.closed.clone()
+    }
+
+    async fn read_loop(
+        mut reader: OwnedReadHalf,
+        transport: &StatelessTransportState,
+        message_tx: mpsc::Sender<Result<PeerMessage>>,
+    ) -> Result<()> {
+        let mut nonce = 0;
+        loop {
+            let result = Self::read_next(&mut reader, transport, &mut nonce).await;
+
+            message_tx.send(result).await?;
+        }
+    }
+
+    async fn read_next(
+        reader: &mut OwnedReadHalf,
+        transport: &StatelessTransportState,
+        nonce: &mut u64,
+    ) -> Result<PeerMessage> {
+        let mut bytes = Self::read_chunk(reader, transport, nonce).await?;
+        let byte_count = bytes
+            .first_chunk::<4>()
+            .map(|length| u32::from_be_bytes(*length) as usize)
+            .context("Peer sent a message without a length")?;
+        bytes.drain(..4);
+
+        while bytes.len() < byte_count {
+            bytes.extend(Self::read_chunk(reader, transport, nonce).await?);
+        }
+        if bytes.len() != byte_count {
+            bail!("Peer sent a message which is longer than announced");
+        }
+        from_bytes(&bytes)
+            .context("Failed to convert bytes to PeerMessage (does the peer run a newer Teamtype?)")
+    }
+
+    async fn read_chunk(
+        reader: &mut OwnedReadHalf,
+        transport: &StatelessTransportState,
+        nonce: &mut u64,
+    ) -> Result<Vec<u8>> {
+        let message = read_frame(reader).await?;
+        let mut chunk = vec![0; message.len()];
+        let length = transport
+            .read_message(*nonce, &message, &mut chunk)
+            .context("Failed to decrypt message from peer")?;
+        *nonce += 1;
+        chunk.truncate(length);
+        Ok(chunk)
+    }
+}
+
+impl Drop for TcpConnection {
+    fn drop(&mut self) {
+        self.closed.cancel();
+    }
+}
+
+#[async_trait]
+impl Connection<PeerMessage> for TcpConnection {
+    async fn send(&mut self, message: PeerMessage) -> Result<()> {
+        let bytes: Vec<u8> =
+            to_allocvec(&message).context("Failed to convert PeerMessage to bytes")?;
+        let byte_count =
+            u32::try_from(bytes.len()).expect("Converting a length to u32 should work");
+        let bytes = [byte_count.to_be_bytes().as_slice(), &bytes].concat();
+
+        let mut message = vec![0; MAX_NOISE_MESSAGE_LENGTH];
+        for chunk in bytes.chunks(MAX_NOISE_MESSAGE_LENGTH - TAG_LENGTH) {
+            let length = self
+                .transport
+                .write_message(self.nonce, chunk, &mut message)?;
+            self.nonce += 1;
+            write_frame(&mut self.writer, &message[..length]).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn next(&mut self) -> Result<PeerMessage> {
+        tokio::select! {
+            message = self.message_rx.recv() => {
+                message.context("Failed to await next peer message")?
+            }
+            () = self.closed.cancelled() => bail!("Connection was closed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Connects to a daemon with `server_key` on localhost, which lets us in without checking
+    /// the passphrase, using a secret address with `node_id`.
+    async fn handshake(
+        server_key: &SecretKey,
+        node_id: NodeId,
+    ) -> Result<(TcpConnection, TcpConnection)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let passphrase = SecretKey::generate(rand::rngs::OsRng);
+        let address = TcpAddress::from_str(&format!(
+            "tcp://{}#{node_id}#{passphrase}",
+            listener.local_addr()?
+        ))?;
+        let client_key = SecretKey::generate(rand::rngs::OsRng);
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(&address.address).await?;
+            connect(stream, &address.secret_address, &client_key).await
+        });
+
+        let (stream, _) = listener.accept().await?;
+        let incoming = IncomingTcpConnection::accept(stream, server_key).await?;
+        assert_eq!(incoming.passphrase, passphrase.to_bytes());
+        let server = incoming.finish().await?;
+        Ok((client.await??, server))
+    }
+
+    #[tokio::test]
+    async fn exchanges_messages_over_localhost() {
+        let server_key = SecretKey::generate(rand::rngs::OsRng);
+        let (mut client, mut server) = handshake(&server_key, server_key.public()).await.unwrap();
+
+        // Larger than a single Noise message.
+        let large = vec![42; 3 * MAX_NOISE_MESSAGE_LENGTH];
+        client.send(PeerMessage::Sync(large.clone())).await.unwrap();
+        client.send(PeerMessage::ReadOnly).await.unwrap();
+        assert!(matches!(server.next().await.unwrap(), PeerMessage::Sync(bytes) if bytes == large));
+        assert!(matches!(
+            server.next().await.unwrap(),
+            PeerMessage::ReadOnly
+        ));
+
+        server
+            .send(PeerMessage::Rejected("bye".into()))
+            .await
+            .unwrap();
+        assert!(
+            matches!(client.next().await.unwrap(), PeerMessage::Rejected(reason) if reason == "bye")
+        );
+
+        drop(server);
+        assert!(client.next().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_with_the_wrong_node_id() {
+        let server_key = SecretKey::generate(rand::rngs::OsRng);
+        let other_node_id = SecretKey::generate(rand::rngs::OsRng).public();
+        assert!(handshake(&server_key, other_node_id).await.is_err());
+    }
+
+    #[test]
+    fn parses_tcp_addresses() {
+        let passphrase = SecretKey::generate(rand::rngs::OsRng);
+        let address = TcpAddress::from_str(&format!(
+            "tcp://192.168.1.23:4000#{}#{passphrase}",
+            passphrase.public()
+        ))
+        .unwrap();
+        assert_eq!(address.address, "192.168.1.23:4000");
+        assert_eq!(
+            address.secret_address.passphrase.to_bytes(),
+            passphrase.to_bytes()
+        );
+
+        assert!(TcpAddress::from_str("tcp://127.0.0.1:1").is_err());
+        assert!(TcpAddress::from_str(&format!("{}#{passphrase}", passphrase.public())).is_err());
+    }
+}
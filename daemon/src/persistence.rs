@@ -0,0 +1,361 @@
+// SPDX-FileCopyrightText: 2025 blinry <mail@blinry.org>
+// SPDX-FileCopyrightText: 2025 zormit <nt4u@kpvn.de>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Where the daemon keeps its history between runs.
+//!
+//! The daemon talks to a [`Persistence`] backend. Normally, that's [`FilePersistence`], which keeps
+//! the history in `.teamtype/doc`. [`MemoryPersistence`] keeps it in memory instead, for tests and
+//! for applications which embed the daemon.
+//!
+//! The file is a sequence of Automerge chunks: A full save, followed by the incremental saves
+//! appended since then. If the daemon crashes while appending, the last chunk can be incomplete,
+//! which would make the whole file unloadable. If the directory is encrypted, each chunk is sealed
+//! into a record of its own, see [`encryption`].
+use crate::encryption::{self, Cipher, Purpose};
+use crate::{config, document::Document, sandbox, timestamp};
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// Every Automerge chunk starts with these bytes.
+const MAGIC_BYTES: [u8; 4] = [0x85, 0x6f, 0x4a, 0x83];
+/// The magic bytes, a 4-byte checksum and a 1-byte chunk type. Then follows the length of the
+/// chunk's data, as an unsigned LEB128 number.
+const CHUNK_HEADER_PREFIX_LENGTH: usize = 9;
+
+/// A place to keep the history in. The history is an Automerge document, which is saved fully from
+/// time to time, and incrementally in between.
+pub trait Persistence: fmt::Display + Send {
+    /// Whether a history was persisted before.
+    fn exists(&self) -> Result<bool>;
+    /// Loads the persisted history.
+    fn load(&mut self) -> Result<Document>;
+    /// Replaces the persisted history with a full save.
+    fn save(&mut self, bytes: &[u8]) -> Result<()>;
+    /// Adds an incremental save to the persisted history.
+    fn append(&mut self, bytes: &[u8]) -> Result<()>;
+    /// How many bytes the persisted history takes up. Zero if there is none.
+    fn size(&self) -> Result<u64>;
+}
+
+/// Keeps the history in `.teamtype/doc`, encrypted if the directory has encryption enabled.
+pub struct FilePersistence {
+    base_dir: PathBuf,
+    cipher: Option<Cipher>,
+    /// Whether we may change the file, see [`FilePersistence::read_only`].
+    writable: bool,
+}
+
+impl FilePersistence {
+    /// Only use this while holding the pidfile (see [`crate::pidfile::acquire`]), so that no daemon
+    /// writes to the file at the same time. Fails if the directory is encrypted, and we can't get
+    /// the passphrase.
+    pub fn new(base_dir: &Path) -> Result<Self> {
+        Ok(Self {
+            base_dir: base_dir.to_path_buf(),
+            cipher: encryption::cipher(base_dir)?,
+            writable: true,
+        })
+    }
+
+    /// For reading the history while a daemon might be writing to it. Never changes the file: A
+    /// damaged end is only left out when loading, because it might be a chunk the daemon is still
+    /// appending.
+    pub fn read_only(base_dir: &Path) -> Result<Self> {
+        Ok(Self {
+            writable: false,
+            ..Self::new(base_dir)?
+        })
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        if !self.writable {
+            bail!("{self} was opened read-only");
+        }
+        Ok(())
+    }
+
+    fn seal(&self, bytes: &[u8]) -> Vec<u8> {
+        self.cipher.as_ref().map_or_else(
+            || bytes.to_vec(),
+            |cipher| cipher.seal(Purpose::Document, bytes),
+        )
+    }
+
+    fn file(&self) -> PathBuf {
+        persistence_file(&self.base_dir)
+    }
+}
+
+impl Persistence for FilePersistence {
+    fn exists(&self) -> Result<bool> {
+        sandbox::exists(&self.base_dir, &self.file())
+    }
+
+    /// If the end of the file is damaged, we load as many chunks as possible, and move the rest to
+    /// `.teamtype/doc.corrupt-<timestamp>` (unless we're read-only).
+    fn load(&mut self) -> Result<Document> {
+        let file = self.file();
+        let bytes = sandbox::read_file(&self.base_dir, &file)?;
+        let (document, valid_length) = match &self.cipher {
+            Some(cipher) => {
+                let (plaintext, valid_length) = cipher.open_records(Purpose::Document, &bytes);
+                (Document::try_load(&plaintext)?, valid_length)
+            }
+            None => load_longest_valid_prefix(&bytes),
+        };
+
+        if valid_length < bytes.len() && !self.writable {
+            warn!(
+                "The end of '{}' is damaged, or the daemon is writing to it right now. Loaded the intact part ({valid_length} of {} bytes).",
+                file.display(),
+                bytes.len()
+            );
+        } else if valid_length < bytes.len() {
+            let mut corrupt_file_name = file.as_os_str().to_owned();
+            corrupt_file_name.push(format!(".corrupt-{}", timestamp::now()));
+            let corrupt_file = PathBuf::from(corrupt_file_name);
+            sandbox::write_file(&self.base_dir, &corrupt_file, &bytes[valid_length..])?;
+            sandbox::write_file_atomically(&self.base_dir, &file, &bytes[..valid_length])?;
+            warn!(
+                "The end of '{}' was damaged, maybe because Teamtype crashed while saving. Loaded the intact part ({valid_length} of {} bytes), and moved the rest to '{}'.",
+                file.display(),
+                bytes.len(),
+                corrupt_file.display()
+            );
+        }
+        Ok(document)
+    }
+
+    fn save(&mut self, bytes: &[u8]) -> Result<()> {
+        self.ensure_writable()?;
+        // Write to a temporary file first, so that a crash can't leave half a save behind.
+        sandbox::write_file_atomically(&self.base_dir, &self.file(), &self.seal(bytes))
+            .with_context(|| format!("Failed to write '{}'", self.file().display()))
+    }
+
+    fn append(&mut self, bytes: &[u8]) -> Result<()> {
+        self.ensure_writable()?;
+        sandbox::append_file(&self.base_dir, &self.file(), &self.seal(bytes))
+            .with_context(|| format!("Failed to append to '{}'", self.file().display()))
+    }
+
+    fn size(&self) -> Result<u64> {
+        if self.exists()? {
+            sandbox::file_size(&self.base_dir, &self.file())
+        } else {
+            Ok(0)
+        }
+    }
+}
+
+impl fmt::Display for FilePersistence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}'", self.file().display())
+    }
+}
+
+/// Keeps the history in memory. Clones share the same history, so a clone can be handed to a new
+/// daemon to "restart" it, or be used to look at what was persisted.
+#[derive(Clone, Default)]
+pub struct MemoryPersistence {
+    bytes: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl MemoryPersistence {
+    /// Returns a copy of the persisted history, if there is one.
+    #[must_use]
+    pub fn bytes(&self) -> Option<Vec<u8>> {
+        self.bytes.lock().expect("Lock was poisoned").clone()
+    }
+}
+
+impl Persistence for MemoryPersistence {
+    fn exists(&self) -> Result<bool> {
+        Ok(self.bytes().is_some())
+    }
+
+    fn load(&mut self) -> Result<Document> {
+        let bytes = self
+            .bytes()
+            .context("There is no history in memory to load")?;
+        Document::try_load(&bytes)
+    }
+
+    fn save(&mut self, bytes: &[u8]) -> Result<()> {
+        *self.bytes.lock().expect("Lock was poisoned") = Some(bytes.to_vec());
+        Ok(())
+    }
+
+    fn append(&mut self, bytes: &[u8]) -> Result<()> {
+        self.bytes
+            .lock()
+            .expect("Lock was poisoned")
+            .get_or_insert_with(Vec::new)
+            .extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.bytes().map_or(0, |bytes| bytes.len() as u64))
+    }
+}
+
+impl fmt::Display for MemoryPersistence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "memory")
+    }
+}
+
+#[must_use]
+pub fn persistence_file(base_dir: &Path) -> PathBuf {
+    base_dir.join(config::CONFIG_DIR).join(config::DOC_FILE)
+}
+
+/// Loads the persisted history of `base_dir`, without changing the file. See
+/// [`FilePersistence::read_only`].
+pub fn load_document(base_dir: &Path) -> Result<Document> {
+    FilePersistence::read_only(base_dir)?.load()
+}
+
+/// Returns the document made from the longest prefix of chunks that can be loaded, and the length
+/// of that prefix.
+fn load_longest_valid_prefix(bytes: &[u8]) -> (Document, usize) {
+    if let Ok(document) = Document::try_load(bytes) {
+        return (document, bytes.len());
+    }
+    // Usually, only the last chunk is damaged, so start looking from the end.
+    for end in chunk_ends(bytes).into_iter().rev() {
+        if let Ok(document) = Document::try_load(&bytes[..end]) {
+            return (document, end);
+        }
+    }
+    (Document::default(), 0)
+}
+
+/// Returns where each of the complete chunks at the start of `bytes` ends.
+fn chunk_ends(bytes: &[u8]) -> Vec<usize> {
+    let mut ends = vec![];
+    let mut start = 0;
+    while let Some(end) = chunk_end(bytes, start) {
+        ends.push(end);
+        start = end;
+    }
+    ends
+}
+
+/// Returns where the chunk starting at `start` ends, if it is complete.
+fn chunk_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let header = bytes.get(start..start + CHUNK_HEADER_PREFIX_LENGTH)?;
+    if header[..MAGIC_BYTES.len()] != MAGIC_BYTES {
+        return None;
+    }
+
+    let mut position = start + CHUNK_HEADER_PREFIX_LENGTH;
+    let mut length: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes.get(position)?;
+        position += 1;
+        length |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift >= 64 {
+            return None;
+        }
+    }
+
+    let end = position.checked_add(usize::try_from(length).ok()?)?;
+    (end <= bytes.len()).then_some(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::RelativePath;
+    use temp_dir::TempDir;
+
+    /// Returns a full save followed by two incremental saves, and the length of the full save.
+    fn persisted_bytes() -> (Vec<u8>, usize) {
+        let mut document = Document::default();
+        let file_path = RelativePath::new("file");
+        document.update_text("hello", &file_path);
+        let mut bytes = document.save();
+        let full_save_length = bytes.len();
+        document.update_text("hello world", &file_path);
+        bytes.extend(document.save_incremental());
+        document.update_text("hello world!", &file_path);
+        bytes.extend(document.save_incremental());
+        (bytes, full_save_length)
+    }
+
+    #[test]
+    fn finds_chunk_boundaries() {
+        let (bytes, full_save_length) = persisted_bytes();
+        let ends = chunk_ends(&bytes);
+        assert_eq!(ends.len(), 3);
+        assert_eq!(ends[0], full_save_length);
+        assert_eq!(ends[2], bytes.len());
+
+        assert_eq!(chunk_ends(&bytes[..bytes.len() - 1]).len(), 2);
+        assert!(chunk_ends(b"garbage").is_empty());
+    }
+
+    #[test]
+    fn recovers_from_truncated_last_chunk() {
+        let dir = TempDir::new().unwrap();
+        sandbox::create_dir(dir.path(), &dir.child(config::CONFIG_DIR)).unwrap();
+        let (bytes, _) = persisted_bytes();
+        let file = persistence_file(dir.path());
+        let truncated = &bytes[..bytes.len() - 3];
+        sandbox::write_file(dir.path(), &file, truncated).unwrap();
+
+        // Only reading leaves the file as it is.
+        let document = load_document(dir.path()).unwrap();
+        assert_eq!(
+            document
+                .current_file_content(&RelativePath::new("file"))
+                .unwrap(),
+            "hello world"
+        );
+        assert_eq!(sandbox::read_file(dir.path(), &file).unwrap(), truncated);
+
+        let document = FilePersistence::new(dir.path()).unwrap().load().unwrap();
+        assert_eq!(
+            document
+                .current_file_content(&RelativePath::new("file"))
+                .unwrap(),
+            "hello world"
+        );
+
+        let valid_length = chunk_ends(&bytes)[1];
+        assert_eq!(
+            sandbox::read_file(dir.path(), &file).unwrap(),
+            &bytes[..valid_length]
+        );
+        let corrupt_files: Vec<_> = std::fs::read_dir(dir.child(config::CONFIG_DIR))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("doc.corrupt-"))
+            .collect();
+        assert_eq!(corrupt_files.len(), 1);
+    }
+
+    #[test]
+    fn loads_intact_files_without_changes() {
+        let dir = TempDir::new().unwrap();
+        sandbox::create_dir(dir.path(), &dir.child(config::CONFIG_DIR)).unwrap();
+        let (bytes, _) = persisted_bytes();
+        let file = persistence_file(dir.path());
+        sandbox::write_file(dir.path(), &file, &bytes).unwrap();
+
+        let _ = load_document(dir.path()).unwrap();
+        assert_eq!(sandbox::read_file(dir.path(), &file).unwrap(), bytes);
+    }
+}
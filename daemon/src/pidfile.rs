@@ -0,0 +1,201 @@
+// SPDX-FileCopyrightText: 2025 blinry <mail@blinry.org>
+// SPDX-FileCopyrightText: 2025 zormit <nt4u@kpvn.de>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Keeps track of which process runs the daemon for a directory, in `.teamtype/pid`.
+//!
+//! This prevents two daemons from working on the same directory, and lets `teamtype stop` and
+//! `teamtype share --detach` find out whether a daemon is running.
+use crate::config;
+use crate::sandbox;
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::sys::signal;
+use nix::unistd::Pid;
+use std::fs::File;
+use std::io::{Seek, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+fn pidfile_path(base_dir: &Path) -> PathBuf {
+    base_dir.join(config::CONFIG_DIR).join(config::PID_FILE)
+}
+
+/// Returns the PID of the daemon running in `base_dir`, if there is one.
+#[must_use]
+pub fn running_pid(base_dir: &Path) -> Option<u32> {
+    let pidfile = pidfile_path(base_dir);
+    if !sandbox::exists(base_dir, &pidfile).unwrap_or(false) {
+        return None;
+    }
+    let pid = sandbox::read_file(base_dir, &pidfile)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|content| content.trim().parse::<u32>().ok())?;
+    // If the PID is our own, the file was left behind by an earlier process which had the same
+    // PID, so it's stale, too.
+    if pid != std::process::id() && is_alive(pid) {
+        Some(pid)
+    } else {
+        None
+    }
+}
+
+/// Fails if a daemon is running in `base_dir` already.
+pub fn ensure_not_running(base_dir: &Path) -> Result<()> {
+    if let Some(pid) = running_pid(base_dir) {
+        bail!(
+            "A daemon is already running in '{}' (PID {pid}). You can stop it with `teamtype stop`.",
+            base_dir.display()
+        );
+    }
+    Ok(())
+}
+
+/// The daemon holds an exclusive lock on the pidfile as long as it runs, so that no other daemon
+/// can start in the same directory, not even at the same time. The pidfile is removed when this
+/// is dropped.
+#[derive(Debug)]
+pub struct Pidfile {
+    base_dir: PathBuf,
+    file: Flock<File>,
+}
+
+/// Makes the current process the daemon for `base_dir`. Fails if another daemon is running, or
+/// starting, already. Once we hold the lock, the pidfile is stale, even if its PID belongs to a
+/// running process (PIDs are reused), and it's overwritten.
+///
+/// Others only see that the daemon runs once it calls [`Pidfile::announce`].
+pub fn acquire(base_dir: &Path) -> Result<Pidfile> {
+    let path = pidfile_path(base_dir);
+    loop {
+        let file = sandbox::open_for_locking(base_dir, &path)?;
+        let file = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(file) => file,
+            Err((_, Errno::EWOULDBLOCK)) => {
+                ensure_not_running(base_dir)?;
+                bail!(
+                    "Another daemon is starting in '{}' right now.",
+                    base_dir.display()
+                );
+            }
+            Err((_, errno)) => return Err(errno).context("Failed to lock the pidfile"),
+        };
+
+        // If the previous daemon removed the pidfile after we opened it, we locked a file nobody
+        // else will look at. So we try again.
+        let locked = file.metadata()?;
+        let current = sandbox::metadata(base_dir, &path).ok();
+        if current
+            .is_some_and(|current| current.dev() == locked.dev() && current.ino() == locked.ino())
+        {
+            return Ok(Pidfile {
+                base_dir: base_dir.to_path_buf(),
+                file,
+            });
+        }
+    }
+}
+
+impl Pidfile {
+    /// Writes our PID into the pidfile, which tells others that the daemon is running.
+    pub fn announce(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        self.file
+            .write_all(std::process::id().to_string().as_bytes())?;
+        Ok(())
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        // We still hold the lock, so no other daemon uses the file yet.
+        debug!("Removing pidfile");
+        if let Err(err) = sandbox::remove_file(&self.base_dir, &pidfile_path(&self.base_dir)) {
+            debug!("Failed to remove pidfile: {err}");
+        }
+    }
+}
+
+fn is_alive(pid: u32) -> bool {
+    // Signal 0 doesn't do anything to the process, but tells us whether it exists. If we may not
+    // signal it, it exists, too.
+    matches!(
+        signal::kill(Pid::from_raw(pid as i32), None),
+        Ok(()) | Err(Errno::EPERM)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use temp_dir::TempDir;
+
+    fn setup() -> TempDir {
+        let dir = TempDir::new().expect("Failed to create temp directory");
+        sandbox::create_dir(dir.path(), &dir.child(config::CONFIG_DIR)).unwrap();
+        dir
+    }
+
+    fn write_pid(dir: &TempDir, pid: u32) {
+        sandbox::write_file(
+            dir.path(),
+            &pidfile_path(dir.path()),
+            pid.to_string().as_bytes(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn the_lock_decides_whether_a_daemon_runs() {
+        let dir = setup();
+        // Another process got the PID of a daemon which crashed.
+        let mut other = Command::new("sleep").arg("10").spawn().unwrap();
+        write_pid(&dir, other.id());
+        assert_eq!(running_pid(dir.path()), Some(other.id()));
+
+        let mut pidfile = acquire(dir.path()).unwrap();
+        pidfile.announce().unwrap();
+        assert_eq!(
+            sandbox::read_file(dir.path(), &pidfile_path(dir.path())).unwrap(),
+            std::process::id().to_string().as_bytes()
+        );
+
+        other.kill().unwrap();
+        other.wait().unwrap();
+    }
+
+    #[test]
+    fn ignores_stale_pidfiles() {
+        let dir = setup();
+        let mut exited = Command::new("true").spawn().unwrap();
+        exited.wait().unwrap();
+        write_pid(&dir, exited.id());
+
+        assert_eq!(running_pid(dir.path()), None);
+        let mut pidfile = acquire(dir.path()).unwrap();
+        pidfile.announce().unwrap();
+        drop(pidfile);
+        assert!(!pidfile_path(dir.path()).exists());
+    }
+
+    #[test]
+    fn refuses_to_start_while_another_daemon_starts() {
+        let dir = setup();
+        let mut pidfile = acquire(dir.path()).unwrap();
+        // Before the first daemon wrote its PID, only the lock keeps the second one out.
+        assert_eq!(running_pid(dir.path()), None);
+        assert!(acquire(dir.path()).is_err());
+
+        pidfile.announce().unwrap();
+        // Our own PID counts as stale, so we still only see the lock here.
+        assert!(acquire(dir.path()).is_err());
+        drop(pidfile);
+        assert!(acquire(dir.path()).is_ok());
+    }
+}
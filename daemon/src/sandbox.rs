@@ -11,10 +11,11 @@ use crate::config::AppConfig;
 use anyhow::{bail, Context, Result};
 use ignore::overrides::OverrideBuilder;
 use ignore::WalkBuilder;
+use nix::fcntl::{Flock, FlockArg};
 use path_clean::PathClean;
 use std::fs::{self, OpenOptions};
 use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 
 pub fn read_file(absolute_base_dir: &Path, absolute_file_path: &Path) -> Result<Vec<u8>> {
@@ -43,6 +44,67 @@ pub fn write_file(
     Ok(())
 }
 
+/// Replaces the content of a file in one step, by writing to a temporary file first and renaming
+/// it. This way, even after a crash, there's never a half-written file.
+pub fn write_file_atomically(
+    absolute_base_dir: &Path,
+    absolute_file_path: &Path,
+    content: &[u8],
+) -> Result<()> {
+    write_atomically_with_mode(absolute_base_dir, absolute_file_path, content, 0o666)
+}
+
+/// Like [`write_file_atomically`], but only the user can read and write the file. Use this for
+/// secrets, so that they're never readable by others, not even briefly.
+pub fn write_private_file_atomically(
+    absolute_base_dir: &Path,
+    absolute_file_path: &Path,
+    content: &[u8],
+) -> Result<()> {
+    write_atomically_with_mode(absolute_base_dir, absolute_file_path, content, 0o600)
+}
+
+fn write_atomically_with_mode(
+    absolute_base_dir: &Path,
+    absolute_file_path: &Path,
+    content: &[u8],
+    mode: u32,
+) -> Result<()> {
+    let mut temporary_file_path = absolute_file_path.as_os_str().to_owned();
+    temporary_file_path.push(".tmp");
+    let canonical_temporary_file_path =
+        check_inside_base_dir_and_canonicalize(absolute_base_dir, Path::new(&temporary_file_path))?;
+    let canonical_file_path =
+        check_inside_base_dir_and_canonicalize(absolute_base_dir, absolute_file_path)?;
+
+    // Start from scratch, so that the mode applies.
+    if canonical_temporary_file_path.exists() {
+        fs::remove_file(&canonical_temporary_file_path)?;
+    }
+    let mut file = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(mode)
+        .open(&canonical_temporary_file_path)?;
+    file.write_all(content)?;
+    // Make sure the content is on disk before the rename can be.
+    file.sync_all()?;
+    fs::rename(&canonical_temporary_file_path, &canonical_file_path)?;
+
+    // Also persist the rename itself.
+    let parent_dir = canonical_file_path
+        .parent()
+        .expect("Failed to get parent directory");
+    fs::File::open(parent_dir)?.sync_all()?;
+    Ok(())
+}
+
+pub fn file_size(absolute_base_dir: &Path, absolute_file_path: &Path) -> Result<u64> {
+    let canonical_file_path =
+        check_inside_base_dir_and_canonicalize(absolute_base_dir, absolute_file_path)?;
+    Ok(fs::metadata(canonical_file_path)?.len())
+}
+
 pub fn append_file(
     absolute_base_dir: &Path,
     absolute_file_path: &Path,
@@ -55,6 +117,43 @@ pub fn append_file(
     Ok(())
 }
 
+/// Opens a file for appending, creating it if it doesn't exist.
+pub fn open_for_appending(absolute_base_dir: &Path, absolute_file_path: &Path) -> Result<fs::File> {
+    let canonical_file_path =
+        check_inside_base_dir_and_canonicalize(absolute_base_dir, absolute_file_path)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(canonical_file_path)?;
+    Ok(file)
+}
+
+/// Opens a file for reading and writing, creating it if it doesn't exist, but without changing
+/// its content. Use this to take a lock on the file.
+pub fn open_for_locking(absolute_base_dir: &Path, absolute_file_path: &Path) -> Result<fs::File> {
+    let canonical_file_path =
+        check_inside_base_dir_and_canonicalize(absolute_base_dir, absolute_file_path)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(canonical_file_path)?;
+    Ok(file)
+}
+
+/// Takes an exclusive lock on a file, creating it if it doesn't exist, and waits until others have
+/// released theirs. The lock is held until the returned value is dropped.
+pub fn lock_exclusively(
+    absolute_base_dir: &Path,
+    absolute_file_path: &Path,
+) -> Result<Flock<fs::File>> {
+    let file = open_for_locking(absolute_base_dir, absolute_file_path)?;
+    Flock::lock(file, FlockArg::LockExclusive)
+        .map_err(|(_, errno)| errno)
+        .with_context(|| format!("Failed to lock '{}'", absolute_file_path.display()))
+}
+
 pub fn rename_file(
     absolute_base_dir: &Path,
     absolute_file_path_old: &Path,
@@ -84,6 +183,17 @@ pub fn create_dir(absolute_base_dir: &Path, absolute_dir_path: &Path) -> Result<
     Ok(())
 }
 
+pub fn set_permissions(
+    absolute_base_dir: &Path,
+    absolute_file_path: &Path,
+    mode: u32,
+) -> Result<()> {
+    let canonical_file_path =
+        check_inside_base_dir_and_canonicalize(absolute_base_dir, absolute_file_path)?;
+    fs::set_permissions(canonical_file_path, fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
 pub fn create_dir_all(absolute_base_dir: &Path, absolute_dir_path: &Path) -> Result<()> {
     let canonical_dir_path =
         check_inside_base_dir_and_canonicalize(absolute_base_dir, absolute_dir_path)?;
@@ -91,6 +201,12 @@ pub fn create_dir_all(absolute_base_dir: &Path, absolute_dir_path: &Path) -> Res
     Ok(())
 }
 
+pub fn metadata(absolute_base_dir: &Path, absolute_file_path: &Path) -> Result<fs::Metadata> {
+    let canonical_file_path =
+        check_inside_base_dir_and_canonicalize(absolute_base_dir, absolute_file_path)?;
+    Ok(fs::metadata(canonical_file_path)?)
+}
+
 pub fn exists(absolute_base_dir: &Path, absolute_file_path: &Path) -> Result<bool> {
     let canonical_file_path =
         check_inside_base_dir_and_canonicalize(absolute_base_dir, absolute_file_path)?;
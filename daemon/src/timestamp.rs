@@ -0,0 +1,139 @@
+// SPDX-FileCopyrightText: 2025 blinry <mail@blinry.org>
+// SPDX-FileCopyrightText: 2025 zormit <nt4u@kpvn.de>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Helpers for the Unix timestamps (in seconds) we show to users and store in the history.
+use anyhow::{bail, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+use time::{
+    format_description::well_known::Rfc3339, macros::format_description, Date, OffsetDateTime,
+    PrimitiveDateTime,
+};
+
+#[must_use]
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time should be after the Unix epoch")
+        .as_secs() as i64
+}
+
+/// Formats a timestamp in UTC, like "2025-10-29 13:37:00 UTC".
+#[must_use]
+pub fn format(timestamp: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(timestamp).map_or_else(
+        |_| format!("@{timestamp}"),
+        |date_time| {
+            let description =
+                format_description!("[year]-[month]-[day] [hour]:[minute]:[second] UTC");
+            date_time
+                .format(&description)
+                .expect("Failed to format timestamp")
+        },
+    )
+}
+
+/// Parses a point in time given by a user. This can be a duration that has passed since then, like
+/// "10m", "2h" or "3d", a date like "2025-10-29", a date and time in UTC like "2025-10-29 13:37",
+/// or an RFC 3339 timestamp.
+pub fn parse(input: &str) -> Result<i64> {
+    parse_relative_to(input, now())
+}
+
+/// Parses a point in the future given by a user. Like [`parse`], but durations are counted from
+/// now into the future, so "7d" means in a week.
+pub fn parse_future(input: &str) -> Result<i64> {
+    parse_future_relative_to(input, now())
+}
+
+fn parse_future_relative_to(input: &str, now: i64) -> Result<i64> {
+    parse_duration(input.trim()).map_or_else(
+        || parse_relative_to(input, now),
+        |seconds| Ok(now + seconds),
+    )
+}
+
+fn parse_relative_to(input: &str, now: i64) -> Result<i64> {
+    let input = input.trim();
+    if let Some(seconds) = parse_duration(input) {
+        return Ok(now - seconds);
+    }
+    if let Ok(date_time) = OffsetDateTime::parse(input, &Rfc3339) {
+        return Ok(date_time.unix_timestamp());
+    }
+    for description in [
+        format_description!("[year]-[month]-[day] [hour]:[minute]:[second]"),
+        format_description!("[year]-[month]-[day] [hour]:[minute]"),
+    ] {
+        if let Ok(date_time) = PrimitiveDateTime::parse(input, description) {
+            return Ok(date_time.assume_utc().unix_timestamp());
+        }
+    }
+    if let Ok(date) = Date::parse(input, format_description!("[year]-[month]-[day]")) {
+        return Ok(date.midnight().assume_utc().unix_timestamp());
+    }
+    bail!(
+        "Could not understand the time '{input}'. Try something like '10m', '2h', '3d', '2025-10-29' or '2025-10-29 13:37'"
+    );
+}
+
+fn parse_duration(input: &str) -> Option<i64> {
+    let unit_start = input.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = input.split_at(unit_start);
+    let amount: i64 = amount.parse().ok()?;
+    let unit_seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    amount.checked_mul(unit_seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_761_745_020; // 2025-10-29 13:37:00 UTC
+
+    #[test]
+    fn formats_in_utc() {
+        assert_eq!(format(NOW), "2025-10-29 13:37:00 UTC");
+    }
+
+    #[test]
+    fn parses_durations_as_ago() {
+        assert_eq!(parse_relative_to("30s", NOW).unwrap(), NOW - 30);
+        assert_eq!(parse_relative_to("10m", NOW).unwrap(), NOW - 600);
+        assert_eq!(parse_relative_to("2h", NOW).unwrap(), NOW - 7200);
+        assert_eq!(parse_relative_to("1d", NOW).unwrap(), NOW - 86400);
+        assert_eq!(parse_future_relative_to("1d", NOW).unwrap(), NOW + 86400);
+        assert_eq!(
+            parse_future_relative_to("2025-10-29 13:37", NOW).unwrap(),
+            NOW
+        );
+    }
+
+    #[test]
+    fn parses_dates_and_times() {
+        assert_eq!(parse_relative_to("2025-10-29 13:37:00", NOW).unwrap(), NOW);
+        assert_eq!(parse_relative_to("2025-10-29 13:37", NOW).unwrap(), NOW);
+        assert_eq!(
+            parse_relative_to("2025-10-29T15:37:00+02:00", NOW).unwrap(),
+            NOW
+        );
+        assert_eq!(
+            parse_relative_to("2025-10-29", NOW).unwrap(),
+            NOW - (13 * 60 + 37) * 60
+        );
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(parse_relative_to("yesterday", NOW).is_err());
+        assert!(parse_relative_to("10 parsecs", NOW).is_err());
+    }
+}
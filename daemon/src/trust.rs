@@ -0,0 +1,396 @@
+// SPDX-FileCopyrightText: 2025 blinry <mail@blinry.org>
+// SPDX-FileCopyrightText: 2025 zormit <nt4u@kpvn.de>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Keeps track of which peers we trust, by their node ID, in `.teamtype/trust`.
+//!
+//! The QUIC handshake already proves that a peer owns its node ID. So trusted peers can connect
+//! without knowing our passphrase. A peer which knows the passphrase becomes trusted on its first
+//! connection ("trust on first use"). If it only knew the read-only passphrase, it's only trusted
+//! with read-only access. Revoked peers are remembered, so that they can't become trusted again
+//! that way, only with `teamtype trust add`. When the passphrase is rotated, the peers which
+//! became trusted because they knew the old one are revoked.
+//!
+//! Each line of the file has the form
+//! `<trusted|read-only|revoked> <node ID> <unix timestamp> <passphrase|invite|explicit>`.
+use crate::types::Access;
+use crate::{config, sandbox, timestamp};
+use anyhow::{bail, Context, Result};
+use nix::fcntl::Flock;
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr as _;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrustState {
+    Trusted,
+    /// Trusted, but only with read-only access.
+    ReadOnly,
+    Revoked,
+}
+
+impl TrustState {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Trusted => "trusted",
+            Self::ReadOnly => "read-only",
+            Self::Revoked => "revoked",
+        }
+    }
+
+    /// The access a peer in this state gets without the passphrase, if any.
+    #[must_use]
+    pub const fn access(self) -> Option<Access> {
+        match self {
+            Self::Trusted => Some(Access::Full),
+            Self::ReadOnly => Some(Access::ReadOnly),
+            Self::Revoked => None,
+        }
+    }
+
+    const fn trusting(access: Access) -> Self {
+        match access {
+            Access::Full => Self::Trusted,
+            Access::ReadOnly => Self::ReadOnly,
+        }
+    }
+}
+
+/// How a peer became trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// It knew our passphrase, or the read-only one.
+    Passphrase,
+    /// It joined with one of our invites.
+    Invite,
+    /// Someone decided to trust it, or we connected to it.
+    Explicit,
+}
+
+impl Origin {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Passphrase => "passphrase",
+            Self::Invite => "invite",
+            Self::Explicit => "explicit",
+        }
+    }
+}
+
+/// A peer we've made a decision about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub node_id: String,
+    pub state: TrustState,
+    /// When the state was last changed.
+    pub since: i64,
+    pub origin: Origin,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} since {}",
+            self.node_id,
+            self.state.as_str(),
+            timestamp::format(self.since)
+        )
+    }
+}
+
+fn trust_file(base_dir: &Path) -> PathBuf {
+    base_dir.join(config::CONFIG_DIR).join(config::TRUST_FILE)
+}
+
+/// The daemon's connections and the CLI change the trust file concurrently, so they take this lock
+/// around reading and rewriting it. The trust file itself is replaced atomically, so it can't be
+/// locked directly.
+fn lock(base_dir: &Path) -> Result<Flock<File>> {
+    let lock_file = base_dir
+        .join(config::CONFIG_DIR)
+        .join(format!("{}.lock", config::TRUST_FILE));
+    sandbox::lock_exclusively(base_dir, &lock_file)
+}
+
+/// Returns all peers we've made a decision about, in the order we first heard of them.
+pub fn peers(base_dir: &Path) -> Result<Vec<Peer>> {
+    let file = trust_file(base_dir);
+    if !sandbox::exists(base_dir, &file)? {
+        return Ok(vec![]);
+    }
+    let content = String::from_utf8(sandbox::read_file(base_dir, &file)?)?;
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            parse_line(line).with_context(|| format!("Invalid line in '{}'", file.display()))
+        })
+        .collect()
+}
+
+fn parse_line(line: &str) -> Result<Peer> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    let [state, node_id, since, origin] = parts[..] else {
+        bail!(
+            "Expected '<trusted|read-only|revoked> <node ID> <timestamp> <origin>', got '{line}'"
+        );
+    };
+    let state = match state {
+        "trusted" => TrustState::Trusted,
+        "read-only" => TrustState::ReadOnly,
+        "revoked" => TrustState::Revoked,
+        _ => bail!("Unknown state '{state}'"),
+    };
+    let origin = match origin {
+        "passphrase" => Origin::Passphrase,
+        "invite" => Origin::Invite,
+        "explicit" => Origin::Explicit,
+        _ => bail!("Unknown origin '{origin}'"),
+    };
+    Ok(Peer {
+        node_id: node_id.to_string(),
+        state,
+        since: since.parse()?,
+        origin,
+    })
+}
+
+fn write_peers(base_dir: &Path, peers: &[Peer]) -> Result<()> {
+    let mut content = String::new();
+    for peer in peers {
+        let _ = writeln!(
+            content,
+            "{} {} {} {}",
+            peer.state.as_str(),
+            peer.node_id,
+            peer.since,
+            peer.origin.as_str()
+        );
+    }
+    sandbox::write_file_atomically(base_dir, &trust_file(base_dir), content.as_bytes())
+}
+
+/// Returns what we decided about the peer with `node_id`, if anything.
+pub fn state(base_dir: &Path, node_id: &str) -> Result<Option<TrustState>> {
+    Ok(peers(base_dir)?
+        .into_iter()
+        .find(|peer| peer.node_id == node_id)
+        .map(|peer| peer.state))
+}
+
+/// Sets the state of the peer with `node_id`. Call this while holding the lock.
+fn set_state(base_dir: &Path, node_id: &str, state: TrustState, origin: Origin) -> Result<()> {
+    let mut peers = peers(base_dir)?;
+    let since = timestamp::now();
+    match peers.iter_mut().find(|peer| peer.node_id == node_id) {
+        Some(peer) => {
+            peer.state = state;
+            peer.since = since;
+            peer.origin = origin;
+        }
+        None => peers.push(Peer {
+            node_id: node_id.to_string(),
+            state,
+            since,
+            origin,
+        }),
+    }
+    write_peers(base_dir, &peers)
+}
+
+/// Trusts the peer with `node_id` with `access`, even if it was revoked before.
+pub fn trust(base_dir: &Path, node_id: &str, access: Access) -> Result<()> {
+    iroh::PublicKey::from_str(node_id).context("Not a valid node ID")?;
+    let _lock = lock(base_dir)?;
+    set_state(
+        base_dir,
+        node_id,
+        TrustState::trusting(access),
+        Origin::Explicit,
+    )
+}
+
+/// Trusts the peer with `node_id` with `access`, unless we've decided about it before. Returns
+/// whether it's newly trusted.
+pub fn trust_on_first_use(
+    base_dir: &Path,
+    node_id: &str,
+    access: Access,
+    origin: Origin,
+) -> Result<bool> {
+    let _lock = lock(base_dir)?;
+    if state(base_dir, node_id)?.is_some() {
+        return Ok(false);
+    }
+    set_state(base_dir, node_id, TrustState::trusting(access), origin)?;
+    Ok(true)
+}
+
+/// Revokes the trust in the peer whose node ID starts with `prefix`. Returns its full node ID.
+pub fn revoke(base_dir: &Path, prefix: &str) -> Result<String> {
+    let _lock = lock(base_dir)?;
+    let matching: Vec<Peer> = peers(base_dir)?
+        .into_iter()
+        .filter(|peer| peer.state != TrustState::Revoked && peer.node_id.starts_with(prefix))
+        .collect();
+    let peer = match &matching[..] {
+        [peer] => peer,
+        [] => bail!("There is no trusted peer with a node ID starting with '{prefix}'"),
+        _ => bail!("Several trusted peers have a node ID starting with '{prefix}'"),
+    };
+    set_state(base_dir, &peer.node_id, TrustState::Revoked, peer.origin)?;
+    Ok(peer.node_id.clone())
+}
+
+/// Revokes the trust in all peers which became trusted because they knew our passphrase. Call
+/// this when it's rotated, so that they have to know the new one. Returns their node IDs.
+pub fn revoke_passphrase_peers(base_dir: &Path) -> Result<Vec<String>> {
+    let _lock = lock(base_dir)?;
+    let mut peers = peers(base_dir)?;
+    let since = timestamp::now();
+    let mut revoked = vec![];
+    for peer in &mut peers {
+        if peer.state != TrustState::Revoked && peer.origin == Origin::Passphrase {
+            peer.state = TrustState::Revoked;
+            peer.since = since;
+            revoked.push(peer.node_id.clone());
+        }
+    }
+    if !revoked.is_empty() {
+        write_peers(base_dir, &peers)?;
+    }
+    Ok(revoked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    fn setup() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        sandbox::create_dir(dir.path(), &dir.child(config::CONFIG_DIR)).unwrap();
+        dir
+    }
+
+    fn node_id() -> String {
+        iroh::SecretKey::generate(rand::rngs::OsRng)
+            .public()
+            .to_string()
+    }
+
+    #[test]
+    fn trusts_on_first_use_only() {
+        let dir = setup();
+        let node_id = node_id();
+
+        assert_eq!(state(dir.path(), &node_id).unwrap(), None);
+        assert!(
+            trust_on_first_use(dir.path(), &node_id, Access::Full, Origin::Passphrase).unwrap()
+        );
+        assert!(
+            !trust_on_first_use(dir.path(), &node_id, Access::ReadOnly, Origin::Passphrase)
+                .unwrap()
+        );
+        assert_eq!(
+            state(dir.path(), &node_id).unwrap(),
+            Some(TrustState::Trusted)
+        );
+
+        assert_eq!(revoke(dir.path(), &node_id[..10]).unwrap(), node_id);
+        assert!(
+            !trust_on_first_use(dir.path(), &node_id, Access::Full, Origin::Passphrase).unwrap()
+        );
+        assert_eq!(
+            state(dir.path(), &node_id).unwrap(),
+            Some(TrustState::Revoked)
+        );
+
+        trust(dir.path(), &node_id, Access::Full).unwrap();
+        assert_eq!(
+            state(dir.path(), &node_id).unwrap(),
+            Some(TrustState::Trusted)
+        );
+        assert_eq!(peers(dir.path()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn concurrent_changes_are_all_kept() {
+        let dir = setup();
+        let node_ids: Vec<String> = std::iter::repeat_with(node_id).take(8).collect();
+        std::thread::scope(|scope| {
+            for node_id in &node_ids {
+                let base_dir = dir.path();
+                scope.spawn(move || {
+                    trust_on_first_use(base_dir, node_id, Access::Full, Origin::Invite).unwrap()
+                });
+            }
+        });
+        assert_eq!(peers(dir.path()).unwrap().len(), node_ids.len());
+    }
+
+    #[test]
+    fn rotating_revokes_only_passphrase_peers() {
+        let dir = setup();
+        let (by_passphrase, by_invite, explicit) = (node_id(), node_id(), node_id());
+        trust_on_first_use(
+            dir.path(),
+            &by_passphrase,
+            Access::ReadOnly,
+            Origin::Passphrase,
+        )
+        .unwrap();
+        trust_on_first_use(dir.path(), &by_invite, Access::Full, Origin::Invite).unwrap();
+        trust(dir.path(), &explicit, Access::Full).unwrap();
+
+        assert_eq!(
+            revoke_passphrase_peers(dir.path()).unwrap(),
+            vec![by_passphrase.clone()]
+        );
+        assert_eq!(
+            state(dir.path(), &by_passphrase).unwrap(),
+            Some(TrustState::Revoked)
+        );
+        assert_eq!(
+            state(dir.path(), &by_invite).unwrap(),
+            Some(TrustState::Trusted)
+        );
+        assert_eq!(
+            state(dir.path(), &explicit).unwrap(),
+            Some(TrustState::Trusted)
+        );
+        assert!(revoke_passphrase_peers(dir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn revokes_only_unambiguous_prefixes() {
+        let dir = setup();
+        trust(dir.path(), &node_id(), Access::Full).unwrap();
+        trust(dir.path(), &node_id(), Access::Full).unwrap();
+
+        assert!(revoke(dir.path(), "").is_err());
+        assert!(revoke(dir.path(), "not-a-node-id").is_err());
+        assert!(trust(dir.path(), "not-a-node-id", Access::Full).is_err());
+    }
+
+    #[test]
+    fn remembers_read_only_access() {
+        let dir = setup();
+        let node_id = node_id();
+
+        assert!(
+            trust_on_first_use(dir.path(), &node_id, Access::ReadOnly, Origin::Passphrase).unwrap()
+        );
+        assert_eq!(
+            state(dir.path(), &node_id).unwrap(),
+            Some(TrustState::ReadOnly)
+        );
+        assert_eq!(TrustState::ReadOnly.access(), Some(Access::ReadOnly));
+
+        assert_eq!(revoke(dir.path(), &node_id).unwrap(), node_id);
+        assert_eq!(TrustState::Revoked.access(), None);
+    }
+}
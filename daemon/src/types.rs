@@ -3,14 +3,14 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use crate::path::RelativePath;
+use crate::{path::RelativePath, timestamp};
 use anyhow::bail;
 use automerge::{patches::TextRepresentation, ConcreteTextValue, Patch, PatchAction, TextEncoding};
 use dissimilar::Chunk;
 use operational_transform::{Operation as OTOperation, OperationSeq};
 use ropey::Rope;
 use serde::{Deserialize, Serialize};
-use std::fmt;
+use std::{fmt, path::PathBuf};
 use tracing::{debug, warn};
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
@@ -135,6 +135,8 @@ pub enum PatchEffect {
 }
 
 impl PatchEffect {
+    /// Patches we don't understand, like ones to parts of the document which newer daemons added,
+    /// are skipped with a warning.
     pub fn from_crdt_patches(patches: Vec<Patch>) -> Vec<Self> {
         let mut file_deltas: Vec<Self> = vec![];
 
@@ -144,7 +146,7 @@ impl PatchEffect {
                     file_deltas.push(result);
                 }
                 Err(e) => {
-                    panic!("Failed to convert patch to delta: {e:#?}");
+                    warn!("Ignoring a change we don't understand (does a peer run a newer Teamtype?): {e}");
                 }
             }
         }
@@ -192,6 +194,43 @@ pub enum EditorProtocolMessageFromEditor {
         uri: DocumentUri,
         ranges: Vec<Range>,
     },
+    /// Asks the daemon for a [`DaemonStatus`]. Used by `teamtype status`.
+    Status,
+    /// Asks the daemon who last changed each line of a file. The result is a list of
+    /// [`BlameLine`]s. Used by `teamtype blame`, and for inline annotations in editors.
+    Blame {
+        uri: DocumentUri,
+    },
+    /// Asks the daemon to restore a file to its content at an earlier point in history. `at` is a
+    /// change hash or a time. Used by `teamtype restore`.
+    Restore {
+        uri: DocumentUri,
+        at: String,
+    },
+    /// Asks the daemon to persist the history and shut down. Used by `teamtype stop`.
+    Shutdown,
+    /// Checks whether the daemon is responsive. The result is "pong".
+    Ping,
+    /// Asks the daemon to rewrite `.teamtype/doc` as a single compacted save. The result is a
+    /// [`CompactionResult`]. Used by `teamtype gc`.
+    Compact,
+    /// Asks the daemon to generate a new passphrase, and to disconnect the peers which used the
+    /// old one. The result is the new secret address. Used by `teamtype rotate-secret`.
+    RotateSecret,
+    /// Asks the daemon to close the connection of a peer which connected to it. `node_id` can be
+    /// a unique prefix. The result is the full node ID. Used by `teamtype disconnect`.
+    #[serde(rename_all = "camelCase")]
+    Disconnect {
+        node_id: String,
+    },
+    /// Decides about a peer which waits for approval, see
+    /// [`EditorProtocolMessageToEditor::PeerWaitingForApproval`]. `node_id` can be a unique prefix.
+    /// The result is the full node ID. Used by `teamtype approve` and `teamtype reject`.
+    #[serde(rename_all = "camelCase")]
+    DecideOnPeer {
+        node_id: String,
+        decision: PeerDecision,
+    },
 }
 
 /// These messages are "internally" passed between the components that the daemon consists of -
@@ -222,6 +261,239 @@ pub struct EditorProtocolMessageError {
     pub data: Option<String>,
 }
 
+/// A snapshot of what a running daemon is doing, as reported by `teamtype status`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DaemonStatus {
+    pub base_dir: PathBuf,
+    pub peers: Vec<PeerStatus>,
+    /// The peers we connect to, also while we can't reach them.
+    pub outgoing_peers: Vec<OutgoingPeerStatus>,
+    pub editors: Vec<EditorStatus>,
+    pub file_count: usize,
+    pub heads: Vec<String>,
+    pub persistence: PersistenceStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PeerDirection {
+    /// The peer connected to us.
+    Incoming,
+    /// We connected to the peer.
+    Outgoing,
+}
+
+/// What a peer may do with the shared directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Access {
+    /// The peer can make changes.
+    #[default]
+    Full,
+    /// The peer receives changes and cursors, and sends its cursors, but its changes are rejected.
+    ReadOnly,
+}
+
+/// What to do with a peer which waits for approval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PeerDecision {
+    /// Let it connect this time.
+    Accept,
+    /// Close its connection.
+    Reject,
+    /// Let it connect, and trust it from now on, so that it won't need approval again.
+    AlwaysTrust,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerStatus {
+    pub node_id: String,
+    pub direction: PeerDirection,
+    /// For incoming peers, what they may do. For outgoing peers, what they let us do.
+    pub access: Access,
+    pub connected_since: i64,
+}
+
+/// How far we got in reaching a peer we connect to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "camelCase")]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    /// Connecting failed, or the connection was lost. We try again at `next_attempt` (a Unix
+    /// timestamp), or right away when the network changes.
+    #[serde(rename_all = "camelCase")]
+    BackingOff {
+        attempts: u32,
+        next_attempt: i64,
+    },
+    /// We've been failing to reach the peer for too long, and stopped trying.
+    GaveUp,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Connecting => write!(f, "connecting"),
+            Self::Connected => write!(f, "connected"),
+            Self::BackingOff {
+                attempts,
+                next_attempt,
+            } => write!(
+                f,
+                "unreachable, attempt {} at {}",
+                attempts + 1,
+                timestamp::format(*next_attempt)
+            ),
+            Self::GaveUp => write!(f, "gave up"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutgoingPeerStatus {
+    pub node_id: String,
+    #[serde(flatten)]
+    pub state: ConnectionState,
+    /// When the peer got into this state.
+    pub since: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EditorStatus {
+    pub id: usize,
+    pub username: Option<String>,
+    /// The files this editor has opened, and thus owns.
+    pub open_files: Vec<RelativePath>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersistenceStatus {
+    /// Whether the history is saved to .teamtype/doc at all.
+    pub enabled: bool,
+    pub last_persisted: Option<i64>,
+    /// Whether all changes to the document have been persisted.
+    pub up_to_date: bool,
+}
+
+impl fmt::Display for DaemonStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Daemon running for {}", self.base_dir.display())?;
+
+        writeln!(f, "\nPeers ({}):", self.peers.len())?;
+        for peer in &self.peers {
+            let direction = match peer.direction {
+                PeerDirection::Incoming => "incoming",
+                PeerDirection::Outgoing => "outgoing",
+            };
+            let access = match peer.access {
+                Access::Full => "",
+                Access::ReadOnly => ", read-only",
+            };
+            writeln!(
+                f,
+                "  {} ({direction}{access}, since {})",
+                peer.node_id,
+                timestamp::format(peer.connected_since)
+            )?;
+        }
+
+        if !self.outgoing_peers.is_empty() {
+            writeln!(f, "\nPeers we connect to ({}):", self.outgoing_peers.len())?;
+            for peer in &self.outgoing_peers {
+                writeln!(
+                    f,
+                    "  {} ({}, since {})",
+                    peer.node_id,
+                    peer.state,
+                    timestamp::format(peer.since)
+                )?;
+            }
+        }
+
+        writeln!(f, "\nEditors ({}):", self.editors.len())?;
+        for editor in &self.editors {
+            let name = editor.username.as_deref().unwrap_or("anonymous");
+            writeln!(f, "  #{} ({name})", editor.id)?;
+            for file_path in &editor.open_files {
+                writeln!(f, "    {file_path}")?;
+            }
+        }
+
+        writeln!(f, "\nDocument:")?;
+        writeln!(f, "  files: {}", self.file_count)?;
+        writeln!(f, "  heads: {}", self.heads.join(", "))?;
+
+        write!(f, "  persistence: ")?;
+        if self.persistence.enabled {
+            let last_persisted = self
+                .persistence
+                .last_persisted
+                .map_or_else(|| "never".to_string(), timestamp::format);
+            let state = if self.persistence.up_to_date {
+                "up to date"
+            } else {
+                "pending changes"
+            };
+            writeln!(f, "{state}, last saved {last_persisted}")
+        } else {
+            writeln!(f, "disabled")
+        }
+    }
+}
+
+/// How much compacting `.teamtype/doc` shrank it, as reported by `teamtype gc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompactionResult {
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+impl fmt::Display for CompactionResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "from {} to {}",
+            format_size(self.size_before),
+            format_size(self.size_after)
+        )
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{bytes} bytes");
+    }
+    #[expect(clippy::cast_precision_loss)] // only for display
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Who changed a line of a file last, as reported by `teamtype blame`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlameLine {
+    /// The hash of the change which last inserted text into this line.
+    pub hash: String,
+    pub actor: String,
+    pub author: Option<String>,
+    /// Unix timestamp in seconds. Zero if the change didn't record a time.
+    pub timestamp: i64,
+}
+
 #[cfg(test)]
 mod test_serde {
 
@@ -245,11 +517,66 @@ mod test_serde {
         );
     }
 
+    #[test]
+    fn status() {
+        let message =
+            JSONRPCFromEditor::from_jsonrpc(r#"{"jsonrpc":"2.0","id":1,"method":"status"}"#);
+        assert_eq!(
+            message.unwrap(),
+            JSONRPCFromEditor::Request {
+                id: 1,
+                payload: EditorProtocolMessageFromEditor::Status,
+            }
+        );
+    }
+
+    #[test]
+    fn status_result() {
+        let status = DaemonStatus {
+            base_dir: PathBuf::from("/tmp/dir"),
+            peers: vec![PeerStatus {
+                node_id: "abcd".to_string(),
+                direction: PeerDirection::Incoming,
+                access: Access::Full,
+                connected_since: 1,
+            }],
+            outgoing_peers: vec![],
+            editors: vec![EditorStatus {
+                id: 2,
+                username: None,
+                open_files: vec![RelativePath::new("file")],
+            }],
+            file_count: 1,
+            heads: vec![],
+            persistence: PersistenceStatus {
+                enabled: true,
+                last_persisted: Some(3),
+                up_to_date: false,
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&status).unwrap(),
+            r#"{"baseDir":"/tmp/dir","peers":[{"nodeId":"abcd","direction":"incoming","access":"full","connectedSince":1}],"outgoingPeers":[],"editors":[{"id":2,"username":null,"openFiles":["file"]}],"fileCount":1,"heads":[],"persistence":{"enabled":true,"lastPersisted":3,"upToDate":false}}"#
+        );
+    }
+
+    #[test]
+    fn compaction_result() {
+        let result = CompactionResult {
+            size_before: 2,
+            size_after: 1,
+        };
+        assert_eq!(
+            serde_json::to_string(&result).unwrap(),
+            r#"{"sizeBefore":2,"sizeAfter":1}"#
+        );
+    }
+
     #[test]
     fn success() {
         let message = EditorProtocolObject::Response(JSONRPCResponse::RequestSuccess {
             id: 1,
-            result: "success".to_string(),
+            result: "success".into(),
         });
         let jsonrpc = message.to_jsonrpc();
         assert_eq!(
@@ -258,6 +585,22 @@ mod test_serde {
         );
     }
 
+    #[test]
+    fn peer_state() {
+        let message = EditorProtocolObject::Request(EditorProtocolMessageToEditor::PeerState {
+            node_id: "abc".into(),
+            state: ConnectionState::BackingOff {
+                attempts: 2,
+                next_attempt: 1_700_000_000,
+            },
+        });
+        let jsonrpc = message.to_jsonrpc();
+        assert_eq!(
+            jsonrpc.unwrap(),
+            r#"{"jsonrpc":"2.0","method":"peerState","params":{"attempts":2,"nextAttempt":1700000000,"nodeId":"abc","state":"backingOff"}}"#
+        );
+    }
+
     #[test]
     fn error() {
         let message = EditorProtocolObject::Response(JSONRPCResponse::RequestError {
@@ -311,6 +654,21 @@ pub enum EditorProtocolMessageToEditor {
         uri: DocumentUri,
         ranges: Vec<Range>,
     },
+    /// The peer we joined only gave us read-only access, so the daemon rejects edits. Sent when
+    /// the editor connects, or when the daemon finds out.
+    ReadOnly,
+    /// A peer we don't know yet wants to connect, and the daemon waits for someone to decide about
+    /// it with a `decideOnPeer` request. Sent when the peer connects, or when the editor connects.
+    #[serde(rename_all = "camelCase")]
+    PeerWaitingForApproval { node_id: String, access: Access },
+    /// The state of the connection to a peer we connect to changed. Sent when it changes, or when
+    /// the editor connects.
+    #[serde(rename_all = "camelCase")]
+    PeerState {
+        node_id: String,
+        #[serde(flatten)]
+        state: ConnectionState,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -318,7 +676,7 @@ pub enum EditorProtocolMessageToEditor {
 pub enum JSONRPCResponse {
     RequestSuccess {
         id: usize,
-        result: String,
+        result: serde_json::Value,
     },
     RequestError {
         // id must be Null if there was an error detecting the id in the Request Object.
@@ -453,11 +811,13 @@ impl TryFrom<Patch> for PatchEffect {
         if patch.path.is_empty() {
             return match patch.action {
                 PatchAction::PutMap { key, .. } => {
-                    if key == "files" {
+                    // The "id" key holds the identity of the shared directory, see
+                    // Document::ensure_identity.
+                    if key == "files" || key == "states" || key == "id" {
                         Ok(Self::NoEffect)
                     } else {
                         Err(anyhow::anyhow!(
-                            "Path is empty and action is PutMap, but key is not 'files'",
+                            "Path is empty and action is PutMap, but key is not 'files', 'states' or 'id'",
                         ))
                     }
                 }
@@ -562,8 +922,10 @@ impl TryFrom<Patch> for PatchEffect {
                     ))
                 }
             }
+            // The "states" map holds the names of the authors, which don't affect any files.
+            (_, automerge::Prop::Map(key)) if key == "states" => Ok(Self::NoEffect),
             (_, _) => Err(anyhow::anyhow!(
-                "Unexpected path in Automerge patch, expected it to begin with 'files'"
+                "Unexpected path in Automerge patch, expected it to begin with 'files' or 'states'"
             )),
         }
     }
@@ -1278,4 +1640,13 @@ mod tests {
             .to_offset("h🥕llo\nwelt");
         }
     }
+
+    #[test]
+    fn format_sizes() {
+        assert_eq!(format_size(0), "0 bytes");
+        assert_eq!(format_size(1023), "1023 bytes");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024 * 1024), "3072.0 GiB");
+    }
 }
@@ -3,15 +3,20 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use crate::invite::JOIN_CODE_INVITE_LIFETIME;
+use crate::types::Access;
+use crate::{peer, timestamp};
 use anyhow::Result;
 use magic_wormhole::{transfer, AppID, Code, MailboxConnection, Wormhole};
+use std::path::{Path, PathBuf};
 use std::{str::FromStr, time::Duration};
 use tokio::time::sleep;
 use tracing::{error, info, warn};
 
-pub async fn put_secret_address_into_wormhole(address: &str) {
+/// Hands out invites via join codes, one after the other. Each join code hands out a new invite,
+/// which grants `access` to one peer, so the secret address itself is never revealed.
+pub async fn put_invites_into_wormhole(base_dir: PathBuf, access: Access) {
     let config = transfer::APP_CONFIG.id(AppID::new("teamtype"));
-    let payload: Vec<u8> = address.into();
 
     tokio::spawn(async move {
         loop {
@@ -23,13 +28,23 @@ pub async fn put_secret_address_into_wormhole(address: &str) {
             };
             let code = mailbox_connection.code().clone();
 
+            let purpose = match access {
+                Access::Full => "connect to you",
+                Access::ReadOnly => "watch, without being able to edit",
+            };
             info!(
-                "\n\tOne other person can use this to connect to you:\n\n\tteamtype join {}\n",
+                "\n\tOne other person can use this to {purpose}:\n\n\tteamtype join {}\n",
                 &code
             );
 
             if let Ok(mut wormhole) = Wormhole::connect(mailbox_connection).await {
-                let _ = wormhole.send(payload.clone()).await;
+                // Only create the invite now, so that unused join codes don't leave invites behind.
+                match create_join_code_invite(&base_dir, access) {
+                    Ok(secret_address) => {
+                        let _ = wormhole.send(secret_address.into_bytes()).await;
+                    }
+                    Err(err) => error!("Failed to create an invite for the join code: {err:#}"),
+                }
             } else {
                 warn!("Failed to share secret address. Did your peer mistype the join code?");
             }
@@ -41,6 +56,29 @@ pub async fn put_secret_address_into_wormhole(address: &str) {
     });
 }
 
+fn create_join_code_invite(base_dir: &Path, access: Access) -> Result<String> {
+    let (_, secret_address) = peer::create_invite(
+        base_dir,
+        access,
+        Some(timestamp::now() + JOIN_CODE_INVITE_LIFETIME),
+        Some(1),
+    )?;
+    Ok(secret_address)
+}
+
+/// Hands out `secret_address` via a single join code, and waits until someone used it.
+pub async fn put_secret_address_into_wormhole(secret_address: String) -> Result<()> {
+    let config = transfer::APP_CONFIG.id(AppID::new("teamtype"));
+    let mailbox_connection = MailboxConnection::create(config, 2).await?;
+    info!(
+        "\n\tOne other person can use this to join:\n\n\tteamtype join {}\n",
+        mailbox_connection.code()
+    );
+    let mut wormhole = Wormhole::connect(mailbox_connection).await?;
+    wormhole.send(secret_address.into_bytes()).await?;
+    Ok(())
+}
+
 pub async fn get_secret_address_from_wormhole(code: &str) -> Result<String> {
     let config = transfer::APP_CONFIG.id(AppID::new("teamtype"));
 
@@ -20,6 +20,8 @@ local configurations = {}
 -- root_dir: the root directory
 -- connection: a JSON-RPC connection
 -- buffers: list of attached buffers
+-- read_only: whether the daemon only has read-only access
+-- peer_states: the connection states of the peers the daemon connects to, by node ID
 local clients = {}
 
 function M.config(name, cfg)
@@ -66,6 +68,45 @@ local function process_operation_for_editor(client, method, parameters)
         end
     elseif method == "cursor" then
         cursor.set_cursor(parameters.uri, parameters.userid, parameters.name, parameters.ranges)
+    elseif method == "readOnly" then
+        -- The daemon only has read-only access, and rejects our edits.
+        client.read_only = true
+        for _, bufnr in ipairs(client.buffers) do
+            vim.bo[bufnr].modifiable = false
+        end
+        print("Teamtype: You only have read-only access to this shared directory.")
+    elseif method == "peerWaitingForApproval" then
+        -- A peer we don't know yet wants to connect, and waits until someone decides.
+        local node_id = parameters.nodeId
+        local prompt = "Teamtype: Let peer " .. node_id:sub(1, 10) .. " connect"
+        if parameters.access == "readOnly" then
+            prompt = prompt .. " (read-only)"
+        end
+        local choices = {
+            { label = "Accept once", decision = "accept" },
+            { label = "Always trust", decision = "alwaysTrust" },
+            { label = "Reject", decision = "reject" },
+        }
+        vim.schedule(function()
+            vim.ui.select(choices, {
+                prompt = prompt .. "?",
+                format_item = function(choice)
+                    return choice.label
+                end,
+            }, function(choice)
+                -- Without a choice, the peer keeps waiting, and can be approved in the terminal.
+                if choice then
+                    client.connection:send_request("decideOnPeer", { nodeId = node_id, decision = choice.decision })
+                end
+            end)
+        end)
+    elseif method == "peerState" then
+        -- Shown by :TeamtypeInfo.
+        local node_id = parameters.nodeId
+        client.peer_states[node_id] = parameters
+        if parameters.state == "gaveUp" then
+            print("Teamtype: Gave up on reaching peer " .. node_id:sub(1, 10) .. ".")
+        end
     else
         print("Unknown method: " .. method)
     end
@@ -137,6 +178,7 @@ local function find_or_create_client(config_name, root_dir)
         root_dir = root_dir,
         files = {},
         buffers = {},
+        peer_states = {},
         connection = nil,
     }
     local the_connection = connection.connect(configurations[config_name].cfg.cmd, root_dir, function(m, p)
@@ -175,6 +217,9 @@ local function activate_config_for_buffer(config_name, buf_nr, root_dir)
         debug("Tracking Edits")
         ensure_autoread_is_off()
         disable_writing()
+        if client.read_only then
+            vim.bo[buf_nr].modifiable = false
+        end
         track_edits(client, filename, uri, lines)
     end)
 end
@@ -243,6 +288,9 @@ local function print_info()
 
     for _, client in ipairs(clients) do
         result = result .. "\"" .. client.name .. "\" in '" .. client.root_dir .. "'\n"
+        for node_id, peer_state in pairs(client.peer_states) do
+            result = result .. "  peer " .. node_id:sub(1, 10) .. ": " .. peer_state.state .. "\n"
+        end
     end
 
     result = result .. "\nCursors:\n\n" .. cursor.list_cursors()
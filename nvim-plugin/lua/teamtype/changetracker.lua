@@ -240,9 +240,16 @@ end
 function M.apply_delta(buffer, delta)
     local text_edits = teamtype_delta_to_lsp_text_edits(delta)
 
+    -- Buffers are not modifiable when we only have read-only access, but the daemon's edits still
+    -- need to go in.
+    local modifiable = vim.bo[buffer].modifiable
+    vim.bo[buffer].modifiable = true
+
     ignore_edits = true
     lsp_util.apply_text_edits(text_edits, buffer, "utf-32")
     ignore_edits = false
+
+    vim.bo[buffer].modifiable = modifiable
 end
 
 return M